@@ -1,3 +1,3 @@
 #!/bin/bash
 set -xeuo pipefail
-yum -y install qemu-img rsync && yum clean all
+yum -y install gnupg2 && yum clean all
@@ -0,0 +1,144 @@
+//! Dehydrate the images of a stream into a bundle: download the stream
+//! metadata and the original images, then generate the bundle from them.
+
+use crate::strategy::StrategyTable;
+use crate::{Error, DIR, STREAM_FILE};
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+use std::fs::OpenOptions;
+use std::io::Write;
+use tracing::info;
+
+/// Where we put temporarily decompressed images
+const CACHEDIR: &str = "dehydrate-cache";
+
+/// Where the build steps keep their files.
+#[derive(Debug, Clone)]
+pub struct BuildDirs {
+    /// Directory for the stream metadata and the original images.
+    pub workdir: Utf8PathBuf,
+    /// Directory for temporarily decompressed images; defaults to
+    /// `dehydrate-cache` in the working directory.
+    pub cachedir: Option<Utf8PathBuf>,
+    /// Directory to write the bundle to; defaults to
+    /// `coreos-images-dehydrated` in the working directory.
+    pub output: Option<Utf8PathBuf>,
+}
+
+impl Default for BuildDirs {
+    fn default() -> Self {
+        Self {
+            workdir: ".".into(),
+            cachedir: None,
+            output: None,
+        }
+    }
+}
+
+impl BuildDirs {
+    /// The path of `name` (e.g. an original image) in the working directory.
+    pub(crate) fn path(&self, name: &str) -> Utf8PathBuf {
+        self.workdir.join(name)
+    }
+
+    pub(crate) fn cachedir(&self) -> Utf8PathBuf {
+        self.cachedir.clone().unwrap_or_else(|| self.path(CACHEDIR))
+    }
+
+    pub(crate) fn output(&self) -> Utf8PathBuf {
+        self.output.clone().unwrap_or_else(|| self.path(DIR))
+    }
+}
+
+/// Options for [`dehydrate`].
+#[derive(Debug, Clone, Default)]
+pub struct DehydrateOptions {
+    /// Where the original images are, and where to write the bundle.
+    pub dirs: BuildDirs,
+    /// The architectures to dehydrate.
+    pub arches: Vec<String>,
+    /// How the artifacts of each platform are dehydrated.
+    pub strategies: StrategyTable,
+    /// Don't fail if there are artifacts without a strategy.
+    pub allow_unhandled: bool,
+    /// The bundle for a previous release of the stream; the qemu image
+    /// is stored as a delta from the one in it, rather than in full.
+    pub previous: Option<Utf8PathBuf>,
+    /// Number of images to dehydrate in parallel.
+    pub jobs: usize,
+}
+
+/// Download the metadata for `stream` (e.g. `stable` for FCOS, `rhcos-4.8`
+/// for RHCOS) to the working directory.
+pub fn init(dirs: &BuildDirs, stream: &str) -> Result<(), Error> {
+    init_impl(dirs, stream).map_err(Error::from_anyhow)
+}
+
+fn init_impl(dirs: &BuildDirs, stream: &str) -> Result<()> {
+    let u = crate::streamid::stream_url_from_id(stream)?;
+    let stream_path = &dirs.path(STREAM_FILE);
+    if stream_path.exists() {
+        return Err(anyhow!("{} exists, not overwriting", stream_path));
+    }
+    std::fs::create_dir_all(&dirs.workdir)?;
+    info!("Downloading {}", u);
+    let mut out = std::io::BufWriter::new(
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(stream_path)?,
+    );
+    let mut resp = reqwest::blocking::get(&u)?;
+    resp.error_for_status_ref()?;
+    resp.copy_to(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Download the images for `arches` which `strategies` include, `jobs` at
+/// a time, along with their signatures.  These are verified with the
+/// public keys in `keyring`, or the Fedora and Red Hat release keys in
+/// /etc/pki/rpm-gpg if it's empty, unless `skip_signatures` is set.
+pub fn download(
+    dirs: &BuildDirs,
+    arches: &[String],
+    strategies: &StrategyTable,
+    skip_signatures: bool,
+    keyring: &[Utf8PathBuf],
+    jobs: usize,
+) -> Result<(), Error> {
+    crate::download::build_download(dirs, arches, strategies, skip_signatures, keyring, jobs)
+        .map_err(Error::from_anyhow)
+}
+
+/// Verify the downloaded images against the stream metadata, as for
+/// [`download`].
+pub fn verify(
+    dirs: &BuildDirs,
+    arches: &[String],
+    strategies: &StrategyTable,
+    skip_signatures: bool,
+    keyring: &[Utf8PathBuf],
+) -> Result<(), Error> {
+    crate::download::build_verify(dirs, arches, strategies, skip_signatures, keyring)
+        .map_err(Error::from_anyhow)
+}
+
+/// Generate the bundle from the downloaded images.
+pub fn dehydrate(opts: &DehydrateOptions) -> Result<(), Error> {
+    crate::build_dehydrate(opts).map_err(Error::from_anyhow)
+}
+
+/// Remove the temporarily decompressed images.
+pub fn clean(dirs: &BuildDirs) -> Result<(), Error> {
+    clean_impl(dirs).map_err(Error::from_anyhow)
+}
+
+fn clean_impl(dirs: &BuildDirs) -> Result<()> {
+    let cachedir = &dirs.cachedir();
+    if cachedir.exists() {
+        std::fs::remove_dir_all(cachedir)?;
+        info!("Removed: {}", cachedir);
+    }
+    Ok(())
+}
@@ -0,0 +1,518 @@
+//! The public API: open a dehydrated bundle, and regenerate images from it.
+
+use crate::compress::Compress;
+use crate::riverdelta::{self, ArtifactExt, Entry, RiverDelta};
+use crate::rsync;
+use crate::strategy::Strategy;
+use crate::utils::ChecksumMismatch;
+use crate::vmdk;
+use crate::{OutputTarget, RehydrateContext};
+use anyhow::anyhow;
+use camino::{Utf8Path, Utf8PathBuf};
+use coreos_stream_metadata::Artifact;
+use serde_derive::Serialize;
+use std::convert::TryInto;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// An error from reading a bundle or regenerating an image.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The bundle doesn't contain images for the architecture.
+    #[error("No images for architecture {arch} in {dir}")]
+    MissingArch {
+        /// The bundle directory.
+        dir: Utf8PathBuf,
+        /// The requested architecture.
+        arch: String,
+    },
+    /// There's no image with the filename in the bundle.
+    #[error("Unknown image: {0}")]
+    UnknownImage(String),
+    /// The qemu image is stored as a delta from the named image in a
+    /// previous bundle, which wasn't provided with [`Bundle::set_base`].
+    #[error("Missing base bundle with {0}, from which the qemu image is a delta")]
+    MissingBase(String),
+    /// The bundle was written by an older version in a format which is no
+    /// longer supported (e.g. a VMDK without its layout), and must be
+    /// dehydrated again.
+    #[error("Bundle format too old for {0}; dehydrate it again with this version")]
+    FormatTooOld(String),
+    /// A regenerated image doesn't match the original.
+    #[error(transparent)]
+    ChecksumMismatch(#[from] ChecksumMismatch),
+    /// Any other failure, e.g. to read the bundle.  The message includes
+    /// its causes, e.g. `Reading bundle: No such file or directory`.
+    #[error("{}", chain(.0.as_ref()))]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// The message of an error followed by those of its causes, as anyhow
+/// formats them with `{:#}`.
+fn chain(e: &(dyn std::error::Error + 'static)) -> String {
+    let mut r = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        r.push_str(": ");
+        r.push_str(&e.to_string());
+        source = e.source();
+    }
+    r
+}
+
+impl Error {
+    /// Convert an internal error, recovering the typed errors it wraps.
+    pub(crate) fn from_anyhow(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        match e.downcast::<ChecksumMismatch>() {
+            Ok(e) => Error::ChecksumMismatch(e),
+            Err(e) => Error::Other(e.into()),
+        }
+    }
+}
+
+/// An image which can be generated.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Image {
+    /// The platform, e.g. `openstack` or `metal`.
+    pub platform: String,
+    /// The format of the image, e.g. `qcow2.xz`.
+    pub format: String,
+    /// The kind of artifact within the format, e.g. `disk` or `kernel`.
+    pub kind: String,
+    /// How the image is dehydrated; not set for the qemu base image.
+    pub strategy: Option<Strategy>,
+    /// The name of the generated file.
+    pub filename: String,
+    /// Size of the generated file, if known in advance.
+    pub size: Option<u64>,
+    /// Size of the data stored in the bundle for this image.
+    pub delta_size: u64,
+    /// Whether the SHA-256 of the generated file can be validated.
+    pub validated: bool,
+    /// The options the VMDKs in the image were created with, e.g.
+    /// `adapter_type=lsilogic,subformat=streamOptimized,hw_version=6`,
+    /// prefixed by `<disk>: ` for those in an OVA.  Empty for other images,
+    /// and in older bundles.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vmdk_options: Vec<String>,
+}
+
+/// The images to regenerate with [`Bundle::rehydrate_selection`].
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// Every image, including the artifacts which are included as is.
+    pub all: bool,
+    /// The disk images for these platforms, e.g. `openstack` or `qemu`.
+    pub disks: Vec<String>,
+    /// The metal ISO.
+    pub iso: bool,
+    /// The metal PXE artifacts: kernel, initramfs and rootfs.
+    pub pxe: bool,
+}
+
+/// Where [`Bundle::rehydrate_selection`] writes images.
+#[derive(Debug)]
+pub enum Output<W> {
+    /// Write each image to a file in the directory.
+    Directory(Utf8PathBuf),
+    /// Write a single image to the stream as is, or multiple images as a
+    /// tar stream.
+    Stream(W),
+}
+
+/// Something in the bundle which can be regenerated.
+pub(crate) enum Item<'a> {
+    Qemu,
+    Entry(&'a Entry),
+    /// An artifact included as is, which isn't otherwise part of an entry.
+    Source(&'a Artifact),
+    /// The detached signature, named as given, for an artifact which is
+    /// regenerated unmodified.
+    Signature(&'a str),
+}
+
+/// Whether we can validate the checksum of an artifact after decompressing it.
+pub(crate) fn can_validate(a: &Artifact) -> bool {
+    crate::maybe_uncompressed_name(a.filename()).is_none() || a.uncompressed_sha256.is_some()
+}
+
+/// The options recorded in the bundle metadata for the VMDKs in `a`.
+fn vmdk_options(meta: &crate::Metadata, a: &Artifact) -> Vec<String> {
+    let name = a.filename();
+    meta.vmdk
+        .iter()
+        .filter_map(|(key, opts)| match key.strip_prefix(name)? {
+            "" => Some(opts.to_string()),
+            disk => Some(format!("{}: {}", disk.strip_prefix('/')?, opts)),
+        })
+        .collect()
+}
+
+fn file_size(p: impl AsRef<Utf8Path>) -> anyhow::Result<u64> {
+    Ok(p.as_ref().metadata()?.len())
+}
+
+fn image(
+    srcdir: &Utf8Path,
+    meta: &crate::Metadata,
+    platform: &str,
+    format: &str,
+    kind: &str,
+    strategy: Strategy,
+    a: &Artifact,
+) -> anyhow::Result<Image> {
+    let (size, delta_size, validated) = match strategy {
+        Strategy::Copy => {
+            let size = file_size(srcdir.join(a.filename()))?;
+            (Some(size), size, can_validate(a))
+        }
+        Strategy::RsyncFromQemu | Strategy::IsoFromRootfs => {
+            let patch = srcdir.join(crate::rdelta_name_for_artifact(a)?);
+            let header = rsync::read_header(&patch)?;
+            (Some(header.target.size), file_size(patch)?, can_validate(a))
+        }
+        Strategy::Vmdk | Strategy::Ova => {
+            let layout = crate::vmdk_layout_for_artifact(srcdir, a)?;
+            let mut delta_size = file_size(&layout)?;
+            for name in crate::vmdk_rdelta_names(a, vmdk::layout_disks(&layout)?) {
+                delta_size += file_size(srcdir.join(name))?;
+            }
+            // Unless it's written anew, and its size isn't known in advance.
+            if vmdk::reproducible(&layout)? {
+                let size = vmdk::layout_size(&layout)?;
+                (Some(size), delta_size, can_validate(a))
+            } else {
+                (None, delta_size, false)
+            }
+        }
+        Strategy::Skip => unreachable!(),
+    };
+    Ok(Image {
+        platform: platform.to_string(),
+        format: format.to_string(),
+        kind: kind.to_string(),
+        strategy: Some(strategy),
+        filename: crate::uncompressed_name(a.filename()).to_string(),
+        size,
+        delta_size,
+        validated,
+        vmdk_options: vmdk_options(meta, a),
+    })
+}
+
+/// The dehydrated images in a bundle for one architecture.
+///
+/// Images are regenerated uncompressed, and named by their filename in
+/// the stream metadata without the compression suffix.
+pub struct Bundle {
+    pub(crate) srcdir: Utf8PathBuf,
+    pub(crate) rd: RiverDelta,
+    pub(crate) base: Option<Utf8PathBuf>,
+    pub(crate) tmpdir: Option<Utf8PathBuf>,
+    pub(crate) skip_validate: bool,
+    /// The decompressed qemu image, once it's needed.
+    qemu: Mutex<Option<tempfile::TempPath>>,
+    pub(crate) sources: crate::rsync::SourceCache,
+}
+
+impl Bundle {
+    /// Open the bundle in `dir` for `arch`, or the architecture of the host.
+    pub fn open(dir: impl AsRef<Utf8Path>, arch: Option<&str>) -> Result<Self, Error> {
+        let (srcdir, rd) = crate::load_bundle(dir.as_ref(), arch).map_err(Error::from_anyhow)?;
+        Ok(Self {
+            srcdir,
+            rd,
+            base: None,
+            tmpdir: None,
+            skip_validate: false,
+            qemu: Mutex::new(None),
+            sources: Default::default(),
+        })
+    }
+
+    /// The architectures in the bundle in `dir`.
+    pub fn arches(dir: impl AsRef<Utf8Path>) -> Result<Vec<String>, Error> {
+        crate::mirror::bundle_arches(dir.as_ref()).map_err(Error::from_anyhow)
+    }
+
+    /// Use the bundle for a previous release in `base`, which is needed if the
+    /// qemu image was dehydrated as a delta from it.
+    pub fn set_base(&mut self, base: Option<Utf8PathBuf>) {
+        self.base = base;
+    }
+
+    /// Write temporary files to `tmpdir` rather than the system default.
+    /// Regenerating an image may need several times its size.
+    pub fn set_tmpdir(&mut self, tmpdir: Option<Utf8PathBuf>) {
+        self.tmpdir = tmpdir;
+    }
+
+    /// Don't verify the SHA-256 of regenerated images.
+    pub fn set_skip_validate(&mut self, skip: bool) {
+        self.skip_validate = skip;
+    }
+
+    /// The architecture of the images.
+    pub fn arch(&self) -> &str {
+        self.srcdir.file_name().unwrap()
+    }
+
+    /// The bundle directory.
+    pub(crate) fn dir(&self) -> &Utf8Path {
+        self.srcdir.parent().unwrap()
+    }
+
+    /// The stream the images are from, e.g. `stable`.
+    pub fn stream(&self) -> &str {
+        &self.rd.stream
+    }
+
+    /// Artifacts in the stream which aren't included, as `platform/format`.
+    pub fn unhandled(&self) -> impl Iterator<Item = &str> {
+        self.rd.unhandled.iter().map(|s| s.as_str())
+    }
+
+    /// The images which can be generated.
+    pub fn images(&self) -> Result<Vec<Image>, Error> {
+        self.images_impl().map_err(Error::from_anyhow)
+    }
+
+    fn images_impl(&self) -> anyhow::Result<Vec<Image>> {
+        let srcdir = &self.srcdir;
+        let rd = &self.rd;
+        let meta = crate::Metadata::load(srcdir)?;
+        let qemu = &rd.qemu;
+        let qemu_name = crate::uncompressed_name(qemu.filename());
+        let mut images = vec![Image {
+            platform: riverdelta::QEMU.to_string(),
+            format: rd.qemu_format.clone(),
+            kind: "disk".to_string(),
+            strategy: None,
+            filename: qemu_name.to_string(),
+            size: meta.qemu_size,
+            delta_size: match meta.qemu_base {
+                Some(_) => file_size(srcdir.join(crate::rdelta_name_for_artifact(qemu)?))?,
+                None => file_size(srcdir.join(format!("{}.zst", qemu_name)))?,
+            },
+            validated: can_validate(qemu),
+            vmdk_options: Vec::new(),
+        }];
+        for e in rd.entries.iter() {
+            images.push(image(
+                srcdir,
+                &meta,
+                &e.platform,
+                &e.format,
+                &e.kind,
+                e.strategy,
+                &e.artifact,
+            )?);
+        }
+        if let Some(rootfs) = rd.rootfs.as_ref() {
+            if !images.iter().any(|i| i.filename == rootfs.filename()) {
+                images.push(image(
+                    srcdir,
+                    &meta,
+                    riverdelta::METAL,
+                    "pxe",
+                    "rootfs",
+                    Strategy::Copy,
+                    rootfs,
+                )?);
+            }
+        }
+        Ok(images)
+    }
+
+    /// Regenerate the image `filename`, writing it to `w`, which is returned.
+    ///
+    /// The SHA-256 of the data is validated (see [`Image::validated`]) as it
+    /// is written; if it doesn't match, [`Error::ChecksumMismatch`] is
+    /// returned, but the data has already been written.
+    pub fn rehydrate_to<W: Write>(&self, filename: &str, w: W) -> Result<W, Error> {
+        let r = self
+            .write(filename, OutputTarget::Stdout(w))
+            .and_then(|target| match target {
+                OutputTarget::Stdout(mut w) => {
+                    w.flush()?;
+                    Ok(w)
+                }
+                _ => unreachable!(),
+            });
+        r.map_err(Error::from_anyhow)
+    }
+
+    /// Regenerate the image `filename` into the directory `dir`, returning
+    /// its path.  The file is only created if the image is valid.
+    pub fn rehydrate_to_path(
+        &self,
+        filename: &str,
+        dir: impl AsRef<Utf8Path>,
+    ) -> Result<Utf8PathBuf, Error> {
+        let dir = dir.as_ref();
+        let target = OutputTarget::<std::io::Sink>::Directory(dir.to_owned());
+        self.write(filename, target).map_err(Error::from_anyhow)?;
+        Ok(dir.join(filename))
+    }
+
+    /// Regenerate the images in `sel`, up to `jobs` at a time, compressed
+    /// as `compress`.
+    ///
+    /// Images written to a stream are validated as for
+    /// [`Bundle::rehydrate_to`].  A tar stream is reproducible: its entries
+    /// are sorted by filename, and timestamped with the `last-modified` time
+    /// of the stream metadata.
+    pub fn rehydrate_selection<W: Write + Send>(
+        &self,
+        sel: &Selection,
+        compress: Compress,
+        jobs: usize,
+        output: Output<W>,
+    ) -> Result<(), Error> {
+        crate::rehydrate_selection(self, sel, compress, jobs, output).map_err(Error::from_anyhow)
+    }
+
+    fn write<W: Write>(
+        &self,
+        name: &str,
+        target: OutputTarget<W>,
+    ) -> anyhow::Result<OutputTarget<W>> {
+        let tmpdir = match self.tmpdir.as_ref() {
+            Some(d) => tempfile::tempdir_in(d)?,
+            None => tempfile::tempdir()?,
+        };
+        let ctx = RehydrateContext {
+            compress: Compress::None,
+            threads: 1,
+            skip_validate: self.skip_validate,
+            srcdir: &self.srcdir,
+            tmpdir: tmpdir.path().try_into()?,
+            output: target.kind(),
+            target: Arc::new(Mutex::new(target)),
+            order: Default::default(),
+            job: 0,
+            sources: &self.sources,
+        };
+        let item = self
+            .find(name)
+            .ok_or_else(|| Error::UnknownImage(name.to_string()))?;
+        let qemu = if crate::needs_qemu(&item) {
+            self.qemu()?
+        } else {
+            Utf8PathBuf::new()
+        };
+        crate::rehydrate_item(&ctx, &self.rd, &qemu, &item)?;
+        let target = Arc::try_unwrap(ctx.target)
+            .map_err(|_| anyhow!("Output still in use"))?
+            .into_inner()
+            .unwrap();
+        Ok(target)
+    }
+
+    /// Find what's regenerated as `name`.
+    pub(crate) fn find(&self, name: &str) -> Option<Item<'_>> {
+        for a in self.rd.all_artifacts() {
+            if crate::uncompressed_name(a.filename()) == name {
+                if a.location == self.rd.qemu.location {
+                    return Some(Item::Qemu);
+                }
+                return Some(
+                    match self
+                        .rd
+                        .entries
+                        .iter()
+                        .find(|e| e.artifact.location == a.location)
+                    {
+                        Some(e) => Item::Entry(e),
+                        None => Item::Source(a),
+                    },
+                );
+            }
+            if let Some(sig) = self.signature_for(a).filter(|&sig| sig == name) {
+                return Some(Item::Signature(sig));
+            }
+        }
+        None
+    }
+
+    /// The signature file in the bundle for `a`, if it's still valid for
+    /// the image we generate; i.e. the image isn't decompressed, and is
+    /// the original.
+    pub(crate) fn signature_for<'a>(&self, a: &'a Artifact) -> Option<&'a str> {
+        if crate::maybe_uncompressed_name(a.filename()).is_some() || !self.reproduces(a) {
+            return None;
+        }
+        a.signature_filename()
+            .filter(|sig| self.srcdir.join(sig).exists())
+    }
+
+    /// Whether the image we generate for `a` is the original, which isn't the
+    /// case for VMDKs written anew because our zlib compresses differently.
+    pub(crate) fn reproduces(&self, a: &Artifact) -> bool {
+        let vmdk = self.rd.entries.iter().any(|e| {
+            e.artifact.location == a.location
+                && matches!(e.strategy, Strategy::Vmdk | Strategy::Ova)
+        });
+        // A missing layout is reported when generating the image.
+        !vmdk
+            || crate::vmdk_layout_for_artifact(&self.srcdir, a)
+                .and_then(|layout| vmdk::reproducible(&layout))
+                .unwrap_or(true)
+    }
+
+    /// Decompress the qemu image if we haven't already, returning its path.
+    fn qemu(&self) -> anyhow::Result<Utf8PathBuf> {
+        let mut decompressed = self.qemu.lock().unwrap();
+        if let Some(p) = decompressed.as_ref() {
+            return Ok(crate::temppath_name(p)?.to_owned());
+        }
+        let tmpf = match self.tmpdir.as_ref() {
+            Some(d) => tempfile::NamedTempFile::new_in(d)?,
+            None => tempfile::NamedTempFile::new()?,
+        };
+        let tmpf = tmpf.into_temp_path();
+        let p = crate::temppath_name(&tmpf)?.to_owned();
+        crate::decompress_qemu(&self.srcdir, &self.rd.qemu, &p, self.base.as_deref())?;
+        *decompressed = Some(tmpf);
+        Ok(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error() -> anyhow::Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        match Bundle::open(dir, Some("s390x")) {
+            Err(Error::MissingArch { arch, .. }) => assert_eq!(arch, "s390x"),
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Opened missing architecture"),
+        }
+
+        // Typed errors are recovered from beneath any context.
+        let e = anyhow::Error::from(ChecksumMismatch {
+            name: "foo".to_string(),
+            len: 3,
+            expected: "abc".to_string(),
+            actual: "def".to_string(),
+        });
+        let e = Error::from_anyhow(e.context("Generating foo"));
+        assert!(matches!(e, Error::ChecksumMismatch(ref m) if m.name == "foo"));
+        let r: anyhow::Result<()> = Err(Error::MissingBase("qemu.qcow2".to_string()).into());
+        let e = Error::from_anyhow(r.context("Decompressing").unwrap_err());
+        assert!(matches!(e, Error::MissingBase(_)));
+        let e = Error::from_anyhow(anyhow!("oops").context("Reading bundle"));
+        assert_eq!(format!("{}", e), "Reading bundle: oops");
+        Ok(())
+    }
+}
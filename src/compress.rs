@@ -0,0 +1,145 @@
+//! Compression of generated images.
+
+use anyhow::Result;
+use std::io::Write;
+use strum_macros::{Display, EnumString};
+
+/// How to compress generated images.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, EnumString, Display)]
+#[strum(serialize_all = "lowercase")]
+pub enum Compress {
+    /// Output uncompressed images.
+    None,
+    /// Compress with xz, using as many threads as images are generated
+    /// in parallel.
+    Xz,
+    /// Compress with gzip.
+    Gz,
+    /// Compress with zstd.
+    Zstd,
+    /// Use the same compression as the original artifact.
+    Original,
+}
+
+impl Compress {
+    /// The compression to use for an artifact with the original `filename`.
+    pub(crate) fn for_filename(self, filename: &str) -> Option<Compressor> {
+        match self {
+            Compress::None => None,
+            Compress::Xz => Some(Compressor::Xz),
+            Compress::Gz => Some(Compressor::Gz),
+            Compress::Zstd => Some(Compressor::Zstd),
+            Compress::Original => [Compressor::Xz, Compressor::Gz]
+                .iter()
+                .copied()
+                .find(|c| filename.ends_with(&format!(".{}", c.extension()))),
+        }
+    }
+}
+
+/// A compression algorithm.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum Compressor {
+    Xz,
+    Gz,
+    Zstd,
+}
+
+impl Compressor {
+    /// The filename extension for compressed files, without the `.`.
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Compressor::Xz => "xz",
+            Compressor::Gz => "gz",
+            Compressor::Zstd => "zst",
+        }
+    }
+
+    /// Create an encoder which writes compressed data to `w`.  For xz,
+    /// which is slow, up to `threads` threads are used.
+    pub(crate) fn encoder<W: Write>(self, w: W, threads: u32) -> Result<Encoder<W>> {
+        Ok(match self {
+            Compressor::Xz => {
+                let stream = xz2::stream::MtStreamBuilder::new()
+                    .threads(threads)
+                    .preset(6)
+                    .encoder()?;
+                Encoder::Xz(xz2::write::XzEncoder::new_stream(w, stream))
+            }
+            Compressor::Gz => Encoder::Gz(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )),
+            Compressor::Zstd => Encoder::Zstd(zstd::Encoder::new(w, 0)?),
+        })
+    }
+}
+
+/// A streaming compressor.
+pub(crate) enum Encoder<W: Write> {
+    Xz(xz2::write::XzEncoder<W>),
+    Gz(flate2::write::GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Write any remaining data, and return the inner writer.
+    pub(crate) fn finish(self) -> Result<W> {
+        Ok(match self {
+            Encoder::Xz(e) => e.finish()?,
+            Encoder::Gz(e) => e.finish()?,
+            Encoder::Zstd(e) => e.finish()?,
+        })
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Encoder::Xz(e) => e.write(buf),
+            Encoder::Gz(e) => e.write(buf),
+            Encoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Encoder::Xz(e) => e.flush(),
+            Encoder::Gz(e) => e.flush(),
+            Encoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_compress() -> Result<()> {
+        assert_eq!(Compress::from_str("original")?, Compress::Original);
+        assert!(Compress::from_str("bz2").is_err());
+        let o = Compress::Original;
+        assert_eq!(o.for_filename("foo.qcow2.xz"), Some(Compressor::Xz));
+        assert_eq!(o.for_filename("foo.qcow2.gz"), Some(Compressor::Gz));
+        assert_eq!(o.for_filename("foo.ova"), None);
+        assert_eq!(Compress::None.for_filename("foo.qcow2.xz"), None);
+
+        let data = b"hello world".repeat(1000);
+        for &c in [Compressor::Xz, Compressor::Gz, Compressor::Zstd].iter() {
+            let mut e = c.encoder(Vec::new(), 2)?;
+            e.write_all(&data)?;
+            let buf = e.finish()?;
+            let mut out = Vec::new();
+            match c {
+                Compressor::Xz => xz2::read::XzDecoder::new(&buf[..]).read_to_end(&mut out)?,
+                Compressor::Gz => flate2::read::GzDecoder::new(&buf[..]).read_to_end(&mut out)?,
+                Compressor::Zstd => zstd::Decoder::new(&buf[..])?.read_to_end(&mut out)?,
+            };
+            assert!(out == data);
+        }
+        Ok(())
+    }
+}
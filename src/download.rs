@@ -1,18 +1,274 @@
+use crate::build::BuildDirs;
+use crate::gpg;
 use crate::riverdelta::{self, ArtifactExt, RiverDelta};
+use crate::strategy::StrategyTable;
+use crate::utils;
 use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use coreos_stream_metadata::Artifact;
 use rayon::prelude::*;
-use smallvec::SmallVec;
-use std::convert::TryInto;
-use std::fs::File;
-use tracing::info;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::time::Duration;
+use tracing::{error, info, warn};
 
-pub(crate) fn build_download(skip_signatures: bool) -> Result<()> {
-    let mut s = crate::read_stream()?;
+/// How we obtained a verified artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fetched {
+    /// Already present, with a valid checksum.
+    Present,
+    /// Newly downloaded.
+    Downloaded,
+    /// Present but corrupted, and downloaded again.
+    Refetched,
+}
+
+/// Summary of artifacts verified against stream metadata.
+#[derive(Debug, Default)]
+struct Report {
+    present: usize,
+    downloaded: usize,
+    refetched: usize,
+    size: u64,
+}
+
+impl Report {
+    fn add(mut self, (fetched, size): (Fetched, u64)) -> Self {
+        match fetched {
+            Fetched::Present => self.present += 1,
+            Fetched::Downloaded => self.downloaded += 1,
+            Fetched::Refetched => self.refetched += 1,
+        }
+        self.size += size;
+        self
+    }
+
+    fn merge(mut self, other: Self) -> Self {
+        self.present += other.present;
+        self.downloaded += other.downloaded;
+        self.refetched += other.refetched;
+        self.size += other.size;
+        self
+    }
+}
+
+/// Number of attempts made for each download.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+/// Delay before the first retry; doubled for each subsequent attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Downloads files, resuming partial downloads and retrying on failure.
+struct Downloader {
+    client: reqwest::blocking::Client,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Downloader {
+    fn new(client: reqwest::blocking::Client) -> Self {
+        Self {
+            client,
+            attempts: DOWNLOAD_ATTEMPTS,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Download `location` to `fname`, verifying its checksum if provided.
+    /// Data is written to `<fname>.tmp` first; if that already exists
+    /// (e.g. from an interrupted run) and `resume` is set, we resume from
+    /// its end.  Failed requests are retried, but a checksum mismatch fails
+    /// immediately.
+    fn download(
+        &self,
+        location: &str,
+        fname: &Utf8Path,
+        sha256: Option<&str>,
+        resume: bool,
+    ) -> Result<()> {
+        let temp_name = &Utf8PathBuf::from(format!("{}.tmp", fname));
+        let mut delay = self.retry_delay;
+        for attempt in 1..=self.attempts {
+            match self.download_once(location, temp_name, fname, sha256, resume) {
+                Ok(()) => break,
+                // Downloading it again would give us the same data.
+                Err(e) if e.is::<utils::ChecksumMismatch>() => {
+                    return Err(e.context(format!("Failed to download {}", location)))
+                }
+                Err(e) if attempt < self.attempts => {
+                    warn!(
+                        "Failed to download {} (attempt {}/{}), retrying in {:?}: {:#}",
+                        location, attempt, self.attempts, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "Failed to download {} after {} attempts",
+                        location, self.attempts
+                    )))
+                }
+            }
+        }
+        std::fs::rename(temp_name, fname)?;
+        info!("Downloaded: {}", fname);
+        Ok(())
+    }
+
+    fn download_once(
+        &self,
+        location: &str,
+        temp_name: &Utf8Path,
+        fname: &Utf8Path,
+        sha256: Option<&str>,
+        resume: bool,
+    ) -> Result<()> {
+        let offset = match temp_name.metadata() {
+            Ok(m) if resume => m.len(),
+            Ok(_) => 0,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let mut req = self.client.get(location);
+        if offset > 0 {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut resp = req.send()?;
+        let resumed = match resp.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                info!("Resuming download of {} at offset {}", fname, offset);
+                true
+            }
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                // Our partial file is bogus; start over next time.
+                std::fs::remove_file(temp_name)?;
+                return Err(anyhow!("Invalid partial download {}", temp_name));
+            }
+            _ => {
+                resp.error_for_status_ref()?;
+                false
+            }
+        };
+        let mut out = if resumed {
+            let prefix = BufReader::new(File::open(temp_name)?);
+            let f = OpenOptions::new().append(true).open(temp_name)?;
+            utils::Sha256Writer::with_prefix(BufWriter::new(f), prefix)?
+        } else {
+            utils::Sha256Writer::new(BufWriter::new(File::create(temp_name)?))
+        };
+        resp.copy_to(&mut out)
+            .with_context(|| anyhow!("Reading response for {}", location))?;
+        let mut out = match sha256 {
+            Some(sha256) => match out.verify(fname.as_str(), sha256) {
+                Ok(out) => out,
+                Err(e) => {
+                    // Don't try to resume from corrupted data.
+                    std::fs::remove_file(temp_name)?;
+                    return Err(e);
+                }
+            },
+            None => out.finish().0,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Verify the detached signature of an artifact in `dirs`, if it has one.
+fn verify_signature(keyring: &gpg::LazyKeyring, dirs: &BuildDirs, a: &Artifact) -> Result<()> {
+    if let Some(sig_fname) = a.signature_filename() {
+        keyring.verify(&dirs.path(a.filename()), &dirs.path(sig_fname))?;
+    }
+    Ok(())
+}
+
+/// Ensure an artifact (and its signature, if any) is present and matches
+/// the checksum from the stream metadata, downloading it if necessary.
+/// If a keyring is provided, the signature is also verified; one which was
+/// already present is fetched again if it doesn't match.
+fn fetch_artifact(
+    downloader: &Downloader,
+    keyring: Option<&gpg::LazyKeyring>,
+    dirs: &BuildDirs,
+    a: &Artifact,
+) -> Result<(Fetched, u64)> {
+    let fname = &dirs.path(a.filename());
+    let fetched = if fname.exists() {
+        match utils::verify_sha256_file(fname, &a.sha256) {
+            Ok(_) => Fetched::Present,
+            Err(e) if e.is::<utils::ChecksumMismatch>() => {
+                warn!("{}; re-fetching", e);
+                std::fs::remove_file(fname)?;
+                Fetched::Refetched
+            }
+            Err(e) => return Err(e),
+        }
+    } else {
+        Fetched::Downloaded
+    };
+    if fetched != Fetched::Present {
+        downloader.download(a.location.as_str(), fname, Some(a.sha256.as_str()), true)?;
+    }
+    if let (Some(signature), Some(sig_fname)) = (a.signature.as_deref(), a.signature_filename()) {
+        let sig_fname = &dirs.path(sig_fname);
+        let present = sig_fname.exists();
+        if !present {
+            downloader.download(signature, sig_fname, None, false)?;
+        }
+        if let Some(keyring) = keyring {
+            match verify_signature(keyring, dirs, a) {
+                // It may be truncated, or left over from an older release.
+                Err(e) if present && e.is::<gpg::BadSignature>() => {
+                    warn!("{}; re-fetching", e);
+                    downloader.download(signature, sig_fname, None, false)?;
+                    verify_signature(keyring, dirs, a)?;
+                }
+                r => r?,
+            }
+        }
+    }
+    Ok((fetched, fname.metadata()?.len()))
+}
+
+/// The keyring used to verify signatures, unless they're skipped.
+fn lazy_keyring(skip_signatures: bool, keys: &[Utf8PathBuf]) -> Option<gpg::LazyKeyring> {
     if skip_signatures {
-        riverdelta::stream_remove_signatures(&mut s)?;
+        None
+    } else {
+        Some(gpg::LazyKeyring::new(keys))
     }
-    let riverdelta: RiverDelta = s.try_into()?;
+}
+
+/// Parse the stream for each of `arches`.
+fn read_riverdeltas(
+    dirs: &BuildDirs,
+    arches: &[String],
+    strategies: &StrategyTable,
+    skip_signatures: bool,
+) -> Result<Vec<RiverDelta>> {
+    arches
+        .iter()
+        .map(|arch| {
+            let mut s = crate::read_stream(dirs)?;
+            if skip_signatures {
+                riverdelta::stream_remove_signatures(&mut s, arch)?;
+            }
+            RiverDelta::new(s, arch, strategies)
+        })
+        .collect()
+}
+
+pub(crate) fn build_download(
+    dirs: &BuildDirs,
+    arches: &[String],
+    strategies: &StrategyTable,
+    skip_signatures: bool,
+    keys: &[Utf8PathBuf],
+    jobs: usize,
+) -> Result<()> {
+    let riverdeltas = read_riverdeltas(dirs, arches, strategies, skip_signatures)?;
+    let keyring = lazy_keyring(skip_signatures, keys);
+    let keyring = keyring.as_ref();
     let client = reqwest::blocking::ClientBuilder::new()
         .user_agent(concat!(
             env!("CARGO_PKG_NAME"),
@@ -21,47 +277,209 @@ pub(crate) fn build_download(skip_signatures: bool) -> Result<()> {
         ))
         .https_only(true)
         .build()?;
-    let artifacts = riverdelta.all_artifacts();
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(crate::N_WORKERS as usize)
-        .build()
-        .unwrap();
-    pool.install(|| -> Result<_> {
+    let downloader = &Downloader::new(client);
+    let artifacts: Vec<_> = riverdeltas.iter().flat_map(|r| r.all_artifacts()).collect();
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    // Keep going if an artifact fails, so we don't discard the progress of the others.
+    let (report, errors) = pool.install(|| {
         artifacts
             .par_iter()
-            .flat_map_iter(|&a| {
-                let mut r = SmallVec::<[(&str, &Utf8Path); 2]>::new();
-                let img_fname = Utf8Path::new(a.filename());
-                if !img_fname.exists() {
-                    r.push((a.location.as_str(), img_fname))
-                }
-                if let Some(signature) = a.signature.as_deref() {
-                    let sig_fname: &Utf8Path = Utf8Path::new(signature).file_name().unwrap().into();
-                    if !sig_fname.exists() {
-                        r.push((signature, sig_fname))
+            .map(|&a| fetch_artifact(downloader, keyring, dirs, a))
+            .fold(
+                || (Report::default(), Vec::new()),
+                |(r, mut errs), v| match v {
+                    Ok(v) => (r.add(v), errs),
+                    Err(e) => {
+                        errs.push(e);
+                        (r, errs)
                     }
-                }
-                r
-            })
-            .try_for_each_init(
-                || client.clone(),
-                |client, (location, fname)| -> Result<()> {
-                    let temp_name = &format!("{}.tmp", fname);
-                    let mut out = std::io::BufWriter::new(File::create(temp_name)?);
-                    let mut resp = client.get(location).send()?;
-                    resp.error_for_status_ref()?;
-                    resp.copy_to(&mut out)
-                        .with_context(|| anyhow!("Failed to download {}", location))?;
-                    std::fs::rename(temp_name, fname)?;
-                    info!("Downloaded: {}", fname);
-                    Ok(())
                 },
             )
-    })?;
-    let size = riverdelta.original_compressed_size()?;
+            .reduce(
+                || (Report::default(), Vec::new()),
+                |(a, mut aerrs), (b, berrs)| {
+                    aerrs.extend(berrs);
+                    (a.merge(b), aerrs)
+                },
+            )
+    });
+    for e in errors.iter() {
+        error!("{:#}", e);
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} of {} artifacts failed to download",
+            errors.len(),
+            artifacts.len()
+        ));
+    }
+    info!(
+        "Verified {} artifacts ({} already present, {} downloaded, {} re-fetched)",
+        artifacts.len(),
+        report.present,
+        report.downloaded,
+        report.refetched
+    );
     info!(
         "Original artifact total size: {}",
-        indicatif::HumanBytes(size)
+        indicatif::HumanBytes(report.size)
     );
     Ok(())
 }
+
+/// Verify all artifacts (and their signatures) against the stream metadata,
+/// without downloading anything.
+pub(crate) fn build_verify(
+    dirs: &BuildDirs,
+    arches: &[String],
+    strategies: &StrategyTable,
+    skip_signatures: bool,
+    keys: &[Utf8PathBuf],
+) -> Result<()> {
+    let riverdeltas = read_riverdeltas(dirs, arches, strategies, false)?;
+    let keyring = lazy_keyring(skip_signatures, keys);
+    let keyring = keyring.as_ref();
+    let artifacts: Vec<_> = riverdeltas.iter().flat_map(|r| r.all_artifacts()).collect();
+    let failed: Vec<_> = artifacts
+        .par_iter()
+        .filter_map(|a| {
+            utils::verify_sha256_file(dirs.path(a.filename()), &a.sha256)
+                .and_then(|_| keyring.map_or(Ok(()), |k| verify_signature(k, dirs, a)))
+                .err()
+        })
+        .collect();
+    for e in failed.iter() {
+        warn!("{:#}", e);
+    }
+    if !failed.is_empty() {
+        return Err(anyhow!(
+            "{} of {} artifacts failed verification",
+            failed.len(),
+            artifacts.len()
+        ));
+    }
+    info!("Verified {} artifacts", artifacts.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use std::convert::TryInto;
+    use std::io::{BufRead, Read};
+    use std::net::TcpListener;
+
+    /// A minimal HTTP server which supports `Range` requests, and which
+    /// drops the connection halfway through the first response if `truncate`
+    /// is set.  Returns the URL and the `Range` headers received.
+    fn serve(
+        data: Vec<u8>,
+        truncate: bool,
+    ) -> Result<(String, std::thread::JoinHandle<Vec<String>>)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/artifact", listener.local_addr()?);
+        let handle = std::thread::spawn(move || {
+            let mut ranges = Vec::new();
+            for (i, conn) in listener.incoming().enumerate() {
+                let mut conn = conn.unwrap();
+                let mut r = BufReader::new(conn.try_clone().unwrap());
+                let mut offset = 0;
+                loop {
+                    let mut line = String::new();
+                    r.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let lower = line.to_ascii_lowercase();
+                    if let Some(v) = lower.strip_prefix("range: bytes=") {
+                        ranges.push(v.to_string());
+                        offset = v.trim_end_matches('-').parse().unwrap();
+                    }
+                }
+                let body = &data[offset..];
+                let status = if offset > 0 {
+                    "206 Partial Content"
+                } else {
+                    "200 OK"
+                };
+                write!(
+                    conn,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                if i == 0 && truncate {
+                    conn.write_all(&body[..body.len() / 2]).unwrap();
+                } else {
+                    conn.write_all(body).unwrap();
+                    break;
+                }
+            }
+            ranges
+        });
+        Ok((url, handle))
+    }
+
+    #[test]
+    fn test_download_resume() -> Result<()> {
+        let data = vec![42u8; 100_000];
+        let sha256 = format!("{:x}", Sha256::digest(&data));
+        let (url, server) = serve(data.clone(), true)?;
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let dest = &td.join("artifact");
+        let downloader = Downloader {
+            client: reqwest::blocking::Client::new(),
+            attempts: 3,
+            retry_delay: Duration::from_millis(1),
+        };
+        downloader.download(&url, dest, Some(&sha256), true)?;
+        let ranges = server.join().unwrap();
+        assert_eq!(ranges, vec!["50000-".to_string()]);
+        let mut buf = Vec::new();
+        File::open(dest)?.read_to_end(&mut buf)?;
+        assert!(buf == data);
+        assert!(!td.join("artifact.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_mismatch() -> Result<()> {
+        let (url, server) = serve(vec![42u8; 1000], false)?;
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let dest = &td.join("artifact");
+        let downloader = Downloader {
+            client: reqwest::blocking::Client::new(),
+            attempts: 3,
+            retry_delay: Duration::from_millis(1),
+        };
+        // The server only answers once, so a retry would fail to connect.
+        let e = downloader
+            .download(&url, dest, Some("0000"), true)
+            .unwrap_err();
+        assert!(e.is::<utils::ChecksumMismatch>());
+        server.join().unwrap();
+        assert!(!dest.exists());
+        assert!(!td.join("artifact.tmp").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_no_resume() -> Result<()> {
+        let data = b"signature".to_vec();
+        let (url, server) = serve(data.clone(), false)?;
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let dest = &td.join("artifact.sig");
+        std::fs::write(td.join("artifact.sig.tmp"), b"stale")?;
+        let downloader = Downloader::new(reqwest::blocking::Client::new());
+        downloader.download(&url, dest, None, false)?;
+        assert!(server.join().unwrap().is_empty());
+        assert_eq!(std::fs::read(dest)?, data);
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Verification of detached OpenPGP signatures using `gpg`.
+
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use std::convert::TryInto;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// Where distributions install their release signing keys.
+const SYSTEM_KEYDIR: &str = "/etc/pki/rpm-gpg";
+/// Prefixes of key files in [`SYSTEM_KEYDIR`] which we trust by default;
+/// Fedora for FCOS, Red Hat for RHCOS.
+const DEFAULT_KEY_PREFIXES: &[&str] = &["RPM-GPG-KEY-fedora-", "RPM-GPG-KEY-redhat-"];
+
+/// A signature failed to verify.
+#[derive(Debug, thiserror::Error)]
+#[error("Bad or untrusted signature {signature} for {name}: {output}")]
+pub(crate) struct BadSignature {
+    pub(crate) name: String,
+    pub(crate) signature: String,
+    pub(crate) output: String,
+}
+
+/// A set of trusted public keys, imported into a private GnuPG home directory
+/// so that we never consult (or modify) the user's keyring.
+#[derive(Debug)]
+pub(crate) struct Keyring {
+    homedir: tempfile::TempDir,
+}
+
+impl Keyring {
+    /// Import the public keys in `keys`, or the default system keys if empty.
+    #[context("Loading signing keys")]
+    pub(crate) fn new(keys: &[Utf8PathBuf]) -> Result<Self> {
+        let keys = if keys.is_empty() {
+            default_keys()?
+        } else {
+            keys.to_vec()
+        };
+        if keys.is_empty() {
+            return Err(anyhow!(
+                "No signing keys found in {}; use --keyring",
+                SYSTEM_KEYDIR
+            ));
+        }
+        let homedir = tempfile::Builder::new()
+            .prefix("rehydrator-gpg")
+            .tempdir()?;
+        let r = Self { homedir };
+        let s = r
+            .gpg()
+            .arg("--import")
+            .args(keys.iter().map(|k| k.as_str()))
+            .output()?;
+        if !s.status.success() {
+            return Err(anyhow!(
+                "gpg --import failed: {}: {}",
+                s.status,
+                String::from_utf8_lossy(&s.stderr).trim()
+            ));
+        }
+        Ok(r)
+    }
+
+    fn gpg(&self) -> Command {
+        let mut c = Command::new("gpg");
+        c.arg("--homedir")
+            .arg(self.homedir.path())
+            .args(&["--batch", "--quiet", "--no-auto-check-trustdb"])
+            .stdin(Stdio::null());
+        c
+    }
+
+    /// Verify that `signature` is a valid signature for `p` from one of our keys.
+    pub(crate) fn verify(&self, p: &Utf8Path, signature: &Utf8Path) -> Result<()> {
+        let s = self
+            .gpg()
+            .args(&["--status-fd", "1", "--verify"])
+            .args(&[signature.as_str(), p.as_str()])
+            .output()
+            .context("Running gpg")?;
+        let status = String::from_utf8_lossy(&s.stdout);
+        let good = status.lines().any(|l| l.starts_with("[GNUPG:] VALIDSIG "));
+        if !s.status.success() || !good {
+            return Err(BadSignature {
+                name: p.to_string(),
+                signature: signature.to_string(),
+                output: String::from_utf8_lossy(&s.stderr).trim().to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A [`Keyring`] which is only loaded when the first signature is verified,
+/// so that no keys are needed if nothing is signed.
+#[derive(Debug)]
+pub(crate) struct LazyKeyring {
+    keys: Vec<Utf8PathBuf>,
+    keyring: Mutex<Option<Arc<Keyring>>>,
+}
+
+impl LazyKeyring {
+    /// Use the public keys in `keys`, or the default system keys if empty.
+    pub(crate) fn new(keys: &[Utf8PathBuf]) -> Self {
+        Self {
+            keys: keys.to_vec(),
+            keyring: Mutex::new(None),
+        }
+    }
+
+    /// Verify that `signature` is a valid signature for `p` from one of our keys.
+    pub(crate) fn verify(&self, p: &Utf8Path, signature: &Utf8Path) -> Result<()> {
+        let keyring = {
+            let mut keyring = self.keyring.lock().unwrap();
+            if keyring.is_none() {
+                *keyring = Some(Arc::new(Keyring::new(&self.keys)?));
+            }
+            Arc::clone(keyring.as_ref().unwrap())
+        };
+        keyring.verify(p, signature)
+    }
+}
+
+/// Find the release keys installed on the system.
+fn default_keys() -> Result<Vec<Utf8PathBuf>> {
+    let d = Utf8Path::new(SYSTEM_KEYDIR);
+    if !d.exists() {
+        return Ok(Vec::new());
+    }
+    let mut r = Vec::new();
+    for e in std::fs::read_dir(d)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = if let Some(n) = name.to_str() {
+            n
+        } else {
+            continue;
+        };
+        if DEFAULT_KEY_PREFIXES.iter().any(|p| name.starts_with(p)) {
+            r.push(e.path().try_into()?);
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn gpg_in(home: &Utf8Path) -> Command {
+        let mut c = Command::new("gpg");
+        c.args(&["--homedir", home.as_str(), "--batch", "--quiet"]);
+        c
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let home = &td.join("signer");
+        std::fs::create_dir(home)?;
+        let s = gpg_in(home)
+            .args(&["--passphrase", "", "--quick-gen-key", "test@example.com"])
+            .args(&["ed25519", "sign", "never"])
+            .output()?;
+        assert!(s.status.success());
+        let key = &td.join("key.asc");
+        let s = gpg_in(home)
+            .args(&["--armor", "--output", key.as_str(), "--export"])
+            .status()?;
+        assert!(s.success());
+        let data = &td.join("data");
+        std::fs::write(data, b"hello world")?;
+        let sig = &td.join("data.sig");
+        let s = gpg_in(home)
+            .args(&["--output", sig.as_str(), "--detach-sign", data.as_str()])
+            .status()?;
+        let _ = Command::new("gpgconf")
+            .args(&["--homedir", home.as_str(), "--kill", "gpg-agent"])
+            .status();
+        assert!(s.success());
+
+        let keyring = Keyring::new(std::slice::from_ref(key))?;
+        keyring.verify(data, sig)?;
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(data)?
+            .write_all(b"!")?;
+        let e = keyring.verify(data, sig).unwrap_err();
+        assert!(e.is::<BadSignature>());
+
+        // Missing keys are only an error once a signature is verified.
+        let lazy = LazyKeyring::new(&[td.join("missing.asc")]);
+        assert!(lazy.verify(data, sig).is_err());
+        let lazy = LazyKeyring::new(std::slice::from_ref(key));
+        assert!(lazy.verify(data, sig).unwrap_err().is::<BadSignature>());
+        Ok(())
+    }
+}
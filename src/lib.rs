@@ -0,0 +1,1750 @@
+//! Regenerate CoreOS disk images from a dehydrated bundle.
+//!
+//! A bundle is a directory (by default `coreos-images-dehydrated`) written
+//! by `build dehydrate`, with a subdirectory per architecture.  Use
+//! [`Bundle`] to list the images it contains and regenerate them:
+//!
+//! ```no_run
+//! use coreos_diskimage_rehydrator::Bundle;
+//!
+//! # fn main() -> Result<(), coreos_diskimage_rehydrator::Error> {
+//! let bundle = Bundle::open("coreos-images-dehydrated", Some("x86_64"))?;
+//! for image in bundle.images()? {
+//!     println!("{} {}", image.platform, image.filename);
+//! }
+//! let path = bundle.rehydrate_to_path("fedora-coreos-openstack.x86_64.qcow2", "/srv/images")?;
+//! # Ok(())
+//! # }
+//! ```
+
+#![deny(unused_must_use)]
+#![deny(unsafe_code)]
+
+use crate::build::{BuildDirs, DehydrateOptions};
+use crate::bundle::Item;
+use crate::riverdelta::{ArtifactExt, Entry, RiverDelta};
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use coreos_stream_metadata::Artifact;
+use coreos_stream_metadata::Stream as CoreStream;
+use fn_error_context::context;
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+pub mod build;
+mod bundle;
+mod compress;
+mod download;
+mod gpg;
+mod mirror;
+mod ova;
+mod riverdelta;
+mod rsync;
+mod serve;
+mod state;
+mod strategy;
+mod streamid;
+mod utils;
+mod vmdk;
+
+pub use bundle::{Bundle, Error, Image, Output, Selection};
+pub use compress::Compress;
+pub use mirror::mirror;
+pub use riverdelta::host_arch;
+pub use serve::{serve, ServeOptions};
+pub use strategy::{Strategy, StrategyOverride, StrategyTable};
+pub use utils::ChecksumMismatch;
+
+/// The default bundle directory; contains a subdirectory per architecture
+pub const DIR: &str = "coreos-images-dehydrated";
+/// The name of our stream file
+pub const STREAM_FILE: &str = "stream.json";
+/// Name of metadata file
+pub(crate) const METADATA_FILE: &str = "meta.json";
+/// Name of the file recording the strategy table used for a bundle
+const STRATEGY_FILE: &str = "strategy.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Metadata {
+    original_artifact_size: u64,
+    /// Size of the uncompressed qemu image; missing in older bundles.
+    #[serde(default)]
+    pub(crate) qemu_size: Option<u64>,
+    /// If the qemu image is stored as a delta from the qemu image in a
+    /// previous bundle, the name of that image.
+    #[serde(default)]
+    pub(crate) qemu_base: Option<String>,
+    /// The options each VMDK was created with, keyed by artifact filename,
+    /// followed by `/<disk>` for the VMDKs in an OVA; missing in older bundles.
+    #[serde(default)]
+    pub(crate) vmdk: BTreeMap<String, vmdk::Options>,
+}
+
+impl Metadata {
+    pub(crate) fn load(srcdir: &Utf8Path) -> Result<Self> {
+        let p = &srcdir.join(METADATA_FILE);
+        let f = File::open(p).with_context(|| anyhow!("Opening {}", p))?;
+        Ok(serde_json::from_reader(BufReader::new(f))?)
+    }
+}
+
+pub(crate) enum OutputTarget<W: std::io::Write> {
+    Directory(Utf8PathBuf),
+    Stdout(W),
+    /// A tar stream, whose entries all have the modification time `mtime`.
+    Tar {
+        builder: tar::Builder<W>,
+        mtime: u64,
+    },
+}
+
+impl<W: std::io::Write> OutputTarget<W> {
+    pub(crate) fn kind(&self) -> OutputKind {
+        match self {
+            OutputTarget::Directory(d) => OutputKind::Directory(d.clone()),
+            OutputTarget::Stdout(_) => OutputKind::Stdout,
+            OutputTarget::Tar { .. } => OutputKind::Tar,
+        }
+    }
+}
+
+/// The kind of an [`OutputTarget`], which can be checked without locking it.
+#[derive(Debug, Clone)]
+pub(crate) enum OutputKind {
+    Directory(Utf8PathBuf),
+    Stdout,
+    Tar,
+}
+
+pub(crate) struct RehydrateContext<'a, W: std::io::Write> {
+    pub(crate) compress: Compress,
+    /// Number of threads to use when compressing an image.
+    pub(crate) threads: u32,
+    pub(crate) skip_validate: bool,
+    /// The dehydrated images for the requested architecture.
+    pub(crate) srcdir: &'a Utf8Path,
+
+    pub(crate) target: Arc<Mutex<OutputTarget<W>>>,
+    /// The kind of `target`.
+    pub(crate) output: OutputKind,
+    pub(crate) order: Arc<Mutex<OutputOrder>>,
+    /// The job generating outputs with this context; see [`OutputOrder`].
+    pub(crate) job: usize,
+    pub(crate) tmpdir: &'a Utf8Path,
+    /// The sources deltas are applied to which have been verified.
+    pub(crate) sources: &'a rsync::SourceCache,
+}
+
+impl<'a, W: std::io::Write> RehydrateContext<'a, W> {
+    /// The context for generating the outputs of `job`.
+    fn for_job(&self, job: usize) -> Self {
+        Self {
+            compress: self.compress,
+            threads: self.threads,
+            skip_validate: self.skip_validate,
+            srcdir: self.srcdir,
+            target: Arc::clone(&self.target),
+            output: self.output.clone(),
+            order: Arc::clone(&self.order),
+            job,
+            tmpdir: self.tmpdir,
+            sources: self.sources,
+        }
+    }
+}
+
+/// Outputs streamed to stdout or as a tar stream are written in the order
+/// of the jobs generating them, whichever finishes first.  Outputs of the
+/// job whose turn it is are streamed directly; the others are written to
+/// temporary files until their turn.
+#[derive(Debug, Default)]
+pub(crate) struct OutputOrder {
+    /// The job whose outputs are being written.
+    next: usize,
+    /// Jobs which have finished, but whose turn hasn't come yet.
+    done: BTreeSet<usize>,
+    /// Outputs waiting for the turn of their job.
+    spooled: BTreeMap<usize, Vec<Spooled>>,
+}
+
+/// An output written to a temporary file.
+#[derive(Debug)]
+struct Spooled {
+    name: String,
+    size: u64,
+    f: File,
+}
+
+impl Spooled {
+    fn write_to<W: std::io::Write>(self, target: &mut OutputTarget<W>) -> Result<()> {
+        let f = self.f;
+        write_target(target, &self.name, self.size, |w| {
+            std::io::copy(&mut BufReader::new(f), w)?;
+            Ok(())
+        })
+    }
+}
+
+/// Write data generated by `f` to the output as `name`.
+///
+/// For directory output, data is written to a temporary file and renamed
+/// into place, which allows multiple outputs to be generated in parallel.
+/// Otherwise, if it's the turn of our job, the output target is locked,
+/// and the data is streamed directly to stdout or as a tar entry.  If it's
+/// not, or if it's a tar entry and the `size` isn't known up front, the
+/// data is first written to a temporary file.
+fn write_stream<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    name: &str,
+    size: Option<u64>,
+    f: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    if let OutputKind::Directory(d) = &ctx.output {
+        let mut tmpf = tempfile::NamedTempFile::new_in(d)?;
+        {
+            let mut w = BufWriter::new(tmpf.as_file_mut());
+            f(&mut w)?;
+            w.flush()?;
+        }
+        tmpf.persist(d.join(name))
+            .map_err(|e| e.error)
+            .with_context(|| format!("Failed to write {} to {}", name, d))?;
+        return Ok(());
+    }
+    if size.is_some() || !matches!(ctx.output, OutputKind::Tar) {
+        let order = ctx.order.lock().unwrap();
+        if order.next == ctx.job {
+            let mut target = ctx.target.lock().unwrap();
+            drop(order);
+            return write_target(&mut target, name, size.unwrap_or_default(), f);
+        }
+    }
+    let mut tmpf = tempfile::tempfile_in(ctx.tmpdir)?;
+    {
+        let mut w = BufWriter::new(&mut tmpf);
+        f(&mut w)?;
+        w.flush()?;
+    }
+    let size = tmpf.metadata()?.len();
+    tmpf.seek(SeekFrom::Start(0))?;
+    let spooled = Spooled {
+        name: name.to_string(),
+        size,
+        f: tmpf,
+    };
+    let mut order = ctx.order.lock().unwrap();
+    if order.next == ctx.job {
+        let mut target = ctx.target.lock().unwrap();
+        drop(order);
+        spooled.write_to(&mut target)
+    } else {
+        order.spooled.entry(ctx.job).or_default().push(spooled);
+        Ok(())
+    }
+}
+
+/// Mark `job` as finished, and write the spooled outputs of the jobs
+/// whose turn has come.
+fn finish_job<W: std::io::Write>(ctx: &RehydrateContext<W>, job: usize) -> Result<()> {
+    let mut order = ctx.order.lock().unwrap();
+    order.done.insert(job);
+    let mut ready = Vec::new();
+    loop {
+        let next = order.next;
+        ready.extend(order.spooled.remove(&next).unwrap_or_default());
+        if !order.done.remove(&next) {
+            break;
+        }
+        order.next += 1;
+    }
+    // Nothing to write, so don't wait for the target, which the job whose
+    // turn it is may hold while streaming.
+    if ready.is_empty() {
+        return Ok(());
+    }
+    // Keep the order locked until we have the target, so that the next job
+    // can't write before these.
+    let mut target = ctx.target.lock().unwrap();
+    drop(order);
+    for spooled in ready {
+        spooled.write_to(&mut target)?;
+    }
+    Ok(())
+}
+
+/// Write `size` bytes generated by `f` to stdout, or as a tar entry `name`.
+fn write_target<W: std::io::Write>(
+    target: &mut OutputTarget<W>,
+    name: &str,
+    size: u64,
+    f: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    match target {
+        OutputTarget::Directory(_) => unreachable!(),
+        OutputTarget::Stdout(ref mut s) => {
+            f(s)?;
+        }
+        OutputTarget::Tar {
+            builder: ref mut t,
+            mtime,
+        } => {
+            // Everything but the name, size and contents is fixed, so
+            // that the stream is reproducible.
+            let mut h = tar::Header::new_gnu();
+            h.set_entry_type(tar::EntryType::Regular);
+            h.set_mode(0o644);
+            h.set_uid(0);
+            h.set_gid(0);
+            h.set_size(size);
+            h.set_mtime(*mtime);
+            // This writes the header, preceded by a GNU long name entry
+            // if `name` doesn't fit in it.  We have a writer, not a reader,
+            // so we pass no data, and write the contents and padding ourselves.
+            t.append_data(&mut h, name, std::io::empty())?;
+            let w = t.get_mut();
+            let mut w = CountingWriter::new(w);
+            f(&mut w)?;
+            if w.count != size {
+                return Err(anyhow!(
+                    "Generated {} bytes for {}, expected {}",
+                    w.count,
+                    name,
+                    size
+                ));
+            }
+            let pad = (512 - (size % 512)) % 512;
+            w.inner.write_all(&vec![0u8; pad as usize])?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts bytes written through it.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write uncompressed data of a known `size` generated by `f` to the output as
+/// `name`, compressing it if requested for the artifact `a`.  If `expected` is
+/// provided, the SHA-256 of the uncompressed data is validated as it is written.
+fn write_image<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    a: &Artifact,
+    name: &str,
+    size: u64,
+    expected: Option<&str>,
+    f: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let generate = |w: &mut dyn Write| match expected {
+        Some(expected) => {
+            let mut w = utils::Sha256Writer::new(w);
+            f(&mut w)?;
+            w.verify(name, expected)?;
+            Ok(())
+        }
+        None => f(w),
+    };
+    match ctx.compress.for_filename(a.filename()) {
+        Some(c) => {
+            let name = &format!("{}.{}", name, c.extension());
+            write_stream(ctx, name, None, |w| {
+                let mut w = c.encoder(w, ctx.threads)?;
+                generate(&mut w)?;
+                w.finish()?;
+                Ok(())
+            })?;
+            info!("Compressed: {}", name);
+        }
+        None => write_stream(ctx, name, Some(size), generate)?,
+    }
+    Ok(())
+}
+
+/// Write an artifact generated by `f` to the output, validating its
+/// uncompressed SHA-256 as it is written.  Note that when streaming, data has
+/// already been written by the time a mismatch is detected; we return an
+/// error (and hence exit with a failure) in that case.
+fn write_artifact<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    a: &Artifact,
+    size: u64,
+    f: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let name = uncompressed_name(a.filename());
+    if ctx.skip_validate {
+        write_image(ctx, a, name, size, None, f)?;
+        info!("Generated (but skipped SHA-256 validation): {}", name);
+        return Ok(());
+    }
+    // We only have the SHA-256 of the compressed artifact.
+    if !bundle::can_validate(a) {
+        write_image(ctx, a, name, size, None, f)?;
+        info!("Generated (but can't validate SHA-256): {}", name);
+        return Ok(());
+    }
+    let expected = a
+        .uncompressed_sha256
+        .as_deref()
+        .unwrap_or_else(|| a.sha256.as_str());
+    write_image(ctx, a, name, size, Some(expected), f)?;
+    debug!("Validated {}", expected);
+    info!("Generated: {}", name);
+    Ok(())
+}
+
+/// Copy an existing file to the output as the artifact `a`, validating it.
+pub(crate) fn write_artifact_from<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    a: &Artifact,
+    path: impl AsRef<Utf8Path>,
+) -> Result<()> {
+    let path = path.as_ref();
+    let size = path.metadata()?.len();
+    write_artifact(ctx, a, size, |w| {
+        let mut src = BufReader::new(File::open(path)?);
+        std::io::copy(&mut src, w)?;
+        Ok(())
+    })
+}
+
+/// Apply a delta to `src`, streaming the result to the output.
+fn write_artifact_from_delta<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    a: &Artifact,
+    src: &Utf8Path,
+    patch: &Utf8Path,
+) -> Result<()> {
+    let size = rsync::read_header(patch)?.target.size;
+    write_artifact(ctx, a, size, |w| {
+        rsync::apply_to(ctx.sources, src, patch, w)?;
+        Ok(())
+    })
+}
+
+/// Load the dehydrated images in `dir` for `arch` (or the host architecture),
+/// returning the directory containing them and the parsed stream.
+pub(crate) fn load_bundle(dir: &Utf8Path, arch: Option<&str>) -> Result<(Utf8PathBuf, RiverDelta)> {
+    let arch = arch.map(String::from).unwrap_or_else(riverdelta::host_arch);
+    let srcdir = dir.join(&arch);
+    if !srcdir.exists() {
+        let dir = dir.to_owned();
+        return Err(bundle::Error::MissingArch { dir, arch }.into());
+    }
+    let stream_path = dir.join(STREAM_FILE);
+    let s = File::open(stream_path).context("Failed to open stream.json")?;
+    let s: CoreStream = serde_json::from_reader(std::io::BufReader::new(s))?;
+    let strategy_path = &srcdir.join(STRATEGY_FILE);
+    let strategies = if strategy_path.exists() {
+        StrategyTable::load(strategy_path)?
+    } else {
+        StrategyTable::default()
+    };
+    let riverdelta = RiverDelta::new(s, &arch, &strategies)?;
+    Ok((srcdir, riverdelta))
+}
+
+/// Regenerate the images in `sel` from `bundle` to `output`; see
+/// [`Bundle::rehydrate_selection`].
+pub(crate) fn rehydrate_selection<W: std::io::Write + Send>(
+    bundle: &Bundle,
+    sel: &Selection,
+    compress: Compress,
+    jobs: usize,
+    output: Output<W>,
+) -> Result<()> {
+    let pxe_or_iso = sel.iso || sel.pxe;
+    if sel.disks.is_empty() && !pxe_or_iso && !sel.all {
+        return Err(anyhow!("No images specified"));
+    }
+
+    let tmpdir = match bundle.tmpdir.as_ref() {
+        Some(d) => tempfile::tempdir_in(d)?,
+        None => tempfile::tempdir()?,
+    };
+    let tmpdir: &Utf8Path = tmpdir.path().try_into()?;
+
+    // PXE is multiple things.
+    let have_multiple = sel.disks.len() + sel.iso as usize > 1 || sel.pxe || sel.all;
+    let (srcdir, riverdelta) = (&bundle.srcdir, &bundle.rd);
+    let target = match output {
+        Output::Stream(w) if have_multiple => OutputTarget::Tar {
+            builder: tar::Builder::new(w),
+            mtime: riverdelta.mtime()?,
+        },
+        Output::Stream(w) => OutputTarget::Stdout(w),
+        Output::Directory(d) => OutputTarget::Directory(d),
+    };
+    let ctx = &RehydrateContext {
+        compress,
+        threads: jobs as u32,
+        skip_validate: bundle.skip_validate,
+        srcdir,
+        tmpdir,
+        output: target.kind(),
+        target: Arc::new(Mutex::new(target)),
+        order: Default::default(),
+        job: 0,
+        sources: &bundle.sources,
+    };
+
+    // Gather the requested artifacts.
+    let mut entries = Vec::new();
+    for &(wanted, format) in [(sel.iso, "iso"), (sel.pxe, "pxe")].iter() {
+        let n = entries.len();
+        if wanted {
+            entries.extend(riverdelta.find(riverdelta::METAL, format));
+            if entries.len() == n {
+                return Err(anyhow!("Missing metal/{}", format));
+            }
+        }
+    }
+    for disk in sel.disks.iter().filter(|&s| s != riverdelta::QEMU) {
+        if riverdelta.is_unhandled(disk) {
+            return Err(anyhow!("Unhandled artifact: {}", disk));
+        }
+        let e = riverdelta
+            .find_disk(disk)
+            .ok_or_else(|| anyhow!("Unknown artifact: {}", disk))?;
+        entries.push(e);
+    }
+
+    let qemu = &riverdelta.qemu;
+    let mut want_qemu = sel.disks.iter().any(|s| s.as_str() == riverdelta::QEMU);
+    // Source artifacts which are included as is, but aren't otherwise requested.
+    let mut sources = Vec::new();
+    if sel.all {
+        for a in riverdelta.all_artifacts() {
+            if let Some(e) = riverdelta
+                .entries
+                .iter()
+                .find(|e| e.artifact.location == a.location)
+            {
+                entries.push(e);
+            } else if a.location == qemu.location {
+                want_qemu = true;
+            } else {
+                sources.push(a);
+            }
+        }
+    }
+
+    // Everything to generate, sorted by filename, which is the order it's
+    // written to stdout.
+    let mut items = Vec::new();
+    if want_qemu {
+        items.push(Item::Qemu);
+    }
+    items.extend(sources.into_iter().map(Item::Source));
+    items.extend(entries.into_iter().map(Item::Entry));
+    items.sort_by(|a, b| item_filename(riverdelta, a).cmp(item_filename(riverdelta, b)));
+    items.dedup_by(|a, b| item_filename(riverdelta, a) == item_filename(riverdelta, b));
+    // Jobs needing the qemu image run once it's decompressed; the others
+    // run alongside that.
+    let (after_qemu, independent): (Vec<_>, Vec<_>) = items
+        .into_iter()
+        .enumerate()
+        .partition(|(_, item)| needs_qemu(item));
+    let qemu_fn = &tmpdir.join(uncompressed_name(qemu.filename()));
+    let error = &Mutex::new(None);
+    let run = &|job: usize, item: &Item| {
+        if error.lock().unwrap().is_some() {
+            return;
+        }
+        let r = rehydrate_item(&ctx.for_job(job), riverdelta, qemu_fn, item)
+            .and_then(|_| finish_job(ctx, job));
+        if let Err(e) = r {
+            error.lock().unwrap().get_or_insert(e);
+        }
+    };
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(jobs).build()?;
+    pool.scope(|s| {
+        for (job, item) in independent {
+            s.spawn(move |_| run(job, &item));
+        }
+        if after_qemu.is_empty() {
+            return;
+        }
+        s.spawn(move |s| {
+            if let Err(e) = decompress_qemu(srcdir, qemu, qemu_fn, bundle.base.as_deref()) {
+                error.lock().unwrap().get_or_insert(e);
+                return;
+            }
+            for (job, item) in after_qemu {
+                s.spawn(move |_| run(job, &item));
+            }
+        });
+    });
+    if let Some(e) = error.lock().unwrap().take() {
+        return Err(e);
+    }
+
+    let mut target = ctx.target.lock().unwrap();
+    match &mut *target {
+        OutputTarget::Directory(_) => {}
+        OutputTarget::Stdout(s) => {
+            s.flush()?;
+        }
+        OutputTarget::Tar { builder, .. } => {
+            builder.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Decompress the qemu image stored in `srcdir` to `dest`; it's the base
+/// from which most other images are generated.  If it's stored as a delta
+/// from a previous release, that's read from the `base` bundle.
+pub(crate) fn decompress_qemu(
+    srcdir: &Utf8Path,
+    qemu: &Artifact,
+    dest: &Utf8Path,
+    base: Option<&Utf8Path>,
+) -> Result<()> {
+    let qemu_zstd_path = &srcdir.join(format!("{}.zst", uncompressed_name(qemu.filename())));
+    if qemu_zstd_path.exists() {
+        zstd_decompress(qemu_zstd_path, dest)?;
+        info!("Unpacked source image: {}", dest);
+        return Ok(());
+    }
+    let base_name = Metadata::load(srcdir)?
+        .qemu_base
+        .ok_or_else(|| anyhow!("Missing {}", qemu_zstd_path))?;
+    let base = base.ok_or_else(|| bundle::Error::MissingBase(base_name.clone()))?;
+    let arch = srcdir.file_name().unwrap();
+    let base_zstd_path = &base.join(arch).join(format!("{}.zst", base_name));
+    if !base_zstd_path.exists() {
+        return Err(anyhow!("Missing {} in base bundle", base_zstd_path));
+    }
+    let tmpdir = dest.parent().unwrap();
+    let base_fn = tempfile::NamedTempFile::new_in(tmpdir)?.into_temp_path();
+    let base_fn = temppath_name(&base_fn)?;
+    zstd_decompress(base_zstd_path, base_fn)?;
+    let patch = srcdir.join(rdelta_name_for_artifact(qemu)?);
+    let sources = &rsync::SourceCache::default();
+    rsync::apply(sources, base_fn, dest.as_str(), tmpdir, patch)?;
+    info!("Unpacked source image from {}: {}", base_name, dest);
+    Ok(())
+}
+
+fn zstd_decompress(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    info!("Decompressing: {}", src);
+    let f = File::open(src).with_context(|| anyhow!("Opening {}", src))?;
+    let mut f = zstd::Decoder::new(f)?;
+    let mut o =
+        std::io::BufWriter::new(File::create(dest).with_context(|| anyhow!("Opening {}", dest))?);
+    std::io::copy(&mut f, &mut o).with_context(|| anyhow!("Failed to decompress {}", src))?;
+    o.flush()?;
+    Ok(())
+}
+
+/// The filename of the image generated for `item`.
+fn item_filename<'a>(riverdelta: &'a RiverDelta, item: &Item<'a>) -> &'a str {
+    let a = match item {
+        Item::Qemu => &riverdelta.qemu,
+        Item::Source(a) => a,
+        Item::Entry(e) => &e.artifact,
+        Item::Signature(name) => return name,
+    };
+    uncompressed_name(a.filename())
+}
+
+/// Whether generating `item` needs the decompressed qemu image.
+pub(crate) fn needs_qemu(item: &Item) -> bool {
+    match item {
+        Item::Qemu => true,
+        Item::Entry(e) => matches!(
+            e.strategy,
+            Strategy::RsyncFromQemu | Strategy::Vmdk | Strategy::Ova
+        ),
+        Item::Source(_) | Item::Signature(_) => false,
+    }
+}
+
+/// Regenerate an image; `qemu_fn` is the decompressed qemu image.
+pub(crate) fn rehydrate_item<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    riverdelta: &RiverDelta,
+    qemu_fn: &Utf8Path,
+    item: &Item,
+) -> Result<()> {
+    match item {
+        Item::Qemu => write_artifact_from(ctx, &riverdelta.qemu, qemu_fn),
+        Item::Source(a) => write_artifact_from(ctx, a, ctx.srcdir.join(a.filename())),
+        Item::Entry(e) => rehydrate_entry(ctx, riverdelta, qemu_fn, e),
+        Item::Signature(name) => {
+            let path = &ctx.srcdir.join(name);
+            let size = path.metadata()?.len();
+            write_stream(ctx, name, Some(size), |w| {
+                std::io::copy(&mut BufReader::new(File::open(path)?), w)?;
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Regenerate a single artifact according to its strategy.
+pub(crate) fn rehydrate_entry<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    riverdelta: &RiverDelta,
+    qemu_fn: &Utf8Path,
+    e: &Entry,
+) -> Result<()> {
+    let srcdir = ctx.srcdir;
+    let a = &e.artifact;
+    match e.strategy {
+        Strategy::Copy => write_artifact_from(ctx, a, srcdir.join(a.filename()))?,
+        Strategy::IsoFromRootfs => {
+            // Validated when parsing the stream
+            let rootfs = riverdelta.rootfs.as_ref().unwrap();
+            let patch = &srcdir.join(rdelta_name_for_artifact(a)?);
+            let rootfs = &srcdir.join(rootfs.filename());
+            write_artifact_from_delta(ctx, a, rootfs, patch)?;
+        }
+        Strategy::RsyncFromQemu => {
+            let patch = &srcdir.join(rdelta_name_for_artifact(a)?);
+            write_artifact_from_delta(ctx, a, qemu_fn, patch)?;
+        }
+        Strategy::Vmdk | Strategy::Ova => rehydrate_vmdk(ctx, qemu_fn, a)?,
+        Strategy::Skip => unreachable!(),
+    }
+    Ok(())
+}
+
+pub(crate) fn temppath_name(t: &tempfile::TempPath) -> Result<&Utf8Path> {
+    let p: &Path = t.as_ref();
+    let r = p.try_into()?;
+    Ok(r)
+}
+
+/// Regenerate a VMDK, or an OVA containing them, from the deltas of their
+/// uncompressed grains and the layout.
+fn rehydrate_vmdk<W: std::io::Write>(
+    ctx: &RehydrateContext<W>,
+    qemu_fn: &Utf8Path,
+    a: &Artifact,
+) -> Result<()> {
+    let layout = &vmdk_layout_for_artifact(ctx.srcdir, a)?;
+    // The options recorded for each VMDK; bundles from before they were
+    // recorded can't be rehydrated.
+    let meta = Metadata::load(ctx.srcdir)?;
+    let options = vmdk::layout_disk_names(layout)?
+        .into_iter()
+        .map(|disk| {
+            let key = vmdk_metadata_key(a, disk.as_deref());
+            meta.vmdk
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| bundle::Error::FormatTooOld(key).into())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut grains = Vec::new();
+    for name in vmdk_rdelta_names(a, options.len()) {
+        let mut f = tempfile::tempfile_in(ctx.tmpdir)?;
+        let patch = ctx.srcdir.join(name);
+        rsync::apply_to(ctx.sources, qemu_fn, patch, BufWriter::new(&mut f))?;
+        f.seek(SeekFrom::Start(0))?;
+        grains.push(BufReader::new(f));
+    }
+    if vmdk::reproducible(layout)? {
+        let size = vmdk::layout_size(layout)?;
+        return write_artifact(ctx, a, size, |w| {
+            vmdk::join(layout, &options, grains, w)?;
+            Ok(())
+        });
+    }
+    // Our zlib compresses differently, so the result won't match the
+    // original and there's nothing to validate it against.
+    let name = uncompressed_name(a.filename());
+    warn!(
+        "Can't reproduce the VMDK grains of {}; writing it anew",
+        name
+    );
+    let mut f = tempfile::tempfile_in(ctx.tmpdir)?;
+    vmdk::write(layout, &options, grains, ctx.tmpdir, BufWriter::new(&mut f))?;
+    let size = f.seek(SeekFrom::End(0))?;
+    f.seek(SeekFrom::Start(0))?;
+    write_image(ctx, a, name, size, None, |w| {
+        std::io::copy(&mut BufReader::new(f), w)?;
+        Ok(())
+    })?;
+    info!("Generated (but can't validate SHA-256): {}", name);
+    Ok(())
+}
+
+pub(crate) fn maybe_uncompressed_name(s: &str) -> Option<&str> {
+    s.strip_suffix(".xz").or_else(|| s.strip_suffix(".gz"))
+}
+
+pub(crate) fn uncompressed_name(s: &str) -> &str {
+    maybe_uncompressed_name(s).unwrap_or(s)
+}
+
+fn hardlink(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
+    let src = src.as_ref();
+    let dest = dest.as_ref();
+    info!("Including: {:?}", src);
+    std::fs::hard_link(src, dest).with_context(|| anyhow!("Hardlinking {:?}", src))?;
+    Ok(())
+}
+
+/// Write a zstd-compressed copy of `src` to `dest`.
+fn zstd_compress(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    let mut srcin = File::open(src)?;
+    let out = File::create(dest)?;
+    let mut out = zstd::Encoder::new(out, 10)?;
+    std::io::copy(&mut srcin, &mut out)?;
+    out.finish()?;
+    Ok(())
+}
+
+pub(crate) fn rdelta_name_for_artifact(a: &Artifact) -> Result<String> {
+    Ok(format!("{}.rdelta", uncompressed_name(a.filename())))
+}
+
+pub(crate) fn layout_name_for_artifact(a: &Artifact) -> String {
+    format!("{}.vmdk-layout", uncompressed_name(a.filename()))
+}
+
+/// The layout file of a VMDK artifact in `srcdir`; bundles from before VMDK
+/// layouts don't have one, and can't be rehydrated.
+pub(crate) fn vmdk_layout_for_artifact(srcdir: &Utf8Path, a: &Artifact) -> Result<Utf8PathBuf> {
+    let layout = srcdir.join(layout_name_for_artifact(a));
+    if !layout.exists() {
+        let name = uncompressed_name(a.filename()).to_string();
+        return Err(bundle::Error::FormatTooOld(name).into());
+    }
+    Ok(layout)
+}
+
+/// The names of the deltas of the uncompressed grains of each of the `disks`
+/// VMDKs in an artifact; the first is the usual delta.
+pub(crate) fn vmdk_rdelta_names(a: &Artifact, disks: usize) -> Vec<String> {
+    let name = uncompressed_name(a.filename());
+    (0..disks)
+        .map(|i| match i {
+            0 => format!("{}.rdelta", name),
+            i => format!("{}.{}.rdelta", name, i),
+        })
+        .collect()
+}
+
+/// The key in [`Metadata::vmdk`] for a VMDK artifact, or the VMDK `disk` in
+/// an OVA.
+fn vmdk_metadata_key(a: &Artifact, disk: Option<&Utf8Path>) -> String {
+    match disk {
+        Some(disk) => format!("{}/{}", a.filename(), disk),
+        None => a.filename().to_string(),
+    }
+}
+
+fn rsync_delta_impl(
+    src_fn: impl AsRef<Utf8Path>,
+    target: impl AsRef<Utf8Path>,
+    delta_path: impl AsRef<Utf8Path>,
+) -> Result<()> {
+    let src_fn = src_fn.as_ref();
+    let target_fn = target.as_ref();
+    let delta_path = delta_path.as_ref();
+    let mut output = std::io::BufWriter::new(File::create(delta_path)?);
+    rsync::prepare(src_fn, target_fn, &mut output)?;
+    output.flush()?;
+    let orig_size = target_fn.metadata()?.len();
+    let delta_size = delta_path.metadata()?.len();
+    info!(
+        "Dehydrated: {} ({:.5}%, {})",
+        target_fn,
+        ((delta_size as f64 / orig_size as f64) * 100f64),
+        indicatif::HumanBytes(delta_size)
+    );
+    Ok(())
+}
+
+#[context("Creating rsync delta")]
+fn rsync_delta(
+    dirs: &BuildDirs,
+    src: &Artifact,
+    target: &Artifact,
+    destdir: impl AsRef<Utf8Path>,
+) -> Result<bool> {
+    let destdir = destdir.as_ref();
+    let src_fn = &get_maybe_uncompressed(dirs, src)?;
+    let target_fn = &get_maybe_uncompressed(dirs, target)?;
+    let delta_path = &destdir.join(rdelta_name_for_artifact(target)?);
+    rsync_delta_impl(src_fn, target_fn, delta_path)?;
+    Ok(true)
+}
+
+pub(crate) fn read_stream(dirs: &BuildDirs) -> Result<CoreStream> {
+    let stream_path = &dirs.path(STREAM_FILE);
+    let s = File::open(stream_path).context("Failed to open stream.json")?;
+    let s: CoreStream = serde_json::from_reader(std::io::BufReader::new(s))?;
+    Ok(s)
+}
+
+fn cached_uncompressed_name(dirs: &BuildDirs, a: &Artifact) -> Option<Utf8PathBuf> {
+    maybe_uncompressed_name(a.filename()).map(|uncomp_name| dirs.cachedir().join(uncomp_name))
+}
+
+pub(crate) fn uncompressor_for(name: &Utf8Path, src: impl Read) -> Result<impl Read> {
+    let r = match name.extension() {
+        Some("xz") => either::Left(xz2::read::XzDecoder::new(src)),
+        Some("gz") => either::Right(flate2::read::GzDecoder::new(src)),
+        Some(other) => return Err(anyhow!("Unknown extension {}", other)),
+        None => return Err(anyhow!("No extension found for {}", name)),
+    };
+    Ok(r)
+}
+
+fn get_maybe_uncompressed(dirs: &BuildDirs, a: &Artifact) -> Result<Utf8PathBuf> {
+    let name = &dirs.path(a.filename());
+    let r = cached_uncompressed_name(dirs, a)
+        .map(|uncomp_name| {
+            if !uncomp_name.exists() {
+                let src = File::open(name).with_context(|| anyhow!("Failed to open {}", name))?;
+                let tmpname = format!("{}.tmp", uncomp_name);
+                let mut src = uncompressor_for(name, src)?;
+                let mut dest = std::io::BufWriter::new(File::create(&tmpname)?);
+                std::io::copy(&mut src, &mut dest)?;
+                dest.flush()?;
+                std::fs::rename(&tmpname, &uncomp_name)?;
+                info!("Uncompressed: {}", uncomp_name);
+            }
+            Ok::<_, anyhow::Error>(uncomp_name)
+        })
+        .transpose()?
+        .unwrap_or_else(|| name.clone());
+    Ok(r)
+}
+
+// Generate an image from its rsync delta.
+fn dehydrate_rsyncable(
+    dirs: &BuildDirs,
+    qemu: &Artifact,
+    target: &Artifact,
+    destdir: &Utf8Path,
+) -> Result<()> {
+    let _found: bool = rsync_delta(dirs, qemu, target, destdir)?;
+    Ok(())
+}
+
+/// The names of the VMDKs in an OVA, in order, or `None` for a VMDK artifact.
+fn vmdk_disk_names(dirs: &BuildDirs, e: &Entry) -> Result<Vec<Option<Utf8PathBuf>>> {
+    match e.strategy {
+        Strategy::Ova => {
+            let ova = ova::Ova::open(&get_maybe_uncompressed(dirs, &e.artifact)?)?;
+            Ok(ova.disks().map(|d| Some(d.name.clone())).collect())
+        }
+        _ => Ok(vec![None]),
+    }
+}
+
+/// Split a VMDK, or an OVA containing them, into its layout and deltas of
+/// the uncompressed grains of each VMDK from the qemu image, returning the
+/// options each VMDK was created with.
+fn dehydrate_vmdk(
+    dirs: &BuildDirs,
+    qemu: &Artifact,
+    e: &Entry,
+    destdir: &Utf8Path,
+) -> Result<Vec<vmdk::Options>> {
+    let target = &e.artifact;
+    let src_fn = &get_maybe_uncompressed(dirs, qemu)?;
+    let target_fn = &get_maybe_uncompressed(dirs, target)?;
+    let (disks, names) = match e.strategy {
+        Strategy::Ova => ova::Ova::open(target_fn)?
+            .disks()
+            .map(|d| ((d.offset, d.size), Some(d.name.clone())))
+            .unzip(),
+        _ => (vec![(0, target_fn.metadata()?.len())], vec![None]),
+    };
+    let deltas = vmdk_rdelta_names(target, disks.len());
+    let grains_fns: Vec<_> = deltas
+        .iter()
+        .map(|d| dirs.cachedir().join(d).with_extension("grains"))
+        .collect();
+    let layout = &destdir.join(layout_name_for_artifact(target));
+    let mut grains = grains_fns
+        .iter()
+        .map(|p| Ok(BufWriter::new(File::create(p)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let opts = vmdk::split(target_fn, &disks, &mut grains, layout, &dirs.cachedir())?;
+    drop(grains);
+    for ((o, name), (delta, grains_fn)) in
+        opts.iter().zip(names).zip(deltas.iter().zip(&grains_fns))
+    {
+        let key = vmdk_metadata_key(target, name.as_deref());
+        info!("VMDK options for {}: {}", key, o);
+        rsync_delta_impl(src_fn, grains_fn, destdir.join(delta))?;
+        std::fs::remove_file(grains_fn)?;
+    }
+    Ok(opts)
+}
+
+/// Find the qemu image in the `previous` bundle, returning its compressed
+/// path and artifact.  If the previous bundle doesn't have the architecture,
+/// returns `None`, and the image should be stored in full.
+#[context("Loading previous bundle {}", previous)]
+fn previous_qemu(previous: &Utf8Path, arch: &str) -> Result<Option<(Utf8PathBuf, Artifact)>> {
+    if !previous.join(arch).exists() {
+        info!("No {} images in previous bundle {}", arch, previous);
+        return Ok(None);
+    }
+    let (prevdir, prev) = load_bundle(previous, Some(arch))?;
+    let base_name = uncompressed_name(prev.qemu.filename());
+    let base_zstd_path = prevdir.join(format!("{}.zst", base_name));
+    // Rehydration would need a chain of bundles; keep it to one.
+    if !base_zstd_path.exists() {
+        return Err(anyhow!(
+            "Missing {}; the previous bundle must contain the full qemu image",
+            base_zstd_path
+        ));
+    }
+    Ok(Some((base_zstd_path, prev.qemu)))
+}
+
+/// Store the qemu image as a delta from the compressed qemu image of a previous bundle.
+#[context("Creating delta from previous bundle")]
+fn dehydrate_qemu_from_previous(
+    dirs: &BuildDirs,
+    base_zstd_path: &Utf8Path,
+    qemu_fn: &Utf8Path,
+    delta_path: &Utf8Path,
+) -> Result<()> {
+    let base_name = base_zstd_path.file_stem().unwrap();
+    let base_fn = &dirs.cachedir().join(format!("previous-{}", base_name));
+    zstd_decompress(base_zstd_path, base_fn)?;
+    rsync_delta_impl(base_fn, qemu_fn, delta_path)?;
+    std::fs::remove_file(base_fn)?;
+    Ok(())
+}
+
+/// Where the bundle for `arch` is generated in `topdir` before replacing any existing one.
+fn staging_dir(topdir: &Utf8Path, arch: &str) -> Utf8PathBuf {
+    topdir.join(format!(".{}.partial", arch))
+}
+
+/// Remove `p` if it exists.  Files in the staging directory may be hardlinks
+/// to the existing bundle, so they must be replaced rather than rewritten.
+fn remove_if_exists(p: impl AsRef<Utf8Path>) -> Result<()> {
+    let p = p.as_ref();
+    if p.exists() {
+        std::fs::remove_file(p).with_context(|| anyhow!("Removing {}", p))?;
+    }
+    Ok(())
+}
+
+/// Loop over stream metadata and generate dehydrated (~deduplicated) content
+/// for each requested architecture.  The content for each architecture is
+/// generated in a staging directory, reusing the outputs of any previous
+/// (possibly interrupted) run whose inputs are unchanged, and the existing
+/// bundle is only replaced once everything has been generated, and only
+/// removed once the stream metadata is replaced too.
+pub(crate) fn build_dehydrate(opts: &DehydrateOptions) -> Result<()> {
+    let stream_path = &opts.dirs.path(STREAM_FILE);
+    let topdir = &opts.dirs.output();
+    std::fs::create_dir_all(topdir)
+        .with_context(|| anyhow!("Failed to create destination directory: {}", topdir))?;
+    let strategies = &opts.strategies;
+    let arches = &opts.arches;
+    for arch in arches.iter() {
+        build_dehydrate_arch(opts, strategies, topdir, arch)?;
+    }
+    let mut old = Vec::new();
+    for arch in arches.iter() {
+        old.extend(finalize_arch(topdir, arch)?);
+    }
+    let stream_dest = &topdir.join(STREAM_FILE);
+    let stream_tmp = &topdir.join(format!(".{}.tmp", STREAM_FILE));
+    // Not a hardlink: renaming over another link to the same file does nothing.
+    std::fs::copy(stream_path, stream_tmp)?;
+    std::fs::rename(stream_tmp, stream_dest)?;
+    for p in old {
+        std::fs::remove_dir_all(&p).with_context(|| anyhow!("Removing {}", p))?;
+    }
+    Ok(())
+}
+
+/// Replace the bundle for `arch` in `topdir` with its staging directory,
+/// returning where the old one was moved, to be removed once the stream
+/// metadata is replaced too.
+#[context("Finalizing images for {}", arch)]
+fn finalize_arch(topdir: &Utf8Path, arch: &str) -> Result<Option<Utf8PathBuf>> {
+    let staging = &staging_dir(topdir, arch);
+    let destdir = &topdir.join(arch);
+    let old = topdir.join(format!(".{}.old", arch));
+    // Left by an interrupted run.
+    if old.exists() {
+        std::fs::remove_dir_all(&old)?;
+    }
+    let old = if destdir.exists() {
+        std::fs::rename(destdir, &old)?;
+        Some(old)
+    } else {
+        None
+    };
+    std::fs::rename(staging, destdir)?;
+    info!("Wrote: {}", destdir);
+    Ok(old)
+}
+
+fn build_dehydrate_arch(
+    opts: &DehydrateOptions,
+    strategies: &StrategyTable,
+    topdir: &Utf8Path,
+    arch: &str,
+) -> Result<()> {
+    info!("Dehydrating images for {}", arch);
+    let dirs = &opts.dirs;
+    let s = read_stream(dirs)?;
+    let riverdelta = RiverDelta::new(s, arch, strategies)?;
+
+    if !opts.allow_unhandled && !riverdelta.unhandled.is_empty() {
+        return Err(anyhow!(
+            "Unhandled artifacts (use --strategy to handle them): {:?}",
+            riverdelta.unhandled
+        ));
+    }
+
+    std::fs::create_dir_all(dirs.cachedir()).context("Creating cachedir")?;
+
+    let qemu = &riverdelta.qemu;
+    let destdir = &staging_dir(topdir, arch);
+    if destdir.exists() {
+        info!("Resuming: {}", destdir);
+    } else {
+        std::fs::create_dir(destdir)
+            .with_context(|| anyhow!("Failed to create destination directory: {}", destdir))?;
+        // Start from the existing bundle, so that we can reuse its outputs
+        let existing = topdir.join(arch);
+        if existing.exists() {
+            for e in std::fs::read_dir(&existing)? {
+                let e = e?;
+                if e.file_type()?.is_file() {
+                    std::fs::hard_link(e.path(), destdir.as_std_path().join(e.file_name()))?;
+                }
+            }
+        }
+    }
+    let state = &state::State::load(destdir)?;
+    // Everything which should be in the bundle; anything else is removed.
+    let mut outputs: BTreeSet<String> = [STRATEGY_FILE, METADATA_FILE, state::STATE_FILE]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+
+    // Record how we dehydrated, so rehydration doesn't depend on the defaults
+    {
+        let p = &destdir.join(STRATEGY_FILE);
+        remove_if_exists(p)?;
+        let w = BufWriter::new(File::create(p)?);
+        serde_json::to_writer_pretty(w, strategies)?;
+    }
+
+    // Source artifacts and signatures which are included as is
+    let mut included = Vec::new();
+    for e in riverdelta.entries.iter() {
+        if e.strategy == Strategy::Copy {
+            included.push(e.artifact.filename());
+        }
+    }
+    // Signatures remain valid for images we regenerate bit-for-bit
+    for a in riverdelta.all_artifacts() {
+        if let Some(sig) = a.signature_filename() {
+            if dirs.path(sig).exists() {
+                included.push(sig);
+            }
+        }
+    }
+    // The rootfs (squashfs-in-cpio) is a source artifact for the ISO
+    if let Some(rootfs) = riverdelta.rootfs.as_ref() {
+        if !included.contains(&rootfs.filename()) {
+            included.push(rootfs.filename());
+        }
+    }
+    for name in included {
+        let dest = &destdir.join(name);
+        remove_if_exists(dest)?;
+        hardlink(dirs.path(name), dest)?;
+        outputs.insert(name.to_string());
+    }
+
+    // The deltas which need to be generated, along with what they're
+    // generated from.
+    let mut work = Vec::new();
+    // The delta of each VMDK, under which its options are recorded in the
+    // state, and its key in the metadata.
+    let mut vmdk_keys = Vec::new();
+    for e in riverdelta.entries.iter() {
+        let delta = rdelta_name_for_artifact(&e.artifact)?;
+        let mut vmdk_deltas = Vec::new();
+        let (src, names) = match e.strategy {
+            Strategy::RsyncFromQemu => (qemu, vec![delta]),
+            Strategy::Vmdk | Strategy::Ova => {
+                let a = &e.artifact;
+                let disks = vmdk_disk_names(dirs, e)?;
+                vmdk_deltas = vmdk_rdelta_names(a, disks.len());
+                for (delta, disk) in vmdk_deltas.iter().zip(disks) {
+                    vmdk_keys.push((delta.clone(), vmdk_metadata_key(a, disk.as_deref())));
+                }
+                let mut names = vmdk_deltas.clone();
+                names.push(layout_name_for_artifact(a));
+                (qemu, names)
+            }
+            // Validated when parsing the stream
+            Strategy::IsoFromRootfs => (riverdelta.rootfs.as_ref().unwrap(), vec![delta]),
+            Strategy::Copy | Strategy::Skip => continue,
+        };
+        let inputs = vec![
+            e.strategy.to_string(),
+            src.sha256.clone(),
+            e.artifact.sha256.clone(),
+        ];
+        let mut current = true;
+        for name in names.iter() {
+            current &= state.is_current(name, &inputs)?;
+        }
+        for name in vmdk_deltas.iter() {
+            current &= state.vmdk_options(name).is_some();
+        }
+        if current {
+            info!("Unchanged: {}", names.join(" "));
+        } else {
+            work.push((e, names.clone(), inputs));
+        }
+        outputs.extend(names);
+    }
+
+    // The qemu image is stored compressed, or as a delta from a previous release.
+    let previous = match opts.previous.as_deref() {
+        Some(previous) => previous_qemu(previous, arch)?,
+        None => None,
+    };
+    let (qemu_out, qemu_inputs) = match previous.as_ref() {
+        Some((_, prev)) => (
+            rdelta_name_for_artifact(qemu)?,
+            vec![prev.sha256.clone(), qemu.sha256.clone()],
+        ),
+        None => (
+            format!("{}.zst", uncompressed_name(qemu.filename())),
+            vec![qemu.sha256.clone()],
+        ),
+    };
+    let qemu_current = state.is_current(&qemu_out, &qemu_inputs)? && state.qemu_size().is_some();
+    if qemu_current {
+        info!("Unchanged: {}", qemu_out);
+    }
+    outputs.insert(qemu_out.clone());
+    let need_qemu = !qemu_current
+        || work
+            .iter()
+            .any(|(e, _, _)| e.strategy != Strategy::IsoFromRootfs);
+    let uncomp_qemu = &if need_qemu {
+        get_maybe_uncompressed(dirs, qemu)?
+    } else {
+        Utf8PathBuf::new()
+    };
+
+    // Add some parallelism
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.jobs)
+        .build()?;
+    pool.install(|| {
+        work.par_iter().try_for_each(|(e, names, inputs)| {
+            for name in names.iter() {
+                remove_if_exists(destdir.join(name))?;
+            }
+            match e.strategy {
+                Strategy::RsyncFromQemu => dehydrate_rsyncable(dirs, qemu, &e.artifact, destdir),
+                Strategy::Vmdk | Strategy::Ova => {
+                    let opts = dehydrate_vmdk(dirs, qemu, e, destdir)?;
+                    for (name, opts) in names.iter().zip(opts) {
+                        state.set_vmdk_options(name, opts)?;
+                    }
+                    Ok(())
+                }
+                Strategy::IsoFromRootfs => {
+                    let rootfs = riverdelta.rootfs.as_ref().unwrap();
+                    dehydrate_rsyncable(dirs, rootfs, &e.artifact, destdir)
+                }
+                Strategy::Copy | Strategy::Skip => unreachable!(),
+            }?;
+            for name in names.iter() {
+                state.record(name, inputs)?;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+    })?;
+
+    if !qemu_current {
+        let qemu_dest = &destdir.join(&qemu_out);
+        remove_if_exists(qemu_dest)?;
+        match previous.as_ref() {
+            Some((base_zstd_path, _)) => {
+                dehydrate_qemu_from_previous(dirs, base_zstd_path, uncomp_qemu, qemu_dest)?
+            }
+            None => {
+                info!("Including (zstd compressed): {}", uncomp_qemu);
+                zstd_compress(uncomp_qemu, qemu_dest)?;
+            }
+        }
+        state.record(&qemu_out, &qemu_inputs)?;
+        state.set_qemu_size(uncomp_qemu.metadata()?.len())?;
+    }
+
+    // Remove anything left over from a previous run
+    state.retain(&outputs)?;
+    for e in std::fs::read_dir(destdir)? {
+        let e = e?;
+        let name = e.file_name();
+        if !matches!(name.to_str(), Some(n) if outputs.contains(n)) {
+            info!("Removing: {:?}", name);
+            std::fs::remove_file(e.path())?;
+        }
+    }
+
+    let original_artifact_size = riverdelta.original_compressed_size(&dirs.workdir)?;
+    let new_size = outputs
+        .iter()
+        .try_fold(0u64, |acc, name| {
+            let p = &destdir.join(name);
+            let l = if p.exists() { p.metadata()?.len() } else { 0 };
+            Ok::<_, anyhow::Error>(acc + l)
+        })
+        .context("Computing new size")?;
+
+    // Write metadata JSON
+    {
+        let vmdk = vmdk_keys
+            .into_iter()
+            .filter_map(|(delta, key)| Some((key, state.vmdk_options(&delta)?)))
+            .collect();
+        let metadata = Metadata {
+            original_artifact_size,
+            qemu_size: state.qemu_size(),
+            qemu_base: previous.map(|(_, prev)| uncompressed_name(prev.filename()).to_string()),
+            vmdk,
+        };
+        let p = &destdir.join(METADATA_FILE);
+        remove_if_exists(p)?;
+        let w = std::io::BufWriter::new(File::create(p)?);
+        serde_json::to_writer_pretty(w, &metadata)?;
+    }
+
+    info!(
+        "Original artifact total size: {}",
+        indicatif::HumanBytes(original_artifact_size)
+    );
+    info!(
+        "Dehydrated artifact total size: {}",
+        indicatif::HumanBytes(new_size)
+    );
+
+    if !riverdelta.unhandled.is_empty() {
+        assert!(opts.allow_unhandled);
+        let s = std::io::stdout();
+        let mut s = s.lock();
+        write!(s, "Unhandled:")?;
+        for k in riverdelta.unhandled.iter() {
+            write!(s, " {}", k)?;
+        }
+        writeln!(s, "")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_suffix() {
+        assert_eq!(uncompressed_name("foo.xz"), "foo");
+    }
+
+    #[test]
+    fn test_output_order() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let ctx = RehydrateContext {
+            compress: Compress::None,
+            threads: 1,
+            skip_validate: false,
+            srcdir: td.path().try_into()?,
+            tmpdir: td.path().try_into()?,
+            target: Arc::new(Mutex::new(OutputTarget::Tar {
+                builder: tar::Builder::new(Vec::new()),
+                mtime: 1621284106,
+            })),
+            output: OutputKind::Tar,
+            order: Default::default(),
+            job: 0,
+            sources: &Default::default(),
+        };
+        let write = |job: usize, name: &str, size: Option<u64>| {
+            write_stream(&ctx.for_job(job), name, size, |w| {
+                w.write_all(name.as_bytes())?;
+                Ok(())
+            })
+        };
+        // Jobs finishing out of order are written in order.
+        write(2, "c", Some(1))?;
+        finish_job(&ctx, 2)?;
+        write(1, "b1", None)?;
+        write(0, "a", None)?;
+        finish_job(&ctx, 0)?;
+        // Job 1 now streams directly.
+        write(1, "b2", Some(2))?;
+        finish_job(&ctx, 1)?;
+        // Names too long for the header get a GNU long name entry.
+        let long = &"d".repeat(150)[..];
+        write(3, long, Some(150))?;
+        finish_job(&ctx, 3)?;
+        let target = Arc::try_unwrap(ctx.target)
+            .ok()
+            .unwrap()
+            .into_inner()
+            .unwrap();
+        let buf = match target {
+            OutputTarget::Tar { builder, .. } => builder.into_inner()?,
+            _ => unreachable!(),
+        };
+        let mut names = Vec::new();
+        for e in tar::Archive::new(buf.as_slice()).entries()? {
+            let mut e = e?;
+            let mut data = String::new();
+            e.read_to_string(&mut data)?;
+            assert_eq!(e.path()?.to_str(), Some(data.as_str()));
+            let h = e.header();
+            assert_eq!(h.mtime()?, 1621284106);
+            assert_eq!((h.uid()?, h.gid()?, h.mode()?), (0, 0, 0o644));
+            names.push(data);
+        }
+        assert_eq!(names, ["a", "b1", "b2", "c", long]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_parallel() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let target = OutputTarget::Stdout(Vec::new());
+        let ctx = &RehydrateContext {
+            compress: Compress::None,
+            threads: 1,
+            skip_validate: false,
+            srcdir: td.path().try_into()?,
+            tmpdir: td.path().try_into()?,
+            output: target.kind(),
+            target: Arc::new(Mutex::new(target)),
+            order: Default::default(),
+            job: 0,
+            sources: &Default::default(),
+        };
+        let write = |job: usize, data: &str| {
+            write_stream(&ctx.for_job(job), data, Some(data.len() as u64), |w| {
+                w.write_all(data.as_bytes())?;
+                Ok(())
+            })
+        };
+        // Jobs 1 to 3 generate their outputs and finish while job 0 is
+        // streaming.
+        let (tx, rx) = std::sync::mpsc::channel();
+        let streaming = &std::sync::Barrier::new(4);
+        let r0 = Mutex::new(None);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build()?;
+        pool.scope(|s| {
+            let r0 = &r0;
+            s.spawn(move |_| {
+                let r = write_stream(&ctx.for_job(0), "a", Some(1), |w| {
+                    streaming.wait();
+                    let mut finished = Vec::new();
+                    for _ in 1..=3 {
+                        let job = rx
+                            .recv_timeout(std::time::Duration::from_secs(30))
+                            .context("Jobs were blocked")??;
+                        finished.push(job);
+                    }
+                    finished.sort_unstable();
+                    assert_eq!(finished, [1, 2, 3]);
+                    w.write_all(b"a")?;
+                    Ok(())
+                })
+                .and_then(|_| finish_job(ctx, 0));
+                *r0.lock().unwrap() = Some(r);
+            });
+            for (job, data) in [(1, "b"), (2, "c"), (3, "d")].iter().copied() {
+                let tx = tx.clone();
+                s.spawn(move |_| {
+                    streaming.wait();
+                    let r = write(job, data).and_then(|_| finish_job(ctx, job));
+                    tx.send(r.map(|_| job)).unwrap();
+                });
+            }
+        });
+        r0.into_inner().unwrap().unwrap()?;
+        match &*ctx.target.lock().unwrap() {
+            OutputTarget::Stdout(buf) => assert_eq!(buf, b"abcd"),
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Write `data` to `name` in `dir`, returning its stream metadata.
+    fn write_artifact_file(dir: &Utf8Path, name: &str, data: &[u8]) -> Result<serde_json::Value> {
+        std::fs::write(dir.join(name), data)?;
+        Ok(serde_json::json!({
+            "location": format!("https://example.com/{}", name),
+            "signature": format!("https://example.com/{}.sig", name),
+            "sha256": sha256(data)?,
+        }))
+    }
+
+    fn sha256(data: &[u8]) -> Result<String> {
+        let mut w = utils::Sha256Writer::new(std::io::sink());
+        w.write_all(data)?;
+        Ok(w.finish().1)
+    }
+
+    /// Disk data which compresses (but not too well), with runs of zeroes.
+    fn disk_data(seed: u32, len: usize) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|i| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                match (i / 65536) % 3 {
+                    1 => 0,
+                    _ => b"coreos "[(x >> 16) as usize % 7],
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_finalize_arch() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        for (d, name) in &[
+            ("x86_64", "old"),
+            (".x86_64.partial", "new"),
+            (".x86_64.old", "stale"),
+        ] {
+            std::fs::create_dir(dir.join(d))?;
+            std::fs::write(dir.join(d).join(name), name)?;
+        }
+        // The old bundle is kept until it's removed.
+        let old = finalize_arch(dir, "x86_64")?.unwrap();
+        assert_eq!(old, dir.join(".x86_64.old"));
+        assert!(old.join("old").exists());
+        assert!(dir.join("x86_64/new").exists());
+        assert!(!dir.join(".x86_64.partial").exists());
+        std::fs::remove_dir_all(old)?;
+        std::fs::create_dir(dir.join(".aarch64.partial"))?;
+        assert_eq!(finalize_arch(dir, "aarch64")?, None);
+        assert!(dir.join("aarch64").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dehydrate_ova() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let qemu = disk_data(1, 6 * 65536);
+        let mut disk1 = qemu.clone();
+        disk1[1000..1006].copy_from_slice(b"vmware");
+        let disk1 = &vmdk::tests::default_fixture(&disk1)?;
+        let disk2 = &vmdk::tests::default_fixture(&disk_data(2, 2 * 65536))?;
+        let ovf = format!(
+            r#"<?xml version="1.0"?>
+<Envelope><References>
+<File ovf:href="disk.vmdk" ovf:id="file1" ovf:size="{}"/>
+<File ovf:href="disk2.vmdk" ovf:id="file2" ovf:size="{}"/>
+</References><DiskSection>
+<Disk ovf:capacity="{}" ovf:diskId="vmdisk1" ovf:fileRef="file1"/>
+<Disk ovf:capacity="{}" ovf:diskId="vmdisk2" ovf:fileRef="file2"/>
+</DiskSection></Envelope>
+"#,
+            disk1.len(),
+            disk2.len(),
+            6 * 65536,
+            2 * 65536
+        );
+        let ovf = ovf.as_bytes();
+        let mut manifest = String::new();
+        for (name, data) in &[
+            ("coreos.ovf", ovf),
+            ("disk.vmdk", disk1),
+            ("disk2.vmdk", disk2),
+        ] {
+            manifest.push_str(&format!("SHA256({})= {}\n", name, sha256(data)?));
+        }
+        let members: &[(&str, &[u8])] = &[
+            ("coreos.ovf", ovf),
+            ("coreos.mf", manifest.as_bytes()),
+            ("coreos.cert", b"cert"),
+            ("disk.vmdk", disk1),
+            ("coreos.nvram", b"nvram"),
+            ("disk2.vmdk", disk2),
+        ];
+        let mut b = tar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let mut h = tar::Header::new_ustar();
+            h.set_mode(0o644);
+            h.set_mtime(1_620_000_000);
+            h.set_size(data.len() as u64);
+            b.append_data(&mut h, name, *data)?;
+        }
+        let ova = &b.into_inner()?;
+        let ova_name = "fcos-vmware.x86_64.ova";
+        std::fs::write(dir.join(format!("{}.sig", ova_name)), b"sig")?;
+        let stream = serde_json::json!({
+            "stream": "stable",
+            "metadata": { "last-modified": "2021-05-05T08:57:10Z" },
+            "architectures": { "x86_64": { "artifacts": {
+                "qemu": { "release": "1", "formats": { "qcow2": {
+                    "disk": write_artifact_file(dir, "fcos-qemu.x86_64.qcow2", &qemu)?,
+                } } },
+                "vmware": { "release": "1", "formats": { "ova": {
+                    "disk": write_artifact_file(dir, ova_name, ova)?,
+                } } },
+            } } },
+        });
+        std::fs::write(dir.join(STREAM_FILE), serde_json::to_vec(&stream)?)?;
+
+        let opts = DehydrateOptions {
+            dirs: BuildDirs {
+                workdir: dir.to_owned(),
+                ..Default::default()
+            },
+            arches: vec!["x86_64".to_string()],
+            jobs: 1,
+            ..Default::default()
+        };
+        build_dehydrate(&opts)?;
+        let bundle = Bundle::open(dir.join(DIR), Some("x86_64"))?;
+        let images = bundle.images()?;
+        let image = images.iter().find(|i| i.filename == ova_name).unwrap();
+        assert!(image.validated);
+        assert_eq!(image.size, Some(ova.len() as u64));
+        assert_eq!(image.vmdk_options.len(), 2);
+        // The OVA is regenerated as it was, so its signature is still valid.
+        let sig_name = &format!("{}.sig", ova_name);
+        assert!(matches!(bundle.find(sig_name), Some(Item::Signature(_))));
+        assert_eq!(bundle.rehydrate_to(sig_name, Vec::new())?, b"sig");
+        let rehydrated = &bundle.rehydrate_to(ova_name, Vec::new())?;
+        assert_eq!(rehydrated, ova);
+
+        // Every member is kept, and the manifest has the digests of the
+        // regenerated disks.
+        let check = |ova: &[u8]| -> Result<BTreeMap<String, Vec<u8>>> {
+            let mut contents = BTreeMap::new();
+            for e in tar::Archive::new(ova).entries()? {
+                let mut e = e?;
+                let mut data = Vec::new();
+                e.read_to_end(&mut data)?;
+                contents.insert(e.path()?.to_str().unwrap().to_string(), data);
+            }
+            let names: BTreeSet<_> = members.iter().map(|m| m.0.to_string()).collect();
+            assert_eq!(contents.keys().cloned().collect::<BTreeSet<_>>(), names);
+            let manifest = String::from_utf8(contents["coreos.mf"].clone())?;
+            let mut digests = 0;
+            for line in manifest.lines() {
+                let (k, digest) = line.split_once("= ").unwrap();
+                let name = k
+                    .strip_prefix("SHA256(")
+                    .unwrap()
+                    .strip_suffix(')')
+                    .unwrap();
+                assert_eq!(sha256(&contents[name])?, digest);
+                digests += 1;
+            }
+            assert_eq!(digests, 3);
+            Ok(contents)
+        };
+        check(rehydrated)?;
+
+        // With a zlib which compresses differently, the disks are written
+        // anew, and can't be validated.  The OVF descriptor has their new
+        // sizes.
+        let layout = &dir
+            .join(DIR)
+            .join("x86_64")
+            .join(format!("{}.vmdk-layout", ova_name));
+        let mut f = std::fs::read(layout)?;
+        f[21] ^= 1;
+        std::fs::write(layout, f)?;
+        let images = bundle.images()?;
+        let image = images.iter().find(|i| i.filename == ova_name).unwrap();
+        assert!(!image.validated);
+        assert_eq!(image.size, None);
+        assert!(bundle.find(sig_name).is_none());
+        let written = &bundle.rehydrate_to(ova_name, Vec::new())?;
+        assert_ne!(written, ova);
+        let contents = check(written)?;
+        for (name, data) in members {
+            if name.ends_with(".vmdk") {
+                let grains = vmdk::tests::grains_of(&contents[*name])?;
+                assert_eq!(grains, vmdk::tests::grains_of(data)?);
+            } else if *name != "coreos.mf" && *name != "coreos.ovf" {
+                assert_eq!(&contents[*name], data);
+            }
+        }
+        let files = ova::Ovf::parse(std::str::from_utf8(&contents["coreos.ovf"])?)?.files;
+        for f in files {
+            assert_eq!(f.size, Some(contents[&f.href].len() as u64));
+        }
+
+        // The descriptors must still have the recorded options, and bundles
+        // without them must be dehydrated again.
+        let srcdir = &dir.join(DIR).join("x86_64");
+        let mut meta = Metadata::load(srcdir)?;
+        for o in meta.vmdk.values_mut() {
+            o.adapter_type = "ide".to_string();
+        }
+        std::fs::write(srcdir.join(METADATA_FILE), serde_json::to_vec(&meta)?)?;
+        assert!(bundle.rehydrate_to(ova_name, Vec::new()).is_err());
+        meta.vmdk.clear();
+        std::fs::write(srcdir.join(METADATA_FILE), serde_json::to_vec(&meta)?)?;
+        assert!(matches!(
+            bundle.rehydrate_to(ova_name, Vec::new()),
+            Err(bundle::Error::FormatTooOld(_))
+        ));
+
+        // OVAs in bundles from before VMDK layouts must be dehydrated again.
+        std::fs::remove_file(layout)?;
+        assert!(matches!(
+            bundle.images(),
+            Err(bundle::Error::FormatTooOld(_))
+        ));
+        assert!(matches!(
+            bundle.rehydrate_to(ova_name, Vec::new()),
+            Err(bundle::Error::FormatTooOld(_))
+        ));
+        Ok(())
+    }
+}
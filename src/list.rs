@@ -0,0 +1,124 @@
+//! Show the images which can be generated from a dehydrated bundle.
+
+use anyhow::Result;
+use camino::Utf8Path;
+use coreos_diskimage_rehydrator::{Bundle, Image};
+use serde_derive::Serialize;
+use std::io::Write;
+
+/// The images in a bundle for a single architecture.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Listing {
+    stream: String,
+    arch: String,
+    images: Vec<Image>,
+    /// Artifacts in the stream which aren't included, as `platform/format`.
+    unhandled: Vec<String>,
+}
+
+fn listing(bundle: &Utf8Path, arch: Option<&str>) -> Result<Listing> {
+    let b = Bundle::open(bundle, arch)?;
+    Ok(Listing {
+        stream: b.stream().to_string(),
+        arch: b.arch().to_string(),
+        images: b.images()?,
+        unhandled: b.unhandled().map(String::from).collect(),
+    })
+}
+
+fn print_table(l: &Listing, mut w: impl Write) -> Result<()> {
+    let size = |s: Option<u64>| {
+        s.map(|s| indicatif::HumanBytes(s).to_string())
+            .unwrap_or_else(|| "-".to_string())
+    };
+    let mut rows = vec![[
+        "PLATFORM".to_string(),
+        "FORMAT".to_string(),
+        "FILENAME".to_string(),
+        "SIZE".to_string(),
+        "DELTA".to_string(),
+        "SHA-256".to_string(),
+        "VMDK".to_string(),
+    ]];
+    for i in l.images.iter() {
+        rows.push([
+            i.platform.clone(),
+            i.format.clone(),
+            i.filename.clone(),
+            size(i.size),
+            size(Some(i.delta_size)),
+            if i.validated { "yes" } else { "no" }.to_string(),
+            i.vmdk_options.join("; "),
+        ]);
+    }
+    let mut widths = [0usize; 7];
+    for row in rows.iter() {
+        for (w, col) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(col.len());
+        }
+    }
+    for row in rows.iter() {
+        let mut line = String::new();
+        for (col, w) in row.iter().zip(widths.iter()) {
+            line.push_str(&format!("{:width$}  ", col, width = w));
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    if !l.unhandled.is_empty() {
+        writeln!(w, "Unhandled: {}", l.unhandled.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Print the images in `bundle` for `arch`, as a table or JSON.
+pub(crate) fn list(bundle: &Utf8Path, arch: Option<&str>, json: bool) -> Result<()> {
+    let l = listing(bundle, arch)?;
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    if json {
+        serde_json::to_writer_pretty(&mut out, &l)?;
+        writeln!(out)?;
+    } else {
+        print_table(&l, &mut out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use coreos_diskimage_rehydrator::Strategy;
+
+    #[test]
+    fn test_print_table() -> Result<()> {
+        let l = Listing {
+            stream: "stable".to_string(),
+            arch: "x86_64".to_string(),
+            images: vec![Image {
+                platform: "vmware".to_string(),
+                format: "ova".to_string(),
+                kind: "disk".to_string(),
+                strategy: Some(Strategy::Ova),
+                filename: "fcos-vmware.x86_64.ova".to_string(),
+                size: None,
+                delta_size: 2048,
+                validated: false,
+                vmdk_options: vec![
+                    "disk.vmdk: adapter_type=lsilogic,subformat=streamOptimized,hw_version=6"
+                        .to_string(),
+                ],
+            }],
+            unhandled: vec!["nutanix/qcow2".to_string()],
+        };
+        let mut buf = Vec::new();
+        print_table(&l, &mut buf)?;
+        let expected = "\
+PLATFORM  FORMAT  FILENAME                SIZE  DELTA    SHA-256  VMDK
+vmware    ova     fcos-vmware.x86_64.ova  -     2.00KiB  no       disk.vmdk: adapter_type=lsilogic,subformat=streamOptimized,hw_version=6
+Unhandled: nutanix/qcow2
+";
+        assert_eq!(String::from_utf8(buf)?, expected);
+        Ok(())
+    }
+}
@@ -3,45 +3,36 @@
 #![deny(unused_must_use)]
 #![deny(unsafe_code)]
 
-use crate::riverdelta::{ArtifactExt, RiverDelta};
-use crate::streamid::stream_url_from_id;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use coreos_stream_metadata::Artifact;
-use coreos_stream_metadata::Stream as CoreStream;
-use fn_error_context::context;
-use rayon::prelude::*;
-use serde_derive::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::convert::TryInto;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use coreos_diskimage_rehydrator::build::{self, BuildDirs, DehydrateOptions};
+use coreos_diskimage_rehydrator::{
+    Bundle, Compress, Output, Selection, ServeOptions, StrategyOverride, StrategyTable, DIR,
+    STREAM_FILE,
+};
+use std::fs::File;
+use std::io::BufReader;
 use structopt::StructOpt;
-use tracing::{debug, info};
-
-mod download;
-mod ova;
-mod qemu_img;
-mod riverdelta;
-mod rsync;
-mod streamid;
-mod utils;
-
-/// The target directory
-const DIR: &str = "coreos-images-dehydrated";
-/// Where we put temporarily decompressed images
-const CACHEDIR: &str = "dehydrate-cache";
-/// The name of our stream file
-const STREAM_FILE: &str = "stream.json";
-/// Name of metadata file
-const METADATA_FILE: &str = "meta.json";
-/// Number of CPUs we'll use
-pub(crate) const N_WORKERS: u32 = 3;
+
+mod list;
+
+/// Default number of images to process in parallel
+const DEFAULT_JOBS: &str = "3";
 
 #[derive(Debug, StructOpt)]
 struct RehydrateOpts {
+    /// Directory containing the dehydrated images
+    #[structopt(long, default_value = DIR)]
+    bundle: Utf8PathBuf,
+
+    /// Architecture of the images to generate; defaults to that of the host
+    #[structopt(long)]
+    arch: Option<String>,
+
+    /// Extract all available images
+    #[structopt(long, conflicts_with_all = &["disk", "iso", "pxe"])]
+    all: bool,
+
     /// Extract the disk image for a specific platform
     #[structopt(long)]
     disk: Vec<String>,
@@ -58,674 +49,445 @@ struct RehydrateOpts {
     #[structopt(long)]
     skip_validate: bool,
 
+    /// Number of images to generate in parallel; xz compression also
+    /// uses this many threads per image
+    #[structopt(long, short = "j", default_value = DEFAULT_JOBS)]
+    jobs: usize,
+
+    /// Compress generated images: one of none, xz, gz, zstd, or original
+    /// to use the same compression (and filename) as the stream metadata.
+    /// The SHA-256 of the uncompressed data is still validated.
+    #[structopt(long, default_value = "none")]
+    compress: Compress,
+
+    /// Write all images for all architectures (or the one specified),
+    /// compressed as in the stream metadata, to this directory along with
+    /// a `stream.json` referring to them
+    #[structopt(long, requires = "base-url", conflicts_with_all = &["all", "disk", "iso", "pxe", "dest"])]
+    mirror: Option<Utf8PathBuf>,
+
+    /// URL at which the `--mirror` directory will be served
+    #[structopt(long, requires = "mirror")]
+    base_url: Option<String>,
+
+    /// The bundle for a previous release, needed if the qemu image was
+    /// dehydrated as a delta from it with `build dehydrate --previous`
+    #[structopt(long)]
+    base: Option<Utf8PathBuf>,
+
+    /// Directory for temporary files; defaults to the system temporary
+    /// directory
+    #[structopt(long)]
+    cachedir: Option<Utf8PathBuf>,
+
     /// Directory to use for image output.  If `-`, use stdout.
     /// If multiple images are specified with `-`, then a GNU tar
     /// stream will be used that can be uncompressed by piping
     /// to e.g. `| tar xf -`.
-    dest: String,
-}
-
-#[derive(Debug, StructOpt, Default)]
-struct DehydrateOpts {
-    /// Do not fatally error if there are unhandled artifacts.
-    #[structopt(long)]
-    allow_unhandled: bool,
+    #[structopt(required_unless = "mirror")]
+    dest: Option<String>,
+}
+
+impl RehydrateOpts {
+    /// Open the bundle for `arch`, or the host architecture.
+    fn open(&self, arch: Option<&str>) -> Result<Bundle> {
+        let mut b = Bundle::open(&self.bundle, arch)?;
+        b.set_base(self.base.clone());
+        b.set_tmpdir(self.cachedir.clone());
+        b.set_skip_validate(self.skip_validate);
+        Ok(b)
+    }
 }
 
-/// Commands used to dehydrate images
+/// Where the `build` commands keep their files.
 #[derive(Debug, StructOpt)]
-#[structopt(rename_all = "kebab-case")]
-enum Build {
-    /// Initialize from a stream
-    Init {
-        /// Stream ID (e.g. `stable` for FCOS, `rhcos-4.8` for RHCOS)
-        stream: String,
-    },
-    /// Download all supported images
-    Download {
-        /// Don't try to download signatures
-        #[structopt(long)]
-        skip_signatures: bool,
-    },
-    /// Generate "dehydration files" from already downloaded files
-    Dehydrate(DehydrateOpts),
-    /// Remove cached files
-    Clean,
-    /// Initialize, download, and dehydrate in one go
-    Run {
-        /// Stream ID (e.g. `stable` for FCOS, `rhcos-4.8` for RHCOS)
-        stream: String,
-    },
-}
+struct DirOpts {
+    /// Directory for the stream metadata and the original images
+    #[structopt(long, default_value = ".")]
+    workdir: Utf8PathBuf,
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "coreos-diskimage-rehydrator")]
-#[structopt(rename_all = "kebab-case")]
-enum Opt {
-    PrintStreamJson,
-    Build(Build),
-    /// Regenerate target file
-    Rehydrate(RehydrateOpts),
-}
+    /// Directory for temporarily decompressed images; defaults to
+    /// `dehydrate-cache` in the working directory
+    #[structopt(long)]
+    cachedir: Option<Utf8PathBuf>,
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Metadata {
-    original_artifact_size: u64,
+    /// Directory to write the dehydrated images to; defaults to
+    /// `coreos-images-dehydrated` in the working directory
+    #[structopt(long)]
+    output: Option<Utf8PathBuf>,
 }
 
-fn run() -> Result<()> {
-    match Opt::from_args() {
-        Opt::PrintStreamJson => {
-            let srcdir = camino::Utf8Path::new(DIR);
-            let mut f = BufReader::new(File::open(srcdir.join("stream.json"))?);
-            let out = std::io::stdout();
-            let mut out = out.lock();
-            std::io::copy(&mut f, &mut out)?;
-            Ok(())
+impl DirOpts {
+    fn dirs(&self) -> BuildDirs {
+        BuildDirs {
+            workdir: self.workdir.clone(),
+            cachedir: self.cachedir.clone(),
+            output: self.output.clone(),
         }
-        Opt::Build(b) => match b {
-            Build::Init { ref stream } => build_init(stream.as_str()),
-            Build::Download { skip_signatures } => download::build_download(skip_signatures),
-            Build::Dehydrate(ref opts) => build_dehydrate(opts),
-            Build::Clean => build_clean(),
-            Build::Run { ref stream } => {
-                build_init(stream.as_str())?;
-                download::build_download(false)?;
-                build_dehydrate(&Default::default())?;
-                build_clean()?;
-                Ok(())
-            }
-        },
-        Opt::Rehydrate(ref opts) => rehydrate(opts),
     }
 }
 
-/// Initialize directory with stream data
-fn build_init(stream: &str) -> Result<()> {
-    let u = stream_url_from_id(stream)?;
-    if Utf8Path::new(STREAM_FILE).exists() {
-        return Err(anyhow!("{} exists, not overwriting", STREAM_FILE));
-    }
-    info!("Downloading {}", u);
-    let mut out = std::io::BufWriter::new(
-        OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(STREAM_FILE)?,
-    );
-    let mut resp = reqwest::blocking::get(&u)?;
-    resp.error_for_status_ref()?;
-    resp.copy_to(&mut out)?;
-    out.flush()?;
-    Ok(())
+#[derive(Debug, StructOpt)]
+struct ArchOpts {
+    /// Architecture to operate on; may be specified multiple times.
+    /// Defaults to that of the host.
+    #[structopt(long)]
+    arch: Vec<String>,
 }
 
-/// Clean cached data from the current directory.
-fn build_clean() -> Result<()> {
-    let cachedir = Utf8Path::new(CACHEDIR);
-    if cachedir.exists() {
-        std::fs::remove_dir_all(cachedir)?;
-        info!("Removed: {}", CACHEDIR);
+impl ArchOpts {
+    fn arches(&self) -> Vec<String> {
+        if self.arch.is_empty() {
+            vec![coreos_diskimage_rehydrator::host_arch()]
+        } else {
+            self.arch.clone()
+        }
     }
-    Ok(())
 }
 
-enum OutputTarget<W: std::io::Write> {
-    Directory(Utf8PathBuf),
-    Stdout(W),
-    Tar(tar::Builder<W>),
-}
-
-struct RehydrateContext<'a, 'b, W: std::io::Write> {
-    opts: &'a RehydrateOpts,
+#[derive(Debug, StructOpt)]
+struct StrategyOpts {
+    /// JSON file mapping `platform` or `platform/format` to a delta
+    /// strategy, overriding the built-in defaults
+    #[structopt(long)]
+    strategy_file: Option<Utf8PathBuf>,
 
-    target: Arc<Mutex<OutputTarget<W>>>,
-    tmpdir: &'b Utf8Path,
+    /// Set the delta strategy for a platform or format, e.g.
+    /// `nutanix=rsync-from-qemu`; may be specified multiple times.
+    /// Strategies are: rsync-from-qemu, vmdk, ova, copy, iso-from-rootfs, skip
+    #[structopt(long)]
+    strategy: Vec<StrategyOverride>,
 }
 
-fn write_output<W: std::io::Write>(
-    ctx: &RehydrateContext<W>,
-    target: impl AsRef<Utf8Path>,
-) -> Result<()> {
-    let target = target.as_ref();
-    let mut outtarget = ctx.target.lock().unwrap();
-    match &mut *outtarget {
-        OutputTarget::Directory(ref d) => {
-            std::fs::rename(target, d.join(target.file_name().unwrap()))
-                .with_context(|| format!("Failed to move {} to {}", target, d))?;
-        }
-        OutputTarget::Stdout(ref mut s) => {
-            let mut src = std::io::BufReader::new(File::open(target)?);
-            std::io::copy(&mut src, s)?;
+impl StrategyOpts {
+    fn table(&self) -> Result<StrategyTable> {
+        let mut r = StrategyTable::default();
+        if let Some(p) = self.strategy_file.as_deref() {
+            r.merge(StrategyTable::load(p)?);
         }
-        OutputTarget::Tar(ref mut t) => {
-            let mut src = File::open(target)?;
-            t.append_file(target.file_name().unwrap(), &mut src)?;
+        for o in self.strategy.iter() {
+            r.set(o);
         }
+        Ok(r)
     }
-    Ok(())
 }
 
-/// Generate a new temporary hardlink.
-///
-/// We do this gyration because most of our code ends up generating
-/// new files that we want to `rename()`.
-fn temp_hardlink(src: impl AsRef<Utf8Path>, tmpdir: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
-    let src = src.as_ref();
-    let tmpdir = tmpdir.as_ref();
-    let dest = tmpdir.join(src.file_name().unwrap());
-    std::fs::hard_link(src, &dest).with_context(|| anyhow!("Failed to hardlink {}", src))?;
-    Ok(dest)
+#[derive(Debug, StructOpt)]
+struct SignatureOpts {
+    /// Don't download or verify signatures
+    #[structopt(long)]
+    skip_signatures: bool,
+
+    /// Public key file trusted to sign images; may be specified
+    /// multiple times.  Defaults to the Fedora and Red Hat release
+    /// keys in /etc/pki/rpm-gpg.
+    #[structopt(long)]
+    keyring: Vec<Utf8PathBuf>,
 }
 
-fn finish_output<W: std::io::Write>(
-    ctx: &RehydrateContext<W>,
-    a: &Artifact,
-    target: impl AsRef<Utf8Path>,
-) -> Result<()> {
-    let target = target.as_ref();
-    if ctx.opts.skip_validate {
-        info!("Generated (but skipped SHA-256 validation): {}", target);
-        return write_output(ctx, target);
-    }
-    let expected = a
-        .uncompressed_sha256
-        .as_deref()
-        .unwrap_or_else(|| a.sha256.as_str());
-    let actual = utils::sha256_file(target)?;
-    if expected != actual {
-        return Err(anyhow!(
-            "SHA-256 mismatch for {} - expected: {} actual: {}",
-            target,
-            expected,
-            actual
-        ));
+#[derive(Debug, StructOpt)]
+struct DehydrateOpts {
+    /// Do not fatally error if there are unhandled artifacts.
+    #[structopt(long)]
+    allow_unhandled: bool,
+
+    #[structopt(flatten)]
+    dirs: DirOpts,
+
+    #[structopt(flatten)]
+    arch: ArchOpts,
+
+    #[structopt(flatten)]
+    strategy: StrategyOpts,
+
+    /// The bundle for a previous release of the stream; the qemu image
+    /// is stored as a delta from the one in it, rather than in full
+    #[structopt(long)]
+    previous: Option<Utf8PathBuf>,
+
+    /// Number of images to download or dehydrate in parallel
+    #[structopt(long, short = "j", default_value = DEFAULT_JOBS)]
+    jobs: usize,
+}
+
+impl DehydrateOpts {
+    fn options(&self) -> Result<DehydrateOptions> {
+        Ok(DehydrateOptions {
+            dirs: self.dirs.dirs(),
+            arches: self.arch.arches(),
+            strategies: self.strategy.table()?,
+            allow_unhandled: self.allow_unhandled,
+            previous: self.previous.clone(),
+            jobs: self.jobs,
+        })
     }
-    debug!("Validated {}", expected);
-    info!("Generated: {}", target);
-    write_output(ctx, target)
 }
 
-fn rehydrate(opts: &RehydrateOpts) -> Result<(), anyhow::Error> {
-    let pxe_or_iso = opts.iso || opts.pxe;
-    if opts.disk.is_empty() && !pxe_or_iso {
-        return Err(anyhow!("No images specified"));
-    }
+/// Commands used to dehydrate images
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Build {
+    /// Initialize from a stream
+    Init {
+        /// Stream ID (e.g. `stable` for FCOS, `rhcos-4.8` for RHCOS)
+        stream: String,
 
-    let tmpdir = tempfile::tempdir_in(".")?;
-    let tmpdir: &Utf8Path = tmpdir.path().try_into()?;
+        #[structopt(flatten)]
+        dirs: DirOpts,
+    },
+    /// Download all supported images
+    Download {
+        #[structopt(flatten)]
+        signatures: SignatureOpts,
 
-    // PXE is multiple things.
-    let have_multiple = opts.disk.len() > 1 || opts.pxe;
-    let stdout = std::io::stdout();
-    let is_stdout = opts.dest == "-";
-    if is_stdout && nix::unistd::isatty(1)? {
-        return Err(anyhow!("Refusing to output to a tty"));
-    }
-    let target = match (is_stdout, have_multiple) {
-        (true, true) => OutputTarget::Tar(tar::Builder::new(stdout)),
-        (true, false) => OutputTarget::Stdout(stdout),
-        (_, _) => OutputTarget::Directory(opts.dest.clone().into()),
-    };
+        /// Number of images to download in parallel
+        #[structopt(long, short = "j", default_value = DEFAULT_JOBS)]
+        jobs: usize,
 
-    let ctx = &RehydrateContext {
-        opts,
-        tmpdir,
-        target: Arc::new(Mutex::new(target)),
-    };
+        #[structopt(flatten)]
+        dirs: DirOpts,
 
-    let srcdir = camino::Utf8Path::new(DIR);
-    let stream_path = &srcdir.join(STREAM_FILE);
-    let s = File::open(stream_path).context("Failed to open stream.json")?;
-    let s: CoreStream = serde_json::from_reader(std::io::BufReader::new(s))?;
-    let riverdelta: RiverDelta = s.try_into()?;
-    if opts.iso {
-        let metal = riverdelta
-            .metal
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing metal"))?;
-        let iso_fn = metal.iso.filename();
-        let patch = srcdir.join(rdelta_name_for_artifact(&metal.iso)?);
-        rsync::apply(
-            &srcdir.join(metal.pxe.rootfs.filename()),
-            iso_fn,
-            Utf8Path::new("."),
-            patch,
-        )?;
-        finish_output(ctx, &metal.iso, iso_fn)?;
-    }
-    if opts.pxe {
-        let metal = riverdelta
-            .metal
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing metal"))?;
-        for a in [&metal.pxe.kernel, &metal.pxe.initramfs, &metal.pxe.rootfs].iter() {
-            let src = srcdir.join(a.filename());
-            let tmp = temp_hardlink(src, tmpdir)?;
-            finish_output(ctx, a, &tmp)?;
-        }
-    }
+        #[structopt(flatten)]
+        arch: ArchOpts,
 
-    let qemu = &riverdelta.qemu;
-    let qemu_fn = Utf8Path::new(uncompressed_name(qemu.filename()));
-    if !opts.disk.is_empty() {
-        // Need to decompress the qemu image
-        if !qemu_fn.exists() {
-            {
-                let qemu_zstd_path =
-                    srcdir.join(format!("{}.zst", uncompressed_name(qemu_fn.as_str())));
-                info!("Decompressing: {}", qemu_zstd_path);
-                let f = File::open(&qemu_zstd_path)
-                    .with_context(|| anyhow!("Opening {}", qemu_zstd_path))?;
-                let mut f = zstd::Decoder::new(f)?;
-                let mut o = std::io::BufWriter::new(
-                    File::create(qemu_fn).context("Opening qemu destination")?,
-                );
-                std::io::copy(&mut f, &mut o).context("Failed to decompress qemu")?;
-                o.flush()?;
-            }
-            info!("Unpacked source image: {}", qemu_fn);
-        }
-    }
-    // Now build a hash set so we can conveniently look up bits, filter out qemu
-    // since we're done with that.
-    let mut disks: HashSet<_> = opts
-        .disk
-        .iter()
-        .map(|s| s.as_str())
-        .filter(|&s| s != riverdelta::QEMU)
-        .collect();
-
-    // Handle non-rsyncable targets.
-    if disks.take("vmware").is_some() {
-        let vmware = riverdelta
-            .vmware
-            .as_ref()
-            .ok_or_else(|| anyhow!("No vmware artifact available"))?;
-        rehydrate_ova(ctx, qemu_fn, vmware)?;
-    }
+        #[structopt(flatten)]
+        strategy: StrategyOpts,
+    },
+    /// Verify downloaded images against the stream metadata
+    Verify {
+        #[structopt(flatten)]
+        signatures: SignatureOpts,
 
-    // And the remainder must be rsyncable, or we fail.
-    disks.par_iter().try_for_each(|&disk| {
-        if riverdelta.unhandled.contains_key(disk) {
-            return Err(anyhow!("Unhandled artifact: {}", disk));
-        }
-        let a = riverdelta
-            .get_rsyncable(disk)
-            .ok_or_else(|| anyhow!("Unknown artifact: {}", disk))?;
-        let artifact_filename = Utf8Path::new(a.filename());
-        let uncompressed_name = Utf8Path::new(uncompressed_name(artifact_filename.as_str()));
-        let patch = srcdir.join(rdelta_name_for_artifact(a)?);
-        let tmpname = &Utf8PathBuf::from(format!("{}.tmp", uncompressed_name));
-        rsync::apply(qemu_fn, tmpname.as_str(), Utf8Path::new("."), patch)?;
-        if uncompressed_name.extension() == Some(qemu_img::VMDK) {
-            info!("Regenerating VMDK for: {}", disk); // 😢
-            qemu_img::copy_to_vmdk(tmpname, uncompressed_name)?;
-            std::fs::remove_file(tmpname)?;
-            info!(
-                "Generated (but skipped SHA-256 validation due to vmdk compression): {}",
-                uncompressed_name
-            );
-        } else {
-            std::fs::rename(tmpname, uncompressed_name)?;
-            finish_output(ctx, a, uncompressed_name)?;
-        }
-        Ok::<_, anyhow::Error>(())
-    })?;
-    if opts.disk.iter().any(|s| s.as_str() == riverdelta::QEMU) {
-        print!("Generated: {}", qemu_fn);
-    }
+        #[structopt(flatten)]
+        dirs: DirOpts,
 
-    let mut target = ctx.target.lock().unwrap();
-    match &mut *target {
-        OutputTarget::Directory(_) => {}
-        OutputTarget::Stdout(s) => {
-            s.flush()?;
-        }
-        OutputTarget::Tar(t) => {
-            t.finish()?;
-        }
-    }
+        #[structopt(flatten)]
+        arch: ArchOpts,
 
-    Ok(())
-}
+        #[structopt(flatten)]
+        strategy: StrategyOpts,
+    },
+    /// Generate "dehydration files" from already downloaded files
+    Dehydrate(DehydrateOpts),
+    /// Remove cached files
+    Clean(DirOpts),
+    /// Initialize, download, and dehydrate in one go
+    Run {
+        /// Stream ID (e.g. `stable` for FCOS, `rhcos-4.8` for RHCOS)
+        stream: String,
 
-fn temppath_name(t: &tempfile::TempPath) -> Result<&Utf8Path> {
-    let p: &Path = t.as_ref();
-    let r = p.try_into()?;
-    Ok(r)
-}
+        #[structopt(flatten)]
+        signatures: SignatureOpts,
 
-fn tempfile_name(t: &tempfile::NamedTempFile) -> Result<&Utf8Path> {
-    let p = t.path();
-    let r = p.try_into()?;
-    Ok(r)
+        #[structopt(flatten)]
+        opts: DehydrateOpts,
+    },
 }
 
-fn rehydrate_ova<W: std::io::Write>(
-    ctx: &RehydrateContext<W>,
-    qemu_path: impl AsRef<Utf8Path>,
-    vmware: &Artifact,
-) -> Result<()> {
-    let srcdir = camino::Utf8Path::new(DIR);
-    let qemu_path = qemu_path.as_ref();
-    let delta_ova_name = &srcdir.join(ova_rdelta_name_for_artifact(vmware));
-    let target_ova_name = vmware.filename();
-    let mut temp_delta = tempfile::NamedTempFile::new_in(ctx.tmpdir)?;
-    let ova_meta = ova::ova_extract(delta_ova_name, &mut temp_delta)?;
-    temp_delta.flush()?;
-    let temp_delta = temp_delta.into_temp_path();
-    let temp_delta: &Path = temp_delta.as_ref();
-    let temp_delta: &Utf8Path = temp_delta.try_into()?;
-    let temp_qcow2 = tempfile::NamedTempFile::new_in(ctx.tmpdir)?;
-    rsync::apply(
-        qemu_path,
-        tempfile_name(&temp_qcow2)?.as_str(),
-        ctx.tmpdir,
-        temp_delta,
-    )?;
-    drop(temp_delta);
-    let temp_vmdk = tempfile::NamedTempFile::new_in(ctx.tmpdir)?.into_temp_path();
-    info!("Regenerating VMDK for: {}", target_ova_name); // 😢
-    qemu_img::copy_to_vmdk(tempfile_name(&temp_qcow2)?, temppath_name(&temp_vmdk)?)?;
-    drop(temp_qcow2);
-    let temp_ova = &ctx.tmpdir.join(target_ova_name);
-    let mut temp_ova_f = BufWriter::new(File::create(temp_ova)?);
-    ova::ova_rebuild(&ova_meta, temppath_name(&temp_vmdk)?, &mut temp_ova_f)?;
-    temp_ova_f.flush()?;
-    info!(
-        "Generated (but skipped SHA-256 validation due to vmdk compression): {}",
-        target_ova_name
-    );
-    write_output(ctx, temp_ova)?;
-    Ok(())
-}
+#[derive(Debug, StructOpt)]
+struct ListOpts {
+    /// Directory containing the dehydrated images
+    #[structopt(long, default_value = DIR)]
+    bundle: Utf8PathBuf,
 
-fn maybe_uncompressed_name(s: &str) -> Option<&str> {
-    s.strip_suffix(".xz").or_else(|| s.strip_suffix(".gz"))
-}
+    /// Architecture of the images to list; defaults to that of the host
+    #[structopt(long)]
+    arch: Option<String>,
 
-fn uncompressed_name(s: &str) -> &str {
-    maybe_uncompressed_name(s).unwrap_or(s)
+    /// Output JSON
+    #[structopt(long)]
+    json: bool,
 }
 
-fn hardlink(src: impl AsRef<Path>, dest: impl AsRef<Path>) -> Result<()> {
-    let src = src.as_ref();
-    let dest = dest.as_ref();
-    info!("Including: {:?}", src);
-    std::fs::hard_link(src, dest).with_context(|| anyhow!("Hardlinking {:?}", src))?;
-    Ok(())
-}
+#[derive(Debug, StructOpt)]
+struct ServeOpts {
+    /// Directory containing the dehydrated images
+    #[structopt(long, default_value = DIR)]
+    bundle: Utf8PathBuf,
 
-/// Replace the input source file with a new zstd-compressed file ending in `.zst`.
-fn zstd_compress(src: impl AsRef<Utf8Path>) -> Result<Utf8PathBuf> {
-    let src = src.as_ref();
-    let mut srcin = File::open(src)?;
-    let dest = Utf8PathBuf::from(format!("{}.zst", src));
-    let out = File::create(&dest)?;
-    let mut out = zstd::Encoder::new(out, 10)?;
-    std::io::copy(&mut srcin, &mut out)?;
-    out.finish()?;
-    std::fs::remove_file(src)?;
-    Ok(dest)
-}
+    /// Address to listen on
+    #[structopt(long, default_value = "127.0.0.1:8080")]
+    listen: String,
 
-fn rdelta_name_for_artifact(a: &Artifact) -> Result<String> {
-    Ok(format!("{}.rdelta", uncompressed_name(a.filename())))
-}
+    /// Architecture to serve; may be specified multiple times.
+    /// Defaults to all architectures in the bundle.
+    #[structopt(long)]
+    arch: Vec<String>,
 
-fn ova_rdelta_name_for_artifact(a: &Artifact) -> String {
-    format!("{}.ova-rdelta", uncompressed_name(a.filename()))
-}
+    /// Maximum total size of cached images, in MiB.  The most recently
+    /// generated image is always kept, even if it's larger.
+    #[structopt(long, default_value = "20480")]
+    cache_size: u64,
 
-fn rsync_delta_impl(
-    src_fn: impl AsRef<Utf8Path>,
-    target: impl AsRef<Utf8Path>,
-    delta_path: impl AsRef<Utf8Path>,
-) -> Result<()> {
-    let src_fn = src_fn.as_ref();
-    let target_fn = target.as_ref();
-    let delta_path = delta_path.as_ref();
-    let mut output = std::io::BufWriter::new(File::create(delta_path)?);
-    rsync::prepare(src_fn, target_fn, delta_path.parent().unwrap(), &mut output)?;
-    output.flush()?;
-    let orig_size = target_fn.metadata()?.len();
-    let delta_size = delta_path.metadata()?.len();
-    info!(
-        "Dehydrated: {} ({:.5}%, {})",
-        target_fn,
-        ((delta_size as f64 / orig_size as f64) * 100f64),
-        indicatif::HumanBytes(delta_size)
-    );
-    Ok(())
-}
+    /// Directory for the cache and temporary files; defaults to the
+    /// system temporary directory
+    #[structopt(long)]
+    cachedir: Option<Utf8PathBuf>,
 
-#[context("Creating rsync delta")]
-fn rsync_delta(src: &Artifact, target: &Artifact, destdir: impl AsRef<Utf8Path>) -> Result<bool> {
-    let destdir = destdir.as_ref();
-    let src_fn = &get_maybe_uncompressed(src)?;
-    let target_fn = &get_maybe_uncompressed(target)?;
-    let delta_path = &destdir.join(rdelta_name_for_artifact(target)?);
-    rsync_delta_impl(src_fn, target_fn, delta_path)?;
-    Ok(true)
-}
+    /// Number of requests to handle at once; each download takes one
+    /// until it's finished
+    #[structopt(long, default_value = "8")]
+    workers: usize,
 
-pub(crate) fn read_stream() -> Result<CoreStream> {
-    let stream_path = Utf8Path::new(STREAM_FILE);
-    let s = File::open(stream_path).context("Failed to open stream.json")?;
-    let s: CoreStream = serde_json::from_reader(std::io::BufReader::new(s))?;
-    Ok(s)
-}
+    /// Don't verify SHA-256 of generated images
+    #[structopt(long)]
+    skip_validate: bool,
 
-fn cached_uncompressed_name(a: &Artifact) -> Result<Option<(Utf8PathBuf, bool)>> {
-    let name = a.filename();
-    let r = maybe_uncompressed_name(name)
-        .map(|uncomp_name| Utf8Path::new(CACHEDIR).join(uncomp_name))
-        .map(|p| {
-            if p.extension() == Some(qemu_img::VMDK) {
-                (p.with_extension(qemu_img::QCOW2), true)
-            } else {
-                (p, false)
-            }
-        });
-    Ok(r)
-}
+    /// URL at which clients reach the server, used for the artifact
+    /// locations in `stream.json`.  Defaults to `http://` and the
+    /// `Host` of the request.
+    #[structopt(long)]
+    base_url: Option<String>,
 
-fn uncompressor_for(name: &Utf8Path, src: impl Read) -> Result<impl Read> {
-    let r = match name.extension() {
-        Some("xz") => either::Left(xz2::read::XzDecoder::new(src)),
-        Some("gz") => either::Right(flate2::read::GzDecoder::new(src)),
-        Some(other) => return Err(anyhow!("Unknown extension {}", other)),
-        None => return Err(anyhow!("No extension found for {}", name)),
-    };
-    Ok(r)
+    /// The bundle for a previous release, needed if the qemu image was
+    /// dehydrated as a delta from it with `build dehydrate --previous`
+    #[structopt(long)]
+    base: Option<Utf8PathBuf>,
 }
 
-fn get_maybe_uncompressed(a: &Artifact) -> Result<Utf8PathBuf> {
-    let name = Utf8Path::new(a.filename());
-    let r = cached_uncompressed_name(a)?
-        .map(|(uncomp_name, is_vmdk)| {
-            if !uncomp_name.exists() {
-                let src = File::open(name).with_context(|| anyhow!("Failed to open {}", name))?;
-                let tmpname = format!("{}.tmp", uncomp_name);
-                let mut src = uncompressor_for(name, src)?;
-                let mut dest = std::io::BufWriter::new(File::create(&tmpname)?);
-                std::io::copy(&mut src, &mut dest)?;
-                dest.flush()?;
-                if is_vmdk {
-                    qemu_img::copy_to_qcow2(&tmpname, &uncomp_name)?;
-                    std::fs::remove_file(tmpname)?;
-                    info!("Converted to uncompressed qcow2: {}", name);
-                } else {
-                    std::fs::rename(&tmpname, &uncomp_name)?;
-                }
-                info!("Uncompressed: {}", uncomp_name);
-            }
-            Ok::<_, anyhow::Error>(uncomp_name)
-        })
-        .transpose()?
-        .unwrap_or_else(|| name.into());
-    Ok(r)
+#[derive(Debug, StructOpt)]
+#[structopt(name = "coreos-diskimage-rehydrator")]
+#[structopt(rename_all = "kebab-case")]
+enum Opt {
+    PrintStreamJson {
+        /// Directory containing the dehydrated images
+        #[structopt(long, default_value = DIR)]
+        bundle: Utf8PathBuf,
+    },
+    /// List the images which can be generated
+    List(ListOpts),
+    Build(Build),
+    /// Regenerate target file
+    Rehydrate(RehydrateOpts),
+    /// Serve images over HTTP, regenerating them on demand
+    Serve(ServeOpts),
 }
 
-// Generate an image from its rsync delta.
-fn dehydrate_rsyncable(qemu: &Artifact, target: &Artifact, destdir: &Utf8Path) -> Result<()> {
-    let _found: bool = rsync_delta(qemu, target, destdir)?;
+fn rehydrate(opts: &RehydrateOpts) -> Result<()> {
+    let dest = opts
+        .dest
+        .as_deref()
+        .ok_or_else(|| anyhow!("No destination specified"))?;
+    let output = if dest == "-" {
+        if nix::unistd::isatty(1)? {
+            return Err(anyhow!("Refusing to output to a tty"));
+        }
+        Output::Stream(std::io::stdout())
+    } else {
+        Output::Directory(dest.into())
+    };
+    let sel = &Selection {
+        all: opts.all,
+        disks: opts.disk.clone(),
+        iso: opts.iso,
+        pxe: opts.pxe,
+    };
+    let bundle = opts.open(opts.arch.as_deref())?;
+    bundle.rehydrate_selection(sel, opts.compress, opts.jobs, output)?;
     Ok(())
 }
 
-// Special dehydration for OVAs.
-fn dehydrate_ova(qemu: &Artifact, target: &Artifact, destdir: &Utf8Path) -> Result<()> {
-    let ova_name = target.filename();
-    let (ova_meta, tmp_delta) = {
-        let mut temp_vmdk = tempfile::NamedTempFile::new_in(destdir)?;
-        let ova_meta = ova::ova_extract(ova_name, &mut temp_vmdk)?;
-        temp_vmdk.flush()?;
-        let temp_vmdk = temp_vmdk.into_temp_path();
-        let temp_vmdk: &Path = temp_vmdk.as_ref();
-        let temp_vmdk_path: &Utf8Path = temp_vmdk.try_into()?;
-        // Now decompress the VMDK
-        let temp_qcow2 = tempfile::Builder::new()
-            .prefix(ova_name)
-            .tempfile_in(destdir)?
-            .into_temp_path();
-        let temp_qcow2: &Path = temp_qcow2.as_ref();
-        let temp_qcow2: &Utf8Path = temp_qcow2.try_into()?;
-        qemu_img::copy_to_qcow2(temp_vmdk_path, temp_qcow2)?;
-        // Done with the vmdk
-        drop(temp_vmdk);
-        // And close the qcow2 fd
-        let src_fn = &get_maybe_uncompressed(qemu)?;
-        let tmp_delta = tempfile::NamedTempFile::new_in(destdir)?;
-        let tmp_delta_path: &Utf8Path = tmp_delta.path().try_into()?;
-        rsync_delta_impl(src_fn, temp_qcow2, tmp_delta_path)?;
-        (ova_meta, tmp_delta)
+fn mirror(opts: &RehydrateOpts, dir: &Utf8Path, base_url: &str) -> Result<()> {
+    let arches = match opts.arch.as_deref() {
+        Some(arch) => vec![arch.to_string()],
+        None => Bundle::arches(&opts.bundle)?,
     };
-    let tmp_delta_path: &Utf8Path = tmp_delta.path().try_into()?;
-
-    let ova_delta = ova_rdelta_name_for_artifact(target);
-    let mut destf = BufWriter::new(File::create(destdir.join(&ova_delta))?);
-    ova::ova_rebuild(&ova_meta, tmp_delta_path, &mut destf)?;
-    destf.flush()?;
-    info!("Generated delta OVA: {}", ova_delta);
+    let bundles = arches
+        .iter()
+        .map(|arch| opts.open(Some(arch)))
+        .collect::<Result<Vec<_>>>()?;
+    coreos_diskimage_rehydrator::mirror(&bundles, opts.jobs, dir, base_url)?;
     Ok(())
 }
 
-/// Loop over stream metadata and generate dehydrated (~deduplicated) content.
-fn build_dehydrate(opts: &DehydrateOpts) -> Result<()> {
-    let stream_path = Utf8Path::new(STREAM_FILE);
-    let s = read_stream()?;
-    let riverdelta: RiverDelta = s.try_into()?;
-
-    if !opts.allow_unhandled && !riverdelta.unhandled.is_empty() {
-        return Err(anyhow!(
-            "Unhandled artifacts: {:?}",
-            riverdelta.unhandled.keys()
-        ));
+fn serve(opts: &ServeOpts) -> Result<()> {
+    if opts.workers == 0 {
+        return Err(anyhow!("--workers must be at least 1"));
     }
-
-    std::fs::create_dir_all(CACHEDIR).context("Creating cachedir")?;
-
-    let qemu = &riverdelta.qemu;
-    let uncomp_qemu = &get_maybe_uncompressed(qemu)?;
-    let destdir = camino::Utf8Path::new(DIR);
-    std::fs::create_dir(destdir)
-        .with_context(|| anyhow!("Failed to create destination directory: {}", destdir))?;
-
-    hardlink(stream_path, destdir.join(stream_path.file_name().unwrap()))?;
-
-    if let Some(metal) = riverdelta.metal.as_ref() {
-        // The rootfs (squashfs-in-cpio) is a source artifact for the ISO
-        let rootfs_name = metal.pxe.rootfs.filename();
-        hardlink(rootfs_name, destdir.join(rootfs_name))?;
-        let _found: bool = rsync_delta(&metal.pxe.rootfs, &metal.iso, destdir)?;
-
-        // And handle the kernel/initramfs
-        for a in [&metal.pxe.kernel, &metal.pxe.initramfs].iter() {
-            let name = a.filename();
-            hardlink(name, destdir.join(name))?;
-        }
-    }
-
-    // Link in the qemu image now, we'll compress it at the end
-    let qemu_dest = &destdir.join(uncomp_qemu.file_name().unwrap());
-    hardlink(uncomp_qemu, qemu_dest)?;
-
-    // Add some parallelism
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(N_WORKERS as usize)
-        .build()
-        .unwrap();
-    pool.install(|| {
-        // The rsyncable artifacts are easy.
-        riverdelta
-            .qemu_rsyncable_artifacts
-            .par_iter()
-            .map(|(_name, target)| dehydrate_rsyncable(qemu, target, destdir))
-            .chain(
-                riverdelta
-                    .aws
-                    .par_iter()
-                    .map(|aws| dehydrate_rsyncable(qemu, aws, destdir)),
-            )
-            .chain(
-                riverdelta
-                    .vmware
-                    .par_iter()
-                    .map(|vmware| dehydrate_ova(qemu, vmware, destdir)),
-            )
-            .try_reduce(|| (), |_, _| Ok(()))?;
-        Ok::<_, anyhow::Error>(())
+    coreos_diskimage_rehydrator::serve(&ServeOptions {
+        bundle: opts.bundle.clone(),
+        listen: opts.listen.clone(),
+        arches: opts.arch.clone(),
+        cache_size: opts.cache_size,
+        cachedir: opts.cachedir.clone(),
+        workers: opts.workers,
+        skip_validate: opts.skip_validate,
+        base_url: opts.base_url.clone(),
+        base: opts.base.clone(),
     })?;
+    Ok(())
+}
 
-    info!("Including (zstd compressed): {}", qemu_dest);
-    zstd_compress(qemu_dest)?;
-
-    let original_artifact_size = riverdelta.original_compressed_size()?;
-    // TODO record exact filenames we expect
-    let new_size = std::fs::read_dir(destdir)?
-        .into_iter()
-        .try_fold(0u64, |acc, f| {
-            let f = f?;
-            let l = if f.file_type()?.is_file() {
-                f.metadata()?.len()
-            } else {
-                0
-            };
-            Ok::<_, anyhow::Error>(acc + l)
-        })
-        .context("Computing new size")?;
-
-    // Write metadata JSON
-    {
-        let metadata = Metadata {
-            original_artifact_size,
-        };
-        let w = std::io::BufWriter::new(File::create(destdir.join(METADATA_FILE))?);
-        serde_json::to_writer_pretty(w, &metadata)?;
-    }
-
-    info!(
-        "Original artifact total size: {}",
-        indicatif::HumanBytes(original_artifact_size)
-    );
-    info!(
-        "Dehydrated artifact total size: {}",
-        indicatif::HumanBytes(new_size)
-    );
-
-    if !riverdelta.unhandled.is_empty() {
-        assert!(!opts.allow_unhandled);
-        let s = std::io::stdout();
-        let mut s = s.lock();
-        write!(s, "Unhandled:")?;
-        for k in riverdelta.unhandled.keys() {
-            write!(s, " {}", k)?;
+fn run() -> Result<()> {
+    match Opt::from_args() {
+        Opt::PrintStreamJson { ref bundle } => {
+            let mut f = BufReader::new(File::open(bundle.join(STREAM_FILE))?);
+            let out = std::io::stdout();
+            let mut out = out.lock();
+            std::io::copy(&mut f, &mut out)?;
+            Ok(())
         }
-        writeln!(s, "")?;
+        Opt::Build(b) => match b {
+            Build::Init {
+                ref stream,
+                ref dirs,
+            } => Ok(build::init(&dirs.dirs(), stream.as_str())?),
+            Build::Download {
+                ref signatures,
+                jobs,
+                ref dirs,
+                ref arch,
+                ref strategy,
+            } => Ok(build::download(
+                &dirs.dirs(),
+                &arch.arches(),
+                &strategy.table()?,
+                signatures.skip_signatures,
+                &signatures.keyring,
+                jobs,
+            )?),
+            Build::Verify {
+                ref signatures,
+                ref dirs,
+                ref arch,
+                ref strategy,
+            } => Ok(build::verify(
+                &dirs.dirs(),
+                &arch.arches(),
+                &strategy.table()?,
+                signatures.skip_signatures,
+                &signatures.keyring,
+            )?),
+            Build::Dehydrate(ref opts) => Ok(build::dehydrate(&opts.options()?)?),
+            Build::Clean(ref dirs) => Ok(build::clean(&dirs.dirs())?),
+            Build::Run {
+                ref stream,
+                ref signatures,
+                ref opts,
+            } => {
+                let opts = &opts.options()?;
+                let dirs = &opts.dirs;
+                build::init(dirs, stream.as_str())?;
+                build::download(
+                    dirs,
+                    &opts.arches,
+                    &opts.strategies,
+                    signatures.skip_signatures,
+                    &signatures.keyring,
+                    opts.jobs,
+                )?;
+                build::dehydrate(opts)?;
+                build::clean(dirs)?;
+                Ok(())
+            }
+        },
+        Opt::List(ref opts) => list::list(&opts.bundle, opts.arch.as_deref(), opts.json),
+        Opt::Rehydrate(ref opts) => match (opts.mirror.as_deref(), opts.base_url.as_deref()) {
+            (Some(dir), Some(base_url)) => mirror(opts, dir, base_url),
+            _ => rehydrate(opts),
+        },
+        Opt::Serve(ref opts) => serve(opts),
     }
-
-    Ok(())
 }
 
 fn main() {
@@ -749,13 +511,3 @@ fn main() {
         std::process::exit(1)
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_strip_suffix() {
-        assert_eq!(uncompressed_name("foo.xz"), "foo");
-    }
-}
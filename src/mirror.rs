@@ -0,0 +1,154 @@
+//! Regenerate all images into a directory which can be served as a mirror,
+//! along with stream metadata referring to it.
+
+use crate::bundle::{Bundle, Error, Output, Selection};
+use crate::compress::Compress;
+use crate::riverdelta::ArtifactExt;
+use crate::utils;
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use coreos_stream_metadata::{Artifact, Stream};
+use fn_error_context::context;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use tracing::info;
+
+/// The architectures in the bundle in `dir`.
+pub(crate) fn bundle_arches(dir: &Utf8Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for e in std::fs::read_dir(dir)? {
+        let e = e?;
+        let p: Utf8PathBuf = e.path().try_into()?;
+        let name = p.file_name().unwrap();
+        // Skip incomplete output from `build dehydrate`
+        if e.file_type()?.is_dir() && !name.starts_with('.') {
+            r.push(name.to_string());
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+/// Point an artifact at its regenerated copy in `dir`, served at `base_url`.
+/// `validated` is whether the uncompressed data was checked to match the original.
+fn rewrite_artifact(
+    a: &mut Artifact,
+    srcdir: &Utf8Path,
+    dir: &Utf8Path,
+    base_url: &str,
+    validated: bool,
+) -> Result<()> {
+    let name = a.filename().to_string();
+    let path = &dir.join(&name);
+    let sha256 = utils::sha256_file(path)?;
+    let identical = sha256 == a.sha256;
+    if !identical && !validated && a.uncompressed_sha256.is_some() {
+        let src = uncompressed_reader(path)?;
+        let mut w = utils::Sha256Writer::new(std::io::sink());
+        std::io::copy(&mut BufReader::new(src), &mut w)?;
+        a.uncompressed_sha256 = Some(w.finish().1);
+    }
+    // The original signature is only valid if we regenerated the same bytes.
+    a.signature = match a.signature_filename() {
+        Some(sig) if identical && srcdir.join(sig).exists() => {
+            std::fs::copy(srcdir.join(sig), dir.join(sig))?;
+            Some(format!("{}/{}", base_url, sig))
+        }
+        _ => None,
+    };
+    a.location = format!("{}/{}", base_url, name);
+    a.sha256 = sha256;
+    Ok(())
+}
+
+fn uncompressed_reader(path: &Utf8Path) -> Result<Box<dyn std::io::Read>> {
+    let f = File::open(path)?;
+    Ok(if crate::maybe_uncompressed_name(path.as_str()).is_some() {
+        Box::new(crate::uncompressor_for(path, f)?)
+    } else {
+        Box::new(f)
+    })
+}
+
+/// Regenerate the images in `bundle` into `dir/arch`, and rewrite their
+/// entries in the stream; artifacts which aren't included are removed.
+fn mirror_arch(
+    bundle: &Bundle,
+    jobs: usize,
+    s: &mut Stream,
+    dir: &Utf8Path,
+    base_url: &str,
+) -> Result<()> {
+    let arch = bundle.arch();
+    let archdir = &dir.join(arch);
+    std::fs::create_dir_all(archdir)?;
+    let sel = &Selection {
+        all: true,
+        ..Default::default()
+    };
+    let output = Output::<std::io::Sink>::Directory(archdir.to_owned());
+    crate::rehydrate_selection(bundle, sel, Compress::Original, jobs, output)?;
+
+    let (srcdir, rd) = (&bundle.srcdir, &bundle.rd);
+    let validated: HashMap<&str, bool> = rd
+        .all_artifacts()
+        .into_iter()
+        .map(|a| {
+            let validated = !bundle.skip_validate && bundle.reproduces(a);
+            (a.location.as_str(), validated)
+        })
+        .collect();
+    let base_url = &format!("{}/{}", base_url, arch);
+    let thisarch = s
+        .architectures
+        .get_mut(arch)
+        .ok_or_else(|| anyhow!("Missing architecture {} in stream metadata", arch))?;
+    for p in thisarch.artifacts.values_mut() {
+        for format in p.formats.values_mut() {
+            format.retain(|_, a| validated.contains_key(a.location.as_str()));
+            for a in format.values_mut() {
+                let v = validated[a.location.as_str()];
+                rewrite_artifact(a, srcdir, archdir, base_url, v)
+                    .with_context(|| anyhow!("Rewriting {}", a.location))?;
+            }
+        }
+        p.formats.retain(|_, f| !f.is_empty());
+    }
+    thisarch.artifacts.retain(|_, p| !p.formats.is_empty());
+    Ok(())
+}
+
+/// Regenerate all images in `bundles`, one for each architecture to
+/// include, into `dir`, up to `jobs` at a time.  A `stream.json` is written
+/// there too, with artifact locations under `base_url`.
+pub fn mirror(
+    bundles: &[Bundle],
+    jobs: usize,
+    dir: &Utf8Path,
+    base_url: &str,
+) -> Result<(), Error> {
+    mirror_impl(bundles, jobs, dir, base_url).map_err(Error::from_anyhow)
+}
+
+#[context("Mirroring to {}", dir)]
+fn mirror_impl(bundles: &[Bundle], jobs: usize, dir: &Utf8Path, base_url: &str) -> Result<()> {
+    let base_url = base_url.trim_end_matches('/');
+    let first = bundles
+        .first()
+        .ok_or_else(|| anyhow!("No architectures to mirror"))?;
+    let stream_path = first.dir().join(crate::STREAM_FILE);
+    let s = File::open(stream_path).context("Failed to open stream.json")?;
+    let mut s: Stream = serde_json::from_reader(BufReader::new(s))?;
+    let arches: Vec<&str> = bundles.iter().map(|b| b.arch()).collect();
+    s.architectures.retain(|k, _| arches.contains(&k.as_str()));
+    for bundle in bundles {
+        mirror_arch(bundle, jobs, &mut s, dir, base_url)?;
+    }
+    let mut w = BufWriter::new(File::create(dir.join(crate::STREAM_FILE))?);
+    serde_json::to_writer_pretty(&mut w, &s)?;
+    w.flush()?;
+    info!("Wrote mirror of {} to {}", arches.join(" "), dir);
+    Ok(())
+}
@@ -1,94 +1,478 @@
-//! A river delta is a fork of a stream.  Yes, I am clever at naming.
-//! This module manages a "parsed" version of a stream that is
-//! organized around how we manage deltas.
+//! An OVA is a tar archive of an OVF descriptor, the VMDK disks it
+//! references, and optionally a manifest of their digests (`.mf`), a
+//! certificate signing the manifest (`.cert`) and other files like NVRAM.
+//!
+//! We only need to find the disks; everything else in the OVA is kept
+//! as it is in the VMDK layout, unless the disks have to be written anew.
+//! Either way, the file and disk sizes in the OVF descriptor are checked
+//! against the regenerated members.
 
-use anyhow::{anyhow, Context, Result};
-use camino::Utf8Path;
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs::File;
-use std::io::{BufReader, Write};
+use std::io::{BufReader, Read};
+use std::ops::Range;
 
-pub(crate) struct OVA {
-    pub(crate) config: Vec<u8>,
-    pub(crate) config_header: tar::Header,
-    pub(crate) disk_header: tar::Header,
+/// Tar headers and data are in blocks of this size.
+pub(crate) const BLOCK: u64 = 512;
+
+/// A member of an OVA.
+#[derive(Debug)]
+pub(crate) struct Entry {
+    pub(crate) name: Utf8PathBuf,
+    /// Offset of the data in the OVA.
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
 }
 
-#[context("Extracting ova")]
-pub(crate) fn ova_extract(src: impl AsRef<Utf8Path>, mut disk_dest: impl Write) -> Result<OVA> {
-    let src = src.as_ref();
-    let r = BufReader::new(File::open(src).with_context(|| anyhow!("Opening {}", src))?);
-    let mut r = tar::Archive::new(r);
-    let mut config = Vec::new();
-    let mut config_header: Option<tar::Header> = None;
-    let mut disk_header: Option<tar::Header> = None;
-    for ent in r.entries()? {
-        let mut ent = ent?;
-        let name = ent.path()?;
-        let name: &Utf8Path = (*name).try_into()?;
-        match name.extension() {
-            Some("ovf") => {
-                if config_header.is_some() {
-                    return Err(anyhow!("Found multiple ovf entries, second={}", name));
+impl Entry {
+    pub(crate) fn is_disk(&self) -> bool {
+        self.name.extension() == Some("vmdk")
+    }
+
+    pub(crate) fn is_manifest(&self) -> bool {
+        self.name.extension() == Some("mf")
+    }
+}
+
+/// The members of an OVA, in order.
+#[derive(Debug)]
+pub(crate) struct Ova {
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Ova {
+    /// Read the members of the OVA at `path`.
+    #[context("Reading ova {}", path)]
+    pub(crate) fn open(path: &Utf8Path) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read the members of an OVA from `r`.
+    pub(crate) fn read(r: impl Read) -> Result<Self> {
+        let mut r = tar::Archive::new(r);
+        let mut entries = Vec::new();
+        for ent in r.entries()? {
+            let ent = ent?;
+            let name = ent.path()?;
+            let name: &Utf8Path = (*name).try_into()?;
+            entries.push(Entry {
+                name: name.to_owned(),
+                offset: ent.raw_file_position(),
+                size: ent.header().size()?,
+            });
+        }
+        if !entries.iter().any(|e| e.name.extension() == Some("ovf")) {
+            return Err(anyhow!("failed to find ovf entry"));
+        }
+        Ok(Self { entries })
+    }
+
+    /// The disks, in order.
+    pub(crate) fn disks(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|e| e.is_disk())
+    }
+
+    /// The OVF descriptors; there's usually one.
+    pub(crate) fn descriptors(&self) -> impl Iterator<Item = &Entry> {
+        self.entries
+            .iter()
+            .filter(|e| e.name.extension() == Some("ovf"))
+    }
+}
+
+/// A file referenced by an OVF descriptor.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct OvfFile {
+    pub(crate) id: String,
+    /// The name of the member of the OVA.
+    pub(crate) href: String,
+    pub(crate) size: Option<u64>,
+    /// Where the size is in the descriptor.
+    size_range: Option<Range<usize>>,
+}
+
+/// A virtual disk in an OVF descriptor.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct OvfDisk {
+    /// The `id` of its file, or `None` for a blank disk.
+    pub(crate) file_ref: Option<String>,
+    /// Its capacity, in bytes.
+    pub(crate) capacity: u64,
+}
+
+/// The files and disks of an OVF descriptor, which is all we need of it.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Ovf {
+    pub(crate) files: Vec<OvfFile>,
+    pub(crate) disks: Vec<OvfDisk>,
+}
+
+impl Ovf {
+    /// Parse the `File` and `Disk` elements of the OVF descriptor `xml`.
+    pub(crate) fn parse(xml: &str) -> Result<Self> {
+        let mut files = Vec::new();
+        let mut disks = Vec::new();
+        for (name, attrs) in elements(xml)? {
+            let get = |k| attrs.get(k).map(|(v, _): &(&str, _)| *v);
+            let require = |k| get(k).ok_or_else(|| anyhow!("OVF {} has no {}", name, k));
+            match name {
+                "File" => {
+                    let size = attrs.get("size");
+                    files.push(OvfFile {
+                        id: require("id")?.to_string(),
+                        href: require("href")?.to_string(),
+                        size: size.map(|(v, _)| parse_u64(v)).transpose()?,
+                        size_range: size.map(|(_, r)| r.clone()),
+                    });
                 }
-                config_header = Some(ent.header().clone());
-                std::io::copy(&mut ent, &mut config)?;
+                "Disk" => {
+                    let units = get("capacityAllocationUnits").unwrap_or("byte");
+                    let capacity = parse_u64(require("capacity")?)?
+                        .checked_mul(parse_units(units)?)
+                        .ok_or_else(|| anyhow!("OVF disk capacity is too large"))?;
+                    disks.push(OvfDisk {
+                        file_ref: get("fileRef").map(String::from),
+                        capacity,
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(Self { files, disks })
+    }
+
+    /// Check the sizes of the files are those of the members of the OVA in
+    /// `sizes`, and the capacities of the disks those of the VMDKs in
+    /// `capacities`, in bytes; both are keyed by name.
+    pub(crate) fn check(
+        &self,
+        sizes: &BTreeMap<String, u64>,
+        capacities: &BTreeMap<String, u64>,
+    ) -> Result<()> {
+        for f in self.files.iter() {
+            let actual = sizes
+                .get(&f.href)
+                .ok_or_else(|| anyhow!("OVF references {}, which isn't in the OVA", f.href))?;
+            match f.size {
+                Some(size) if size != *actual => {
+                    return Err(anyhow!(
+                        "OVF gives the size of {} as {}, but it's {}",
+                        f.href,
+                        size,
+                        actual
+                    ))
+                }
+                _ => {}
             }
-            Some("vmdk") => {
-                if disk_header.is_some() {
-                    return Err(anyhow!("Found multiple vmdk entries, second={}", name));
+        }
+        for d in self.disks.iter() {
+            let id = match d.file_ref.as_deref() {
+                Some(id) => id,
+                None => continue,
+            };
+            let f = self
+                .files
+                .iter()
+                .find(|f| f.id == id)
+                .ok_or_else(|| anyhow!("OVF disk references unknown file {}", id))?;
+            match capacities.get(&f.href) {
+                Some(&actual) if actual != d.capacity => {
+                    return Err(anyhow!(
+                        "OVF gives the capacity of {} as {}, but it's {}",
+                        f.href,
+                        d.capacity,
+                        actual
+                    ))
                 }
-                disk_header = Some(ent.header().clone());
-                std::io::copy(&mut ent, &mut disk_dest)?;
+                _ => {}
             }
-            _ => return Err(anyhow!("Unhandled ova file {}", name)),
+        }
+        Ok(())
+    }
+}
+
+/// Update the sizes in the OVF descriptor `xml` of the files in `sizes`,
+/// keyed by name.
+pub(crate) fn update_ovf(xml: &str, sizes: &BTreeMap<String, u64>) -> Result<String> {
+    let ovf = Ovf::parse(xml)?;
+    let mut r = String::with_capacity(xml.len());
+    let mut pos = 0;
+    for f in ovf.files.iter() {
+        if let (Some(range), Some(size)) = (f.size_range.as_ref(), sizes.get(&f.href)) {
+            r.push_str(&xml[pos..range.start]);
+            r.push_str(&size.to_string());
+            pos = range.end;
         }
     }
-    let config_header = config_header.ok_or_else(|| anyhow!("failed to find ovf entry"))?;
-    let disk_header = disk_header.ok_or_else(|| anyhow!("failed to find vmdk entry"))?;
-    Ok(OVA {
-        config_header,
-        config,
-        disk_header,
-    })
+    r.push_str(&xml[pos..]);
+    Ok(r)
+}
+
+fn parse_u64(s: &str) -> Result<u64> {
+    s.trim()
+        .parse()
+        .map_err(|_| anyhow!("Invalid OVF size {:?}", s))
+}
+
+/// The bytes in OVF allocation units, e.g. `byte * 2^20`.
+fn parse_units(s: &str) -> Result<u64> {
+    let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    let shift = match s.strip_prefix("byte") {
+        Some("") => 0,
+        Some(rest) => rest
+            .strip_prefix("*2^")
+            .and_then(|n| n.parse().ok())
+            .filter(|&n: &u32| n < 64)
+            .ok_or_else(|| anyhow!("Unsupported OVF allocation units {:?}", s))?,
+        None => return Err(anyhow!("Unsupported OVF allocation units {:?}", s)),
+    };
+    Ok(1 << shift)
+}
+
+/// An XML name without the namespace prefix, e.g. `size` for `ovf:size`.
+fn local_name(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
 }
 
-/// Create a new ustar header derived from values in the original header.
-fn header_clone_ustar(h: &tar::Header) -> Result<tar::Header> {
-    let mut n = tar::Header::new_ustar();
-    n.set_path(h.path()?)?;
-    n.set_entry_type(h.entry_type());
-    n.set_mode(h.mode()?);
-    n.set_size(h.size()?);
-    n.set_mtime(h.mtime()?);
-    n.set_size(h.size()?);
-    if let Some(u) = h.username()? {
-        n.set_username(u)?;
+/// The attributes of an element, with the location of each value in the
+/// document, by name without the namespace prefix.
+type Attrs<'a> = BTreeMap<&'a str, (&'a str, Range<usize>)>;
+
+/// The elements of the XML document `xml` and their attributes, by name
+/// without the namespace prefix.  This is just enough XML to read an OVF
+/// descriptor: entities in attribute values aren't expanded.
+fn elements(xml: &str) -> Result<Vec<(&str, Attrs<'_>)>> {
+    let mut r = Vec::new();
+    let mut pos = 0;
+    while let Some(i) = xml[pos..].find('<') {
+        let start = pos + i + 1;
+        let rest = &xml[start..];
+        if rest.starts_with("!--") {
+            let end = rest
+                .find("-->")
+                .ok_or_else(|| anyhow!("Invalid OVF: unterminated comment"))?;
+            pos = start + end + 3;
+            continue;
+        }
+        // The end of the tag, which may be in an attribute value.
+        let mut quote = None;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| match quote {
+                Some(q) if c == q => {
+                    quote = None;
+                    false
+                }
+                Some(_) => false,
+                None if c == '"' || c == '\'' => {
+                    quote = Some(c);
+                    false
+                }
+                None => c == '>',
+            })
+            .map(|(i, _)| i)
+            .ok_or_else(|| anyhow!("Invalid OVF: unterminated tag at offset {}", start))?;
+        pos = start + end + 1;
+        let tag = &rest[..end];
+        if tag.starts_with(&['/', '?', '!'][..]) {
+            continue;
+        }
+        let name_end = tag
+            .find(|c: char| c.is_whitespace() || c == '/')
+            .unwrap_or(tag.len());
+        let mut attrs = Attrs::new();
+        let mut i = name_end;
+        while let Some(eq) = tag[i..].find('=') {
+            let key = tag[i..i + eq].trim();
+            let value = &tag[i + eq + 1..];
+            let value_start = i + eq + 1 + (value.len() - value.trim_start().len()) + 1;
+            let q = tag[value_start - 1..]
+                .chars()
+                .next()
+                .filter(|&c| c == '"' || c == '\'')
+                .ok_or_else(|| anyhow!("Invalid OVF: unquoted attribute {}", key))?;
+            let len = tag[value_start..]
+                .find(q)
+                .ok_or_else(|| anyhow!("Invalid OVF: unterminated attribute {}", key))?;
+            let range = start + value_start..start + value_start + len;
+            attrs.insert(local_name(key), (&xml[range.clone()], range));
+            i = value_start + len + 1;
+        }
+        r.push((local_name(&tag[..name_end]), attrs));
     }
-    if let Some(u) = h.groupname()? {
-        n.set_groupname(u)?;
+    Ok(r)
+}
+
+/// Update the SHA-256 digests in the manifest `mf` of the members in
+/// `digests`, keyed by name.
+pub(crate) fn update_manifest(mf: &str, digests: &BTreeMap<String, String>) -> Result<String> {
+    let mut r = String::with_capacity(mf.len());
+    for line in mf.split_inclusive('\n') {
+        // e.g. `SHA256(disk.vmdk)= <hex>`
+        let entry = line
+            .split_once(")= ")
+            .and_then(|(k, _)| k.split_once('('))
+            .filter(|(_, name)| digests.contains_key(*name));
+        match entry {
+            Some(("SHA256", name)) => {
+                let end = &line[line.trim_end().len()..];
+                r.push_str(&format!("SHA256({})= {}{}", name, digests[name], end));
+            }
+            Some((alg, name)) => {
+                return Err(anyhow!("Can't update the {} digest of {}", alg, name));
+            }
+            None => r.push_str(line),
+        }
     }
-    Ok(n)
+    Ok(r)
 }
 
-#[context("Building ova")]
-pub(crate) fn ova_rebuild(
-    header: &OVA,
-    disk: impl AsRef<Utf8Path>,
-    dest: impl Write,
-) -> Result<()> {
-    let disk = disk.as_ref();
-    let diskmeta = &disk.metadata()?;
-    let mut builder = tar::Builder::new(dest);
-    let mut config_header = header_clone_ustar(&header.config_header)?;
-    config_header.set_cksum();
-    builder.append(&config_header, header.config.as_slice())?;
-    let mut disk_header = header_clone_ustar(&header.disk_header)?;
-    disk_header.set_size(diskmeta.len());
-    disk_header.set_cksum();
-    let mut disk_src = BufReader::new(File::open(disk)?);
-    builder.append(&disk_header, &mut disk_src)?;
-    builder.into_inner()?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let members: &[(&str, &[u8])] = &[
+            ("coreos.ovf", b"<Envelope/>\n"),
+            ("coreos.mf", b"SHA256(disk1.vmdk)= 00\n"),
+            ("coreos.cert", b"cert"),
+            ("disk1.vmdk", b"disk1"),
+            ("coreos.nvram", b"nvram"),
+            ("disk2.vmdk", b"disk 2"),
+        ];
+        let mut b = tar::Builder::new(Vec::new());
+        for (name, data) in members {
+            let mut h = tar::Header::new_ustar();
+            h.set_mode(0o644);
+            h.set_size(data.len() as u64);
+            b.append_data(&mut h, name, *data)?;
+        }
+        let buf = b.into_inner()?;
+        let src = &dir.join("src.ova");
+        std::fs::write(src, &buf)?;
+
+        let ova = Ova::open(src)?;
+        let names: Vec<_> = ova.entries.iter().map(|e| e.name.as_str()).collect();
+        let expected: Vec<_> = members.iter().map(|m| m.0).collect();
+        assert_eq!(names, expected);
+        let disks: Vec<_> = ova
+            .disks()
+            .map(|d| &buf[d.offset as usize..(d.offset + d.size) as usize])
+            .collect();
+        assert_eq!(disks, [&b"disk1"[..], b"disk 2"]);
+
+        std::fs::write(src, &buf[1024..])?;
+        assert!(Ova::open(src).is_err());
+        Ok(())
+    }
+
+    /// An OVF descriptor like the one coreos-assembler writes, trimmed.
+    const OVF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!--Generated by coreos-assembler, <File> elements below-->
+<Envelope xmlns="http://schemas.dmtf.org/ovf/envelope/1" xmlns:ovf="http://schemas.dmtf.org/ovf/envelope/1">
+  <References>
+    <File ovf:href="disk.vmdk" ovf:id="file1" ovf:size="1024"/>
+    <File ovf:href='disk2.vmdk' ovf:id="file2"/>
+  </References>
+  <DiskSection>
+    <Info>Virtual disk information</Info>
+    <Disk ovf:capacity="8" ovf:capacityAllocationUnits="byte * 2^20" ovf:diskId="vmdisk1" ovf:fileRef="file1" ovf:format="http://www.vmware.com/interfaces/specifications/vmdk.html#streamOptimized"/>
+    <Disk ovf:capacity="4096" ovf:diskId="vmdisk2" ovf:fileRef="file2"/>
+    <Disk ovf:capacity="1" ovf:capacityAllocationUnits="byte * 2^30" ovf:diskId="blank"/>
+  </DiskSection>
+  <VirtualSystem ovf:id="coreos"><Info>A virtual machine &gt; 1</Info></VirtualSystem>
+</Envelope>
+"#;
+
+    #[test]
+    fn test_ovf() -> Result<()> {
+        let ovf = Ovf::parse(OVF)?;
+        let files: Vec<_> = ovf
+            .files
+            .iter()
+            .map(|f| (f.id.as_str(), f.href.as_str(), f.size))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("file1", "disk.vmdk", Some(1024)),
+                ("file2", "disk2.vmdk", None)
+            ]
+        );
+        let disks: Vec<_> = ovf
+            .disks
+            .iter()
+            .map(|d| (d.file_ref.as_deref(), d.capacity))
+            .collect();
+        assert_eq!(
+            disks,
+            [
+                (Some("file1"), 8 << 20),
+                (Some("file2"), 4096),
+                (None, 1 << 30)
+            ]
+        );
+
+        let sizes: BTreeMap<_, _> = [("coreos.ovf", 10), ("disk.vmdk", 1024), ("disk2.vmdk", 7)]
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v))
+            .collect();
+        let capacities: BTreeMap<_, _> = [("disk.vmdk", 8 << 20), ("disk2.vmdk", 4096)]
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v))
+            .collect();
+        ovf.check(&sizes, &capacities)?;
+        let mut wrong = sizes.clone();
+        wrong.insert("disk.vmdk".to_string(), 1025);
+        assert!(ovf.check(&wrong, &capacities).is_err());
+        wrong.remove("disk.vmdk");
+        assert!(ovf.check(&wrong, &capacities).is_err());
+        let mut wrong = capacities.clone();
+        wrong.insert("disk2.vmdk".to_string(), 8192);
+        assert!(ovf.check(&sizes, &wrong).is_err());
+
+        // Only the sizes of the files change.
+        let updated = update_ovf(OVF, &wrong)?;
+        assert_eq!(
+            updated,
+            OVF.replace(r#"ovf:size="1024""#, &format!(r#"ovf:size="{}""#, 8 << 20))
+        );
+        let ovf = Ovf::parse(&updated)?;
+        assert_eq!(ovf.files[0].size, Some(8 << 20));
+        assert_eq!(ovf.files[1].size, None);
+
+        assert!(
+            Ovf::parse("<Disk ovf:capacity=\"1\" ovf:capacityAllocationUnits=\"sector\"/>")
+                .is_err()
+        );
+        assert!(Ovf::parse("<File ovf:id=\"f\" ovf:href=\"disk.vmdk\" ovf:size=\"1k\"/>").is_err());
+        assert!(Ovf::parse("<File ovf:id=\"file1\" ovf:href=\"disk.vmdk\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_manifest() -> Result<()> {
+        let digests: BTreeMap<_, _> = [("disk1.vmdk", "11"), ("disk2.vmdk", "22")]
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mf = "SHA256(coreos.ovf)= 00\r\nSHA256(disk1.vmdk)= 01\r\nSHA256(disk2.vmdk)= 02";
+        assert_eq!(
+            update_manifest(mf, &digests)?,
+            "SHA256(coreos.ovf)= 00\r\nSHA256(disk1.vmdk)= 11\r\nSHA256(disk2.vmdk)= 22"
+        );
+        assert!(update_manifest("SHA1(disk1.vmdk)= 01\n", &digests).is_err());
+        assert_eq!(
+            update_manifest("SHA1(coreos.ovf)= 00\n", &digests)?,
+            "SHA1(coreos.ovf)= 00\n"
+        );
+        Ok(())
+    }
 }
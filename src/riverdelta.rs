@@ -2,99 +2,110 @@
 //! This module manages a "parsed" version of a stream that is
 //! organized around how we manage deltas.
 
+use crate::strategy::{Strategy, StrategyTable};
 use anyhow::{anyhow, Context, Result};
 use camino::Utf8Path;
 use coreos_stream_metadata::{Artifact, Platform, Stream};
 use fn_error_context::context;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::convert::TryFrom;
-
-// Most of these are just just qcow2 images.
-// gcp is a tarball with a sparse disk image inside it, but for rsync that's
-// not really different than a qcow2.
-// A few others are raw disk images (azure, vultr).
-const RSYNC_STRATEGY_DISK: &[&str] = &[
-    "aliyun",
-    "azure",
-    "exoscale",
-    "openstack",
-    "ibmcloud",
-    "gcp",
-    "digitalocean",
-    "vultr",
-];
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+
 pub(crate) const QEMU: &str = "qemu";
-const METAL: &str = "metal";
-const AWS: &str = "aws";
-const VMWARE: &str = "vmware";
+pub(crate) const METAL: &str = "metal";
+/// The kind of artifact for a platform's disk image.
+const DISK: &str = "disk";
 
 /// Extension trait for Artifact.
 pub(crate) trait ArtifactExt {
     fn filename(&self) -> &str;
+    fn signature_filename(&self) -> Option<&str>;
 }
 
-pub(crate) struct MetalPXE {
-    pub(crate) kernel: Artifact,
-    pub(crate) initramfs: Artifact,
-    pub(crate) rootfs: Artifact,
-}
-
-pub(crate) struct Metal {
-    pub(crate) iso: Artifact,
-    pub(crate) pxe: MetalPXE,
+/// An artifact, and how it is dehydrated.
+pub(crate) struct Entry {
+    pub(crate) platform: String,
+    pub(crate) format: String,
+    /// The kind of artifact within the format, e.g. `disk` or `kernel`.
+    pub(crate) kind: String,
+    pub(crate) strategy: Strategy,
+    pub(crate) artifact: Artifact,
 }
 
-/// A parsed stream with data for the current CPU architecture,
+/// A parsed stream with data for a single CPU architecture,
 /// split up by delta strategy.
 pub(crate) struct RiverDelta {
     /// Name of the stream.
     pub stream: String,
+    /// When the stream metadata was last updated, e.g. `2021-05-17T20:41:46Z`.
+    pub(crate) last_modified: String,
 
-    /// Used as a basis for the qemu_rsyncable_artifact set.
+    /// Used as a basis for most other disk images.
     pub(crate) qemu: Artifact,
-    /// Images which derive from qemu.
-    pub(crate) qemu_rsyncable_artifacts: HashMap<String, Artifact>,
-    /// This is the VMDK, not the AMIs.
-    pub(crate) aws: Option<Artifact>,
-    /// vmware image is an OVA, which needs special handling.
-    pub(crate) vmware: Option<Artifact>,
-    /// The Live ISO and PXE data
-    pub(crate) metal: Option<Metal>,
-    /// Unhandled set.
-    pub(crate) unhandled: HashMap<String, Platform>,
+    /// The format of the qemu image, e.g. `qcow2.xz`.
+    pub(crate) qemu_format: String,
+    /// The PXE rootfs, if any, used as the basis for the ISO.
+    pub(crate) rootfs: Option<Artifact>,
+    /// All other included artifacts, sorted by platform, format and kind.
+    pub(crate) entries: Vec<Entry>,
+    /// Unhandled set, as `platform/format`.
+    pub(crate) unhandled: BTreeSet<String>,
 }
 
 impl RiverDelta {
-    pub(crate) fn get_rsyncable(&self, name: &str) -> Option<&Artifact> {
-        if name == AWS {
-            return self.aws.as_ref();
-        }
-        self.qemu_rsyncable_artifacts.get(name)
+    /// The modification time for generated files, in seconds since the
+    /// epoch; the stream's `last-modified` time, so that it's reproducible.
+    pub(crate) fn mtime(&self) -> Result<u64> {
+        let t = chrono::DateTime::parse_from_rfc3339(&self.last_modified)
+            .with_context(|| anyhow!("Parsing last-modified: {}", self.last_modified))?;
+        Ok(t.timestamp().try_into()?)
+    }
+
+    /// Find the entries for a platform and format.
+    pub(crate) fn find<'a>(
+        &'a self,
+        platform: &'a str,
+        format: &'a str,
+    ) -> impl Iterator<Item = &'a Entry> + 'a {
+        self.entries
+            .iter()
+            .filter(move |e| e.platform == platform && e.format == format)
+    }
+
+    /// Find the disk image for a platform.
+    pub(crate) fn find_disk(&self, platform: &str) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|e| e.platform == platform && e.kind == DISK)
+    }
+
+    /// Whether a platform has artifacts with no strategy.
+    pub(crate) fn is_unhandled(&self, platform: &str) -> bool {
+        self.unhandled
+            .iter()
+            .any(|k| k.split('/').next() == Some(platform))
     }
 
     /// Get all artifacts.
     pub(crate) fn all_artifacts(&self) -> Vec<&Artifact> {
-        use std::iter::once;
-        once(&self.qemu)
-            .chain(self.qemu_rsyncable_artifacts.values())
-            .chain(self.aws.as_ref())
-            .chain(self.vmware.as_ref())
-            .chain(
-                self.metal
-                    .iter()
-                    .flat_map(|p| vec![&p.iso, &p.pxe.kernel, &p.pxe.initramfs, &p.pxe.rootfs]),
-            )
-            .collect()
+        let mut r: Vec<_> = std::iter::once(&self.qemu)
+            .chain(self.entries.iter().map(|e| &e.artifact))
+            .collect();
+        if let Some(rootfs) = self.rootfs.as_ref() {
+            if !r.iter().any(|a| a.location == rootfs.location) {
+                r.push(rootfs);
+            }
+        }
+        r
     }
 
-    /// Size in bytes of the original artifacts (compressed).
+    /// Size in bytes of the original artifacts (compressed), downloaded to `dir`.
     #[context("Computing original compressed size")]
-    pub(crate) fn original_compressed_size(&self) -> Result<u64> {
+    pub(crate) fn original_compressed_size(&self, dir: &Utf8Path) -> Result<u64> {
         let r = self
             .all_artifacts()
             .into_par_iter()
-            .map(|a| Utf8Path::new(a.filename()))
+            .map(|a| dir.join(a.filename()))
             .try_fold(
                 || 0u64,
                 |acc, filename| {
@@ -110,14 +121,17 @@ impl RiverDelta {
     }
 }
 
-/// Remove all signatures from a stream.
-pub(crate) fn stream_remove_signatures(s: &mut Stream) -> Result<()> {
-    let utsname = nix::sys::utsname::uname();
-    let thisarch_name = utsname.machine();
+/// The CPU architecture of the running system.
+pub fn host_arch() -> String {
+    nix::sys::utsname::uname().machine().to_string()
+}
+
+/// Remove all signatures for an architecture from a stream.
+pub(crate) fn stream_remove_signatures(s: &mut Stream, arch: &str) -> Result<()> {
     let thisarch = s
         .architectures
-        .get_mut(thisarch_name)
-        .ok_or_else(|| anyhow::anyhow!("Missing this architecture in stream metadata"))?;
+        .get_mut(arch)
+        .ok_or_else(|| anyhow!("Missing architecture {} in stream metadata", arch))?;
     for platform in thisarch.artifacts.values_mut() {
         for format in platform.formats.values_mut() {
             for artifact in format.values_mut() {
@@ -135,94 +149,104 @@ fn validate_artifact(a: Artifact) -> Result<Artifact> {
     Ok(a)
 }
 
-fn platform_disk_artifact(p: Platform) -> Result<Artifact> {
-    let a = p
+/// Find the disk image of a platform, and its format.
+fn platform_disk_artifact(p: Platform) -> Result<(String, Artifact)> {
+    let (format, mut artifacts) = p
         .formats
         .into_iter()
         .next()
-        .ok_or_else(|| anyhow!("Empty platform"))?
-        .1
-        .remove("disk")
+        .ok_or_else(|| anyhow!("Empty platform"))?;
+    let a = artifacts
+        .remove(DISK)
         .ok_or_else(|| anyhow!("Missing 'disk' entry for platform"))?;
-    validate_artifact(a)
+    Ok((format, validate_artifact(a)?))
 }
 
-impl TryFrom<Stream> for RiverDelta {
-    type Error = anyhow::Error;
-
-    fn try_from(mut s: Stream) -> Result<Self, Self::Error> {
+impl RiverDelta {
+    /// Parse the artifacts for `arch` from a stream, classifying them
+    /// using `strategies`.
+    pub(crate) fn new(mut s: Stream, arch: &str, strategies: &StrategyTable) -> Result<Self> {
         let stream_name = s.stream;
-        let utsname = nix::sys::utsname::uname();
-        let thisarch_name = utsname.machine();
+        let last_modified = s.metadata.last_modified;
         let mut thisarch = s
             .architectures
-            .remove(thisarch_name)
-            .ok_or_else(|| anyhow::anyhow!("Missing this architecture in stream metadata"))?;
+            .remove(arch)
+            .ok_or_else(|| anyhow!("Missing architecture {} in stream metadata", arch))?;
         let qemu = thisarch
             .artifacts
             .remove(QEMU)
             .ok_or_else(|| anyhow!("Missing qemu"))?;
-        let qemu = platform_disk_artifact(qemu)?;
-        let metal = thisarch
-            .artifacts
-            .remove(METAL)
-            .map(|mut m| {
-                let iso = m
-                    .formats
-                    .remove("iso")
-                    .ok_or_else(|| anyhow!("metal missing `iso`"))?
-                    .remove("disk")
-                    .ok_or_else(|| anyhow!("metal/iso missing disk"))?;
-                let mut pxe = m
-                    .formats
-                    .remove("pxe")
-                    .ok_or_else(|| anyhow!("metal missing `pxe`"))?;
-                let kernel = pxe
-                    .remove("kernel")
-                    .ok_or_else(|| anyhow!("metal/pxe missing kernel"))?;
-                let initramfs = pxe
-                    .remove("initramfs")
-                    .ok_or_else(|| anyhow!("metal/pxe missing initramfs"))?;
-                let rootfs = pxe
-                    .remove("rootfs")
-                    .ok_or_else(|| anyhow!("metal/pxe missing rootfs"))?;
-                let pxe = MetalPXE {
-                    kernel,
-                    initramfs,
-                    rootfs,
+        let (qemu_format, qemu) = platform_disk_artifact(qemu)?;
+        let mut rootfs = None;
+        let mut entries = Vec::new();
+        let mut unhandled = BTreeSet::new();
+        for (platform, p) in thisarch.artifacts {
+            for (format, artifacts) in p.formats {
+                // The ISO is generated from the rootfs even if the PXE
+                // artifacts themselves are skipped.
+                if format == "pxe" {
+                    if let Some(a) = artifacts.get("rootfs") {
+                        rootfs = Some(validate_artifact(a.clone())?);
+                    }
+                }
+                let strategy = match strategies.lookup(&platform, &format) {
+                    Some(Strategy::Skip) => continue,
+                    Some(s) => s,
+                    None => {
+                        unhandled.insert(format!("{}/{}", platform, format));
+                        continue;
+                    }
                 };
-                Ok::<_, anyhow::Error>(Metal { iso, pxe })
-            })
-            .transpose()?;
-        let aws = thisarch
-            .artifacts
-            .remove(AWS)
-            .map(platform_disk_artifact)
-            .transpose()?;
-        let vmware = thisarch
-            .artifacts
-            .remove(VMWARE)
-            .map(platform_disk_artifact)
-            .transpose()?;
-        let (qemu_rsyncable_artifacts, unhandled): (HashMap<_, _>, HashMap<_, _>) = thisarch
-            .artifacts
-            .into_par_iter()
-            .partition(|(name, _)| RSYNC_STRATEGY_DISK.contains(&name.as_str()));
-        let qemu_rsyncable_artifacts: Result<HashMap<_, _>> = qemu_rsyncable_artifacts
-            .into_iter()
-            .map(|(k, v)| {
-                let a = platform_disk_artifact(v)?;
-                Ok::<_, anyhow::Error>((k, a))
-            })
-            .collect();
-        let qemu_rsyncable_artifacts = qemu_rsyncable_artifacts?;
+                for (kind, artifact) in artifacts {
+                    let artifact = validate_artifact(artifact)
+                        .with_context(|| anyhow!("Parsing {}/{}", platform, format))?;
+                    entries.push(Entry {
+                        platform: platform.clone(),
+                        format: format.clone(),
+                        kind,
+                        strategy,
+                        artifact,
+                    });
+                }
+            }
+        }
+        entries.sort_by(|a, b| {
+            (&a.platform, &a.format, &a.kind).cmp(&(&b.platform, &b.format, &b.kind))
+        });
+        for e in entries.iter() {
+            match e.strategy {
+                Strategy::IsoFromRootfs if rootfs.is_none() => {
+                    return Err(anyhow!("{}/{} requires a PXE rootfs", e.platform, e.format));
+                }
+                // Outputs are named and validated as uncompressed images.
+                Strategy::Copy
+                    if crate::maybe_uncompressed_name(e.artifact.filename()).is_some() =>
+                {
+                    return Err(anyhow!(
+                        "{}/{}: Strategy copy doesn't apply to compressed artifacts",
+                        e.platform,
+                        e.format
+                    ));
+                }
+                Strategy::RsyncFromQemu | Strategy::Vmdk | Strategy::Ova if e.kind != DISK => {
+                    return Err(anyhow!(
+                        "{}/{}: Strategy {:?} only applies to disk images, not {}",
+                        e.platform,
+                        e.format,
+                        e.strategy,
+                        e.kind
+                    ));
+                }
+                _ => {}
+            }
+        }
         Ok(RiverDelta {
             stream: stream_name,
+            last_modified,
             qemu,
-            qemu_rsyncable_artifacts,
-            aws,
-            vmware,
-            metal,
+            qemu_format,
+            rootfs,
+            entries,
             unhandled,
         })
     }
@@ -235,4 +259,48 @@ impl ArtifactExt for Artifact {
     fn filename(&self) -> &str {
         Utf8Path::new(&self.location).file_name().unwrap()
     }
+
+    /// Return the filename for the artifact's signature, if any.
+    fn signature_filename(&self) -> Option<&str> {
+        self.signature
+            .as_deref()
+            .and_then(|s| Utf8Path::new(s).file_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream() -> Result<Stream> {
+        let s = include_str!("../tests/it/fixtures/stream.json");
+        Ok(serde_json::from_str(s)?)
+    }
+
+    #[test]
+    fn test_skip_pxe() -> Result<()> {
+        let mut t = StrategyTable::default();
+        t.set(&"metal/pxe=skip".parse()?);
+        let rd = RiverDelta::new(stream()?, "x86_64", &t)?;
+        assert!(rd.entries.iter().all(|e| e.format != "pxe"));
+        assert!(rd
+            .entries
+            .iter()
+            .any(|e| e.format == "iso" && e.strategy == Strategy::IsoFromRootfs));
+        assert!(rd
+            .rootfs
+            .unwrap()
+            .location
+            .ends_with("live-rootfs.x86_64.img"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_compressed() -> Result<()> {
+        let mut t = StrategyTable::default();
+        t.set(&"metal/raw.xz=copy".parse()?);
+        let e = RiverDelta::new(stream()?, "x86_64", &t).err().unwrap();
+        assert!(e.to_string().contains("compressed"));
+        Ok(())
+    }
 }
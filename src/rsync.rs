@@ -1,134 +1,645 @@
-//use rayon::prelude::*;
+//! An rsync-style rolling checksum delta engine.
+//!
+//! A delta is a [`DeltaHeader`] identifying the source and target files,
+//! followed by a zstd-compressed sequence of [`DeltaOp`] entries which
+//! either copy a range from the source file, or insert literal data.
+//! Generating a delta indexes every block of the source file by its weak
+//! rolling checksum, then scans the target byte-by-byte looking for matches.
+//! Unlike rsync, both files are local, so candidate matches are verified by
+//! directly comparing bytes rather than with a strong checksum.
+
+use crate::bundle;
+use crate::utils;
 use anyhow::{anyhow, Context, Result};
+use bincode::Options;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use camino::{Utf8Path, Utf8PathBuf};
 use fn_error_context::context;
 use serde_derive::{Deserialize, Serialize};
-use std::convert::TryInto;
+use smallvec::SmallVec;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tracing::info;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct RollsumDeltaHeader {
-    src_name: String,
-    dest_name: String,
-}
-
-//fn reflink(src: impl AsRef<Utf8Path>, dest: impl AsRef<Utf8Path>) -> Result<()> {
-//    let status = Command::new("cp")
-//        .args(&["-p", "--reflink=auto"])
-//        .arg(src.as_ref())
-//        .arg(dest.as_ref())
-//        .status()?;
-//    if !status.success() {
-//        return Err(anyhow!("cp --reflink failed: {:?}", status));
-//    }
-//    Ok(())
-//}
-
-fn setup_rsync_src(src: &Utf8Path, tempdir: &Utf8Path) -> Result<()> {
-    let src_filename = src
-        .file_name()
-        .ok_or_else(|| anyhow!("Invalid source filename {}", src))?;
-    let orig = "orig";
-    let origdir = tempdir.join(orig);
-    std::fs::create_dir(&origdir)?;
-    std::fs::hard_link(src, origdir.join(src_filename)).context("Creating src hardlink")?;
+/// Size of the blocks in the source file which we index.
+const BLOCK_SIZE: usize = 4096;
+/// Flush literal data once it reaches this size.
+const MAX_LITERAL: usize = 1024 * 1024;
+/// The largest encoded delta operation: a literal is flushed once it reaches
+/// `MAX_LITERAL`, having grown by less than a block.
+const MAX_OP_SIZE: u64 = (MAX_LITERAL + BLOCK_SIZE + 16) as u64;
+/// How much of the target we read at a time.
+const READ_CHUNK: usize = 4 * 1024 * 1024;
+/// Maximum number of source blocks we remember for a single checksum;
+/// this avoids degenerate behavior with e.g. runs of zeroes.
+const MAX_CANDIDATES: usize = 8;
+/// Added to each byte in the rolling checksum, same as rsync.
+const CHAR_OFFSET: u32 = 31;
+
+/// Magic bytes at the start of every delta file.
+const MAGIC: &[u8; 8] = b"RDELTA\0\0";
+/// Current version of the delta file format.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+/// Magic bytes of a zstd frame, which is how older delta files (zstd
+/// compressed rsync batches, without a header) start.
+const ZSTD_MAGIC: &[u8; 4] = b"\x28\xb5\x2f\xfd";
+
+/// The algorithm used to generate the delta operations.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum DeltaAlgorithm {
+    /// Rolling checksum with the given block size.
+    Rollsum { block_size: u32 },
+}
+
+/// Identity of a file a delta applies to or produces.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileInfo {
+    pub(crate) sha256: String,
+    pub(crate) size: u64,
+}
+
+impl FileInfo {
+    fn from_path(p: &Utf8Path) -> Result<Self> {
+        let size = p.metadata()?.len();
+        let sha256 = utils::sha256_file(p)?;
+        Ok(Self { sha256, size })
+    }
+}
+
+/// Header for a delta file.  On disk, this follows the magic bytes
+/// and a little-endian `u32` format version, and is uncompressed so
+/// that it can be inspected cheaply.  The delta operations follow
+/// as a zstd stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeltaHeader {
+    pub(crate) algorithm: DeltaAlgorithm,
+    /// The file the delta must be applied to.
+    pub(crate) src: FileInfo,
+    /// The file the delta generates.
+    pub(crate) target: FileInfo,
+    /// Filename of the generated file.
+    pub(crate) target_name: String,
+}
+
+/// Errors from applying a delta.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum DeltaError {
+    #[error("Not a delta file (invalid magic)")]
+    InvalidMagic,
+    #[error("Unsupported delta format version {0} (expected {})", FORMAT_VERSION)]
+    UnsupportedVersion(u32),
+    #[error("Invalid delta: {0}")]
+    Decode(#[from] bincode::Error),
+    #[error("Truncated delta (missing end marker)")]
+    Truncated,
+    #[error("Invalid delta: operation larger than {} bytes", MAX_OP_SIZE)]
+    OpTooLarge,
+    #[error("Copy of {len} bytes at offset {offset} exceeds source size {size}")]
+    CopyOutOfRange { offset: u64, len: u64, size: u64 },
+    #[error("Delta expects source of size {expected}, found {actual}")]
+    SourceSizeMismatch { expected: u64, actual: u64 },
+    #[error("Delta expects source with SHA-256 {expected}, found {actual}")]
+    SourceChecksumMismatch { expected: String, actual: String },
+    #[error("Delta generated {actual} bytes, expected {expected}")]
+    TargetSizeMismatch { expected: u64, actual: u64 },
+}
+
+/// A single instruction in a delta.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum DeltaOp {
+    /// Copy `len` bytes starting at `offset` from the source.
+    Copy { offset: u64, len: u64 },
+    /// Insert literal data.
+    Literal(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// Marks the end of the delta, so truncation is detected.
+    End,
+}
+
+/// The rsync rolling checksum.
+#[derive(Debug, Default, Clone, Copy)]
+struct Rollsum {
+    a: u32,
+    b: u32,
+}
+
+impl Rollsum {
+    fn new(buf: &[u8]) -> Self {
+        let mut r = Self::default();
+        let l = buf.len() as u32;
+        for (i, &c) in buf.iter().enumerate() {
+            let c = c as u32 + CHAR_OFFSET;
+            r.a = r.a.wrapping_add(c);
+            r.b = r.b.wrapping_add((l - i as u32).wrapping_mul(c));
+        }
+        r
+    }
+
+    /// Remove `out` from the front of the window of size `len`, and append `inb`.
+    fn rotate(&mut self, out: u8, inb: u8, len: usize) {
+        let out = out as u32 + CHAR_OFFSET;
+        let inb = inb as u32 + CHAR_OFFSET;
+        self.a = self.a.wrapping_sub(out).wrapping_add(inb);
+        self.b = self
+            .b
+            .wrapping_sub((len as u32).wrapping_mul(out))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Index of source blocks by rolling checksum.
+struct Signature {
+    src: File,
+    blocks: HashMap<u32, SmallVec<[u64; 1]>>,
+}
+
+impl Signature {
+    fn new(mut src: File) -> Result<Self> {
+        src.seek(SeekFrom::Start(0))?;
+        let mut blocks: HashMap<u32, SmallVec<[u64; 1]>> = HashMap::new();
+        let mut r = BufReader::with_capacity(READ_CHUNK, &src);
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut idx = 0u64;
+        loop {
+            let n = read_full(&mut r, &mut buf)?;
+            if n < BLOCK_SIZE {
+                break;
+            }
+            let candidates = blocks.entry(Rollsum::new(&buf).digest()).or_default();
+            if candidates.len() < MAX_CANDIDATES {
+                candidates.push(idx);
+            }
+            idx += 1;
+        }
+        drop(r);
+        Ok(Self { src, blocks })
+    }
+
+    /// Find a source block matching `window`, preferring `hint` if it matches.
+    fn find(
+        &self,
+        sum: &Rollsum,
+        window: &[u8],
+        hint: Option<u64>,
+        scratch: &mut [u8],
+    ) -> Result<Option<u64>> {
+        let candidates = match self.blocks.get(&sum.digest()) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let hinted = hint.filter(|h| candidates.contains(h));
+        for &idx in hinted.iter().chain(candidates.iter()) {
+            self.src
+                .read_exact_at(scratch, idx * BLOCK_SIZE as u64)
+                .context("Reading source block")?;
+            if scratch == window {
+                return Ok(Some(idx));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Like `read_exact`, but returns a short count at EOF.
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(l) => n += l,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(n)
+}
+
+/// Accumulates delta operations, coalescing adjacent copies.
+struct DeltaWriter<W: Write> {
+    out: W,
+    literal: Vec<u8>,
+    copy: Option<(u64, u64)>,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    fn emit(&mut self, op: &DeltaOp) -> Result<()> {
+        bincode::serialize_into(&mut self.out, op)?;
+        Ok(())
+    }
+
+    fn flush_copy(&mut self) -> Result<()> {
+        if let Some((offset, len)) = self.copy.take() {
+            self.emit(&DeltaOp::Copy { offset, len })?;
+        }
+        Ok(())
+    }
+
+    fn flush_literal(&mut self) -> Result<()> {
+        if !self.literal.is_empty() {
+            let literal = std::mem::take(&mut self.literal);
+            self.emit(&DeltaOp::Literal(literal))?;
+        }
+        Ok(())
+    }
+
+    fn push_copy(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.flush_literal()?;
+        match self.copy.as_mut() {
+            Some((o, l)) if *o + *l == offset => *l += len,
+            _ => {
+                self.flush_copy()?;
+                self.copy = Some((offset, len));
+            }
+        }
+        Ok(())
+    }
+
+    fn push_literal(&mut self, buf: &[u8]) -> Result<()> {
+        self.flush_copy()?;
+        self.literal.extend_from_slice(buf);
+        if self.literal.len() >= MAX_LITERAL {
+            self.flush_literal()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W> {
+        self.flush_copy()?;
+        self.flush_literal()?;
+        self.emit(&DeltaOp::End)?;
+        Ok(self.out)
+    }
+}
+
+/// Compute the delta from `sig` to `target`, writing uncompressed operations to `out`.
+fn compute_delta<W: Write>(sig: &Signature, mut target: impl Read, out: W) -> Result<W> {
+    let mut w = DeltaWriter {
+        out,
+        literal: Vec::new(),
+        copy: None,
+    };
+    let mut scratch = vec![0u8; BLOCK_SIZE];
+    let mut buf: Vec<u8> = Vec::with_capacity(READ_CHUNK + BLOCK_SIZE);
+    // Offset in `buf` of the current window.
+    let mut pos = 0usize;
+    let mut eof = false;
+    let mut sum: Option<Rollsum> = None;
+    // Source block after the last match; likely to match next.
+    let mut hint: Option<u64> = None;
+    loop {
+        // Ensure we have a full window available (or are at EOF).
+        if !eof && buf.len() - pos < BLOCK_SIZE + 1 {
+            buf.drain(..pos);
+            pos = 0;
+            let start = buf.len();
+            buf.resize(start + READ_CHUNK, 0);
+            let n = read_full(&mut target, &mut buf[start..])?;
+            buf.truncate(start + n);
+            eof = n < READ_CHUNK;
+        }
+        if buf.len() - pos < BLOCK_SIZE {
+            w.push_literal(&buf[pos..])?;
+            break;
+        }
+        let window = &buf[pos..pos + BLOCK_SIZE];
+        let cur = *sum.get_or_insert_with(|| Rollsum::new(window));
+        if let Some(idx) = sig.find(&cur, window, hint, &mut scratch)? {
+            w.push_copy(idx * BLOCK_SIZE as u64, BLOCK_SIZE as u64)?;
+            hint = Some(idx + 1);
+            pos += BLOCK_SIZE;
+            sum = None;
+            continue;
+        }
+        w.push_literal(&buf[pos..pos + 1])?;
+        if let Some(&next) = buf.get(pos + BLOCK_SIZE) {
+            if let Some(s) = sum.as_mut() {
+                s.rotate(buf[pos], next, BLOCK_SIZE);
+            }
+        } else {
+            sum = None;
+        }
+        pos += 1;
+    }
+    w.finish()
+}
+
+/// How the header and operations of a delta are decoded: as by
+/// `bincode::deserialize_from`, but limiting the size of each, so a corrupt
+/// length can't make us allocate without bound.
+fn decode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_OP_SIZE)
+}
+
+/// Apply uncompressed delta operations from `delta` to `src`, writing the result to `out`.
+/// Returns the number of bytes written.
+fn apply_delta(src: &File, mut delta: impl Read, mut out: impl Write) -> Result<u64> {
+    let size = src.metadata()?.len();
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut written = 0u64;
+    loop {
+        let op: DeltaOp = match decode_options().deserialize_from(&mut delta) {
+            Ok(op) => op,
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref ioe)
+                    if ioe.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return Err(DeltaError::Truncated.into())
+                }
+                bincode::ErrorKind::SizeLimit => return Err(DeltaError::OpTooLarge.into()),
+                _ => return Err(DeltaError::Decode(e).into()),
+            },
+        };
+        match op {
+            DeltaOp::Copy { offset, len } => {
+                if offset.checked_add(len).map(|e| e > size).unwrap_or(true) {
+                    return Err(DeltaError::CopyOutOfRange { offset, len, size }.into());
+                }
+                let mut remaining = len;
+                let mut offset = offset;
+                while remaining > 0 {
+                    let n = remaining.min(buf.len() as u64) as usize;
+                    let buf = &mut buf[..n];
+                    src.read_exact_at(buf, offset)
+                        .context("Reading source file")?;
+                    out.write_all(buf)?;
+                    offset += n as u64;
+                    remaining -= n as u64;
+                }
+                written += len;
+            }
+            DeltaOp::Literal(data) => {
+                out.write_all(&data)?;
+                written += data.len() as u64;
+            }
+            DeltaOp::End => break,
+        }
+    }
+    Ok(written)
+}
+
+fn write_header(h: &DeltaHeader, mut out: impl Write) -> Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+    bincode::serialize_into(out, h)?;
+    Ok(())
+}
+
+/// Read the header of the delta `patch` from `r`.
+fn read_header_from(mut r: impl Read, patch: &Utf8Path) -> Result<DeltaHeader> {
+    let mut magic = [0u8; MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if magic.starts_with(ZSTD_MAGIC) {
+        return Err(bundle::Error::FormatTooOld(patch.to_string()).into());
+    }
+    if &magic != MAGIC {
+        return Err(DeltaError::InvalidMagic.into());
+    }
+    let version = r.read_u32::<LittleEndian>()?;
+    if version != FORMAT_VERSION {
+        return Err(DeltaError::UnsupportedVersion(version).into());
+    }
+    let h = decode_options()
+        .deserialize_from(r)
+        .map_err(DeltaError::Decode)?;
+    Ok(h)
+}
+
+/// The SHA-256 of the sources deltas are applied to, keyed by path and
+/// modification time, so that applying many deltas to the same (large) file
+/// only reads it once.
+#[derive(Debug, Default)]
+pub(crate) struct SourceCache {
+    digests: Mutex<HashMap<(Utf8PathBuf, SystemTime), SourceDigest>>,
+}
+
+/// The SHA-256 of a source, once it's been computed.
+type SourceDigest = Arc<Mutex<Option<String>>>;
+
+impl SourceCache {
+    /// The SHA-256 of `path`, computed the first time it's needed.
+    fn sha256(&self, path: &Utf8Path) -> Result<String> {
+        let key = (path.to_owned(), path.metadata()?.modified()?);
+        let digest = Arc::clone(self.digests.lock().unwrap().entry(key).or_default());
+        // Others wanting the same file wait for us, rather than reading it too.
+        let mut digest = digest.lock().unwrap();
+        match &*digest {
+            Some(d) => Ok(d.clone()),
+            None => {
+                let d = utils::sha256_file(path)?;
+                *digest = Some(d.clone());
+                Ok(d)
+            }
+        }
+    }
+}
+
+/// Verify that `src` is the file expected by `header`.
+fn verify_source(header: &DeltaHeader, src: &Utf8Path, sources: &SourceCache) -> Result<()> {
+    let actual = src.metadata()?.len();
+    let expected = header.src.size;
+    if expected != actual {
+        return Err(DeltaError::SourceSizeMismatch { expected, actual }.into());
+    }
+    let actual = sources.sha256(src)?;
+    if header.src.sha256 != actual {
+        return Err(DeltaError::SourceChecksumMismatch {
+            expected: header.src.sha256.clone(),
+            actual,
+        }
+        .into());
+    }
     Ok(())
 }
 
 #[context("Generating rsync delta")]
-pub(crate) fn prepare(
-    src: &Utf8Path,
-    dest: &Utf8Path,
-    tempdir: &Utf8Path,
-    patch: impl Write,
-) -> Result<()> {
-    let tempdir = tempfile::tempdir_in(tempdir).context("Creating tempdir")?;
-    let tempdir: &Utf8Path = tempdir.path().try_into()?;
-    let src_filename = src
-        .file_name()
-        .ok_or_else(|| anyhow!("Invalid source filename {}", src))?;
-    setup_rsync_src(src, tempdir)?;
-    let destdir = tempdir.join("new");
-    std::fs::create_dir(&destdir)?;
-    std::fs::hard_link(dest, destdir.join(src_filename))
-        .with_context(|| format!("Creating dest hardlink from {}", dest))?;
-    let out: Utf8PathBuf = tempdir.join("d");
+pub(crate) fn prepare(src: &Utf8Path, dest: &Utf8Path, mut patch: impl Write) -> Result<()> {
     info!("Preparing delta: {} -> {}", src, dest);
-    let status = Command::new("rsync")
-        .args(&["-rl"])
-        .arg(format!("--only-write-batch={}", out.as_str()))
-        .args(&["new/", "orig/"]) // FIXME I have no idea why these need to be (apparently) inverted
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .current_dir(tempdir)
-        .status()?;
-    if !status.success() {
-        return Err(anyhow!("rsync failed: {:?}", status));
-    }
-
-    let batchf = File::open(&out).with_context(|| format!("Reading delta file {}", out))?;
-    let mut batchf = std::io::BufReader::new(batchf);
-    // zstd encode the rsync delta because it saves space.
-    let mut patch = zstd::Encoder::new(patch, 7)?;
-    std::io::copy(&mut batchf, &mut patch)?;
+    let target_name = dest
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid target filename {}", dest))?;
+    let header = DeltaHeader {
+        algorithm: DeltaAlgorithm::Rollsum {
+            block_size: BLOCK_SIZE as u32,
+        },
+        src: FileInfo::from_path(src)?,
+        target: FileInfo::from_path(dest)?,
+        target_name: target_name.to_string(),
+    };
+    write_header(&header, &mut patch)?;
+    let srcf = File::open(src).with_context(|| anyhow!("Opening {}", src))?;
+    let sig = Signature::new(srcf)?;
+    let destf = File::open(dest).with_context(|| anyhow!("Opening {}", dest))?;
+    // zstd encode the delta because it saves space.
+    let patch = zstd::Encoder::new(patch, 7)?;
+    let patch = compute_delta(&sig, destf, BufWriter::new(patch))?;
+    let patch = patch.into_inner().map_err(|e| e.into_error())?;
     patch.finish()?;
     Ok(())
 }
 
+/// Read just the header from a delta file.
+#[context("Reading delta header from {}", patch)]
+pub(crate) fn read_header(patch: &Utf8Path) -> Result<DeltaHeader> {
+    let f = File::open(patch)?;
+    read_header_from(BufReader::new(f), patch)
+}
+
+/// Apply a delta to `src`, streaming the generated data to `out`.
+/// Returns the number of bytes written; the size and SHA-256 of the
+/// generated data are verified against the delta header, as is `src`,
+/// whose SHA-256 is only computed once for each `sources`.
 #[context("Applying rsync delta")]
+pub(crate) fn apply_to(
+    sources: &SourceCache,
+    src: &Utf8Path,
+    patch: impl AsRef<Utf8Path>,
+    out: impl Write,
+) -> Result<u64> {
+    let patch = patch.as_ref();
+    let srcf = File::open(src).with_context(|| anyhow!("Opening {}", src))?;
+    let mut patchin =
+        BufReader::new(File::open(patch).with_context(|| anyhow!("Opening {}", patch))?);
+    let header = read_header_from(&mut patchin, patch)?;
+    verify_source(&header, src, sources)?;
+    let patchin = zstd::Decoder::with_buffer(patchin)?;
+    info!("Rehydrating: {} -> {}", src, header.target_name);
+    let mut out = utils::Sha256Writer::new(out);
+    let actual = apply_delta(&srcf, BufReader::new(patchin), &mut out)?;
+    let expected = header.target.size;
+    if expected != actual {
+        return Err(DeltaError::TargetSizeMismatch { expected, actual }.into());
+    }
+    out.verify(&header.target_name, &header.target.sha256)?
+        .flush()?;
+    Ok(actual)
+}
+
+/// Apply a delta to `src`, writing the result to `dest_filename`.
 pub(crate) fn apply(
+    sources: &SourceCache,
     src: &Utf8Path,
     dest_filename: &str,
     tempdir: &Utf8Path,
     patch: impl AsRef<Utf8Path>,
 ) -> Result<()> {
-    let src_filename = src
-        .file_name()
-        .ok_or_else(|| anyhow!("Invalid source filename {}", src))?;
-
-    let tempdir = tempfile::tempdir_in(tempdir).context("Creating tempdir")?;
-    let tempdir: &Utf8Path = tempdir.path().try_into()?;
-
-    let patch = {
-        let patch = patch.as_ref();
-        let mut patchin = zstd::Decoder::new(File::open(patch)?)?;
-        let mut p = tempfile::NamedTempFile::new_in(tempdir)?;
-        std::io::copy(&mut patchin, &mut p)?;
-        p.flush()?;
-        p
-    };
-    let patch: &Utf8Path = patch.path().try_into()?;
-
-    let destdir = tempdir.join("new");
-    let temp_dest = destdir.join(src_filename);
-    std::fs::create_dir(&destdir)?;
-    std::fs::hard_link(src, &temp_dest).context("Creating dest hardlink")?;
-
-    info!("Rehydrating: {} -> {}", src, dest_filename);
-    let status = Command::new("rsync")
-        .args(&["-rl"])
-        .arg(format!("--read-batch={}", patch))
-        .args(&["new/"])
-        .current_dir(tempdir)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .status()?;
-    if !status.success() {
-        return Err(anyhow!("rsync failed: {:?}", status));
-    }
-    std::fs::rename(&temp_dest, dest_filename)
-        .with_context(|| anyhow!("Renaming {}", temp_dest))?;
+    let mut temp_dest = tempfile::NamedTempFile::new_in(tempdir).context("Creating tempfile")?;
+    apply_to(sources, src, patch, BufWriter::new(temp_dest.as_file_mut()))?;
+    temp_dest
+        .persist(dest_filename)
+        .map_err(|e| e.error)
+        .with_context(|| anyhow!("Renaming to {}", dest_filename))?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use byteorder::ReadBytesExt;
-    use std::io::Seek;
+    use nix::sys::time::{TimeVal, TimeValLike};
+    use std::convert::TryInto;
+
+    /// Roundtrip `target` through a delta against `src`, returning the delta size.
+    fn roundtrip(src: &[u8], target: &[u8]) -> Result<usize> {
+        let mut srcf = tempfile::tempfile()?;
+        srcf.write_all(src)?;
+        let sig = Signature::new(srcf)?;
+        let delta = compute_delta(&sig, target, Vec::new())?;
+        let mut out = Vec::new();
+        let n = apply_delta(&sig.src, delta.as_slice(), &mut out)?;
+        assert_eq!(n, target.len() as u64);
+        assert!(out == target);
+        Ok(delta.len())
+    }
+
+    /// Deterministic pseudo-random data.
+    fn testdata(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rollsum() {
+        let data = testdata(BLOCK_SIZE * 2, 1);
+        let mut s = Rollsum::new(&data[..BLOCK_SIZE]);
+        for i in 0..BLOCK_SIZE {
+            s.rotate(data[i], data[i + BLOCK_SIZE], BLOCK_SIZE);
+            assert_eq!(
+                s.digest(),
+                Rollsum::new(&data[i + 1..i + 1 + BLOCK_SIZE]).digest()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_roundtrip() -> Result<()> {
+        let src = testdata(BLOCK_SIZE * 64 + 17, 42);
+        assert!(roundtrip(&[], &[])? < 16);
+        roundtrip(&[], &src)?;
+        roundtrip(&src, &[])?;
+        assert!(roundtrip(&src, &src)? < 64);
+        // Insertion and deletion shift the remaining data.
+        let mut target = src.clone();
+        target.splice(1000..1000, b"inserted".iter().cloned());
+        target.drain(BLOCK_SIZE * 10..BLOCK_SIZE * 12 + 5);
+        target.extend_from_slice(&testdata(300, 7));
+        assert!(roundtrip(&src, &target)? < BLOCK_SIZE * 4);
+        // Runs of zeroes
+        let zeroes = vec![0u8; BLOCK_SIZE * 16];
+        assert!(roundtrip(&zeroes, &zeroes[..BLOCK_SIZE * 8 + 3])? < 128);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_invalid() -> Result<()> {
+        let src = tempfile::tempfile()?;
+        let mut delta = Vec::new();
+        let op = DeltaOp::Copy { offset: 0, len: 10 };
+        bincode::serialize_into(&mut delta, &op)?;
+        let e = apply_delta(&src, delta.as_slice(), std::io::sink()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::CopyOutOfRange { .. })
+        ));
+        let delta: &[u8] = &[];
+        let e = apply_delta(&src, delta, std::io::sink()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::Truncated)
+        ));
+        // A literal with a huge length isn't allocated.
+        let mut delta = Vec::new();
+        bincode::serialize_into(&mut delta, &DeltaOp::Literal(vec![0u8; 8]))?;
+        delta[4..12].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        let e = apply_delta(&src, delta.as_slice(), std::io::sink()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::OpTooLarge)
+        ));
+        // The largest literal we write is accepted.
+        let mut delta = Vec::new();
+        let literal = vec![1u8; MAX_LITERAL + BLOCK_SIZE - 1];
+        bincode::serialize_into(&mut delta, &DeltaOp::Literal(literal))?;
+        bincode::serialize_into(&mut delta, &DeltaOp::End)?;
+        let n = apply_delta(&src, delta.as_slice(), std::io::sink())?;
+        assert_eq!(n, (MAX_LITERAL + BLOCK_SIZE - 1) as u64);
+        Ok(())
+    }
 
     #[test]
     fn test_rsync_delta() -> Result<()> {
@@ -148,23 +659,128 @@ mod tests {
             f.seek(std::io::SeekFrom::Start(l / 3))?;
             let b = f.read_u8()?;
             let nb = b.wrapping_add(1);
+            f.seek(std::io::SeekFrom::Start(l / 3))?;
             f.write_all(&[nb])?;
         }
         let patch = {
             let patch = td.join("rdelta");
             let mut out = File::create(&patch)?;
-            super::prepare(src, dest, td, &mut out)?;
+            super::prepare(src, dest, &mut out)?;
             out.flush()?;
             patch
         };
-        let orig_dest = format!("{}.orig", dest);
+        let orig_dest = &format!("{}.orig", dest);
         std::fs::rename(dest, orig_dest).context("Renaming dest to .orig")?;
 
-        super::apply(src, dest.as_str(), td, &patch)?;
-        let s = Command::new("cmp").args(&[src, dest]).status()?;
-        if !s.success() {
-            return Err(anyhow!("cmp failed: {:?}", s));
-        }
+        super::apply(&SourceCache::default(), src, dest.as_str(), td, &patch)?;
+        assert!(std::fs::read(dest)? == std::fs::read(orig_dest)?);
+        assert!(std::fs::read(dest)? != std::fs::read(src)?);
+        Ok(())
+    }
+
+    /// Write a delta in `td` from a copy of `/usr/bin/sh` to it with a byte
+    /// changed, returning the source and the delta.
+    fn sh_delta(td: &Utf8Path) -> Result<(Utf8PathBuf, Utf8PathBuf)> {
+        let src = td.join("sh.pristine");
+        std::fs::copy("/usr/bin/sh", &src)?;
+        let mut data = std::fs::read(&src)?;
+        let i = data.len() / 3;
+        data[i] = data[i].wrapping_add(1);
+        let dest = td.join("sh");
+        std::fs::write(&dest, &data)?;
+        let patch = td.join("rdelta");
+        let mut out = File::create(&patch)?;
+        super::prepare(&src, &dest, &mut out)?;
+        out.flush()?;
+        Ok((src, patch))
+    }
+
+    #[test]
+    fn test_delta_header() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let (src, patch) = &sh_delta(td)?;
+        let h = read_header(patch)?;
+        assert_eq!(h.target_name, "sh");
+        assert_eq!(h.target.size, src.metadata()?.len());
+        assert_eq!(h.target.sha256, utils::sha256_file(td.join("sh"))?);
+        assert_eq!(h.src.size, src.metadata()?.len());
+        assert_eq!(h.src.sha256, utils::sha256_file(src)?);
+        let e = read_header_from(File::open(src)?, src).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::InvalidMagic)
+        ));
+        // A delta from before there was a header: a zstd compressed rsync
+        // batch.
+        let old = &td.join("old.rdelta");
+        let mut out = zstd::Encoder::new(File::create(old)?, 7)?;
+        out.write_all(b"rsync batch")?;
+        out.finish()?;
+        let e = read_header(old).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<bundle::Error>(),
+            Some(bundle::Error::FormatTooOld(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_mismatch() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let (_, patch) = &sh_delta(td)?;
+        // Applying against the wrong source fails without generating anything.
+        let wrong = &td.join("wrong");
+        let sources = &SourceCache::default();
+        let e = super::apply(sources, &td.join("sh"), wrong.as_str(), td, patch).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::SourceChecksumMismatch { .. })
+        ));
+        assert!(!wrong.exists());
+        let short = &td.join("short");
+        std::fs::write(short, b"short")?;
+        let e = super::apply(sources, short, wrong.as_str(), td, patch).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::SourceSizeMismatch { .. })
+        ));
+        assert!(!wrong.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_cache() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td: &Utf8Path = td.path().try_into()?;
+        let (src, patch) = &sh_delta(td)?;
+        let set_mtime = |secs| {
+            let t = TimeVal::seconds(secs);
+            nix::sys::stat::utimes(src.as_std_path(), &t, &t)
+        };
+        set_mtime(1_000_000)?;
+        let sources = &SourceCache::default();
+        super::apply_to(sources, src, patch, std::io::sink())?;
+        // The SHA-256 of the source is cached until it's modified, so
+        // changing it behind the cache's back without touching the mtime goes
+        // unnoticed until the generated data is verified.
+        let pristine = std::fs::read(src)?;
+        let mut changed = pristine.clone();
+        let i = changed.len() / 2;
+        changed[i] = changed[i].wrapping_add(1);
+        std::fs::write(src, &changed)?;
+        set_mtime(1_000_000)?;
+        let e = super::apply_to(sources, src, patch, std::io::sink()).unwrap_err();
+        assert!(e.is::<utils::ChecksumMismatch>());
+        set_mtime(2_000_000)?;
+        let e = super::apply_to(sources, src, patch, std::io::sink()).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<DeltaError>(),
+            Some(DeltaError::SourceChecksumMismatch { .. })
+        ));
+        std::fs::write(src, &pristine)?;
+        super::apply_to(sources, src, patch, std::io::sink())?;
         Ok(())
     }
 }
@@ -0,0 +1,531 @@
+//! An HTTP server which regenerates images from a bundle on demand.
+//!
+//! The bundle's `stream.json` is served with artifact locations pointing
+//! back at the server, and each image is served at `/<arch>/<filename>`.
+//! An image is generated the first time it's requested, and kept in a
+//! cache bounded by total size; concurrent requests for an image which is
+//! being generated stream it as it's written rather than generating it again.
+
+use crate::bundle::{Bundle, Error, Item};
+use crate::riverdelta::ArtifactExt;
+use anyhow::{anyhow, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use coreos_stream_metadata::Stream;
+use lru::LruCache;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use tiny_http::{Header, Method, Request, Response, ResponseBox, StatusCode};
+use tracing::{error, info};
+
+/// Options for [`serve`].
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// Directory containing the dehydrated images.
+    pub bundle: Utf8PathBuf,
+    /// Address to listen on, e.g. `127.0.0.1:8080`.
+    pub listen: String,
+    /// Architectures to serve; all those in the bundle if empty.
+    pub arches: Vec<String>,
+    /// Maximum total size of cached images, in MiB.  The most recently
+    /// generated image is always kept, even if it's larger.
+    pub cache_size: u64,
+    /// Directory for the cache and temporary files; defaults to the
+    /// system temporary directory.
+    pub cachedir: Option<Utf8PathBuf>,
+    /// Number of requests to handle at once; each download takes one
+    /// until it's finished.
+    pub workers: usize,
+    /// Don't verify the SHA-256 of generated images.
+    pub skip_validate: bool,
+    /// URL at which clients reach the server, used for the artifact
+    /// locations in `stream.json`.  If not set, `http://` and the `Host`
+    /// of the request are used.
+    pub base_url: Option<String>,
+    /// The bundle for a previous release, needed if the qemu image was
+    /// dehydrated as a delta from it.
+    pub base: Option<Utf8PathBuf>,
+}
+
+/// The generated images, evicted least recently used first once their
+/// total size exceeds the maximum.
+struct Cache {
+    dir: Utf8PathBuf,
+    entries: LruCache<String, u64>,
+    size: u64,
+    max_size: u64,
+}
+
+impl Cache {
+    fn new(dir: Utf8PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            entries: LruCache::unbounded(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Open a cached image, marking it as recently used.
+    fn open(&mut self, key: &str) -> Result<Option<File>> {
+        if self.entries.get(&key.to_string()).is_none() {
+            return Ok(None);
+        }
+        Ok(Some(File::open(self.dir.join(key))?))
+    }
+
+    /// Add an image of `size` bytes which has been written to `key`, and
+    /// evict others as needed.  Files which are being served remain
+    /// readable after they're evicted.
+    fn insert(&mut self, key: &str, size: u64) -> Result<()> {
+        if let Some(old) = self.entries.put(key.to_string(), size) {
+            self.size -= old;
+        }
+        self.size += size;
+        while self.size > self.max_size && self.entries.len() > 1 {
+            let (k, size) = self.entries.pop_lru().unwrap();
+            std::fs::remove_file(self.dir.join(&k))?;
+            self.size -= size;
+            info!("Evicted from cache: {}", k);
+        }
+        Ok(())
+    }
+}
+
+/// An image being generated, shared by all requests for it.
+struct Generation {
+    path: Utf8PathBuf,
+    state: Mutex<GenerationState>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct GenerationState {
+    written: u64,
+    /// Set when generation finishes; the error is formatted, since
+    /// it's reported to every reader.
+    done: Option<std::result::Result<(), String>>,
+}
+
+impl Generation {
+    /// Create the file at `path`, and return a writer for it.
+    fn new(path: Utf8PathBuf) -> Result<(Arc<Self>, GenerationWriter)> {
+        let f = File::create(&path).with_context(|| anyhow!("Creating {}", path))?;
+        let g = Arc::new(Self {
+            path,
+            state: Default::default(),
+            cond: Condvar::new(),
+        });
+        let w = GenerationWriter {
+            f,
+            g: Arc::clone(&g),
+        };
+        Ok((g, w))
+    }
+
+    /// Read the image from the start, waiting for data as it's written.
+    fn reader(self: &Arc<Self>) -> Result<GenerationReader> {
+        Ok(GenerationReader {
+            f: File::open(&self.path)?,
+            g: Arc::clone(self),
+            pos: 0,
+        })
+    }
+
+    fn finish(&self, r: std::result::Result<(), String>) {
+        self.state.lock().unwrap().done = Some(r);
+        self.cond.notify_all();
+    }
+}
+
+/// Writes the file for a [`Generation`], waking readers.
+struct GenerationWriter {
+    f: File,
+    g: Arc<Generation>,
+}
+
+impl Write for GenerationWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.f.write(buf)?;
+        self.g.state.lock().unwrap().written += n as u64;
+        self.g.cond.notify_all();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.f.flush()
+    }
+}
+
+/// Reads the file for a [`Generation`], which may still be being written.
+struct GenerationReader {
+    f: File,
+    g: Arc<Generation>,
+    pos: u64,
+}
+
+impl Read for GenerationReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = {
+            let mut state = self.g.state.lock().unwrap();
+            loop {
+                if state.written > self.pos {
+                    break state.written - self.pos;
+                }
+                match state.done.as_ref() {
+                    Some(Ok(())) => return Ok(0),
+                    // The image is truncated.
+                    Some(Err(e)) => {
+                        let kind = std::io::ErrorKind::UnexpectedEof;
+                        return Err(std::io::Error::new(kind, e.clone()));
+                    }
+                    None => state = self.g.cond.wait(state).unwrap(),
+                }
+            }
+        };
+        let n = buf.len().min(available.try_into().unwrap_or(usize::MAX));
+        let n = self.f.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+struct Server {
+    /// The bundle directory.
+    dir: Utf8PathBuf,
+    base_url: Option<String>,
+    listen: String,
+    bundles: BTreeMap<String, Bundle>,
+    /// Holds the cache, and temporary files for generating images.
+    workdir: tempfile::TempDir,
+    cache: Mutex<Cache>,
+    /// Images being generated, keyed like the cache.  Always locked
+    /// before the cache.
+    generating: Mutex<HashMap<String, Arc<Generation>>>,
+}
+
+type Body = Box<dyn Read + Send>;
+
+impl Server {
+    fn new(opts: &ServeOptions) -> Result<Self> {
+        let arches = if opts.arches.is_empty() {
+            Bundle::arches(&opts.bundle)?
+        } else {
+            opts.arches.clone()
+        };
+        let mut workdir = tempfile::Builder::new();
+        workdir.prefix("rehydrator-serve");
+        let workdir = match opts.cachedir.as_ref() {
+            Some(d) => workdir.tempdir_in(d)?,
+            None => workdir.tempdir()?,
+        };
+        let dir: &Utf8Path = workdir.path().try_into()?;
+        let cachedir = dir.join("cache");
+        let mut bundles = BTreeMap::new();
+        for arch in arches {
+            let mut b = Bundle::open(&opts.bundle, Some(&arch))?;
+            b.set_base(opts.base.clone());
+            b.set_tmpdir(Some(dir.to_owned()));
+            b.set_skip_validate(opts.skip_validate);
+            std::fs::create_dir_all(cachedir.join(&arch))?;
+            bundles.insert(arch, b);
+        }
+        Ok(Self {
+            dir: opts.bundle.clone(),
+            base_url: opts
+                .base_url
+                .as_deref()
+                .map(|u| u.trim_end_matches('/').to_string()),
+            listen: opts.listen.clone(),
+            bundles,
+            cache: Mutex::new(Cache::new(cachedir, opts.cache_size * 1024 * 1024)),
+            workdir,
+            generating: Default::default(),
+        })
+    }
+
+    fn workdir(&self) -> &Utf8Path {
+        self.workdir.path().try_into().unwrap()
+    }
+
+    /// Open the image `arch/name`, generating it if it's not cached.
+    /// Returns the size too, if it's known.
+    fn open(self: &Arc<Self>, arch: &str, name: &str) -> Result<(Body, Option<u64>)> {
+        let key = format!("{}/{}", arch, name);
+        let mut generating = self.generating.lock().unwrap();
+        if let Some(g) = generating.get(&key) {
+            return Ok((Box::new(g.reader()?), None));
+        }
+        if let Some(f) = self.cache.lock().unwrap().open(&key)? {
+            let size = f.metadata()?.len();
+            return Ok((Box::new(f), Some(size)));
+        }
+        let path = self.workdir().join("cache").join(&key);
+        let (g, w) = Generation::new(path)?;
+        generating.insert(key.clone(), Arc::clone(&g));
+        let r = g.reader()?;
+        drop(generating);
+        info!("Generating: {}", key);
+        let server = Arc::clone(self);
+        let (arch, name) = (arch.to_string(), name.to_string());
+        std::thread::spawn(move || server.generate(&arch, &name, &key, g, w));
+        Ok((Box::new(r), None))
+    }
+
+    /// Generate an image, then cache it and wake any readers.
+    fn generate(&self, arch: &str, name: &str, key: &str, g: Arc<Generation>, w: GenerationWriter) {
+        let r = self.write_image(arch, name, w).and_then(|size| {
+            let mut generating = self.generating.lock().unwrap();
+            self.cache.lock().unwrap().insert(key, size)?;
+            generating.remove(key);
+            Ok(())
+        });
+        let r = r.map_err(|e| {
+            error!("Generating {}: {:#}", key, e);
+            self.generating.lock().unwrap().remove(key);
+            let _ = std::fs::remove_file(&g.path);
+            format!("{:#}", e)
+        });
+        g.finish(r);
+    }
+
+    /// Write the image to `w`, returning its size.
+    fn write_image(&self, arch: &str, name: &str, w: GenerationWriter) -> Result<u64> {
+        let w = self.bundles[arch].rehydrate_to(name, w)?;
+        let size = w.g.state.lock().unwrap().written;
+        Ok(size)
+    }
+
+    /// The size of the image `arch/name`, if it's cached or known in
+    /// advance, without generating it.
+    fn size(&self, arch: &str, name: &str) -> Result<Option<u64>> {
+        let key = format!("{}/{}", arch, name);
+        if let Some(f) = self.cache.lock().unwrap().open(&key)? {
+            return Ok(Some(f.metadata()?.len()));
+        }
+        let images = self.bundles[arch].images()?;
+        Ok(images
+            .into_iter()
+            .find(|i| i.filename == name)
+            .and_then(|i| i.size))
+    }
+
+    /// The stream metadata for the served architectures, with artifacts
+    /// pointing at `base_url`.  Artifacts which aren't included are removed,
+    /// as are compressed artifacts without the SHA-256 of their uncompressed
+    /// data and VMDKs written anew, since nothing would match what we serve.
+    fn stream(self: &Arc<Self>, base_url: &str) -> Result<Stream> {
+        let stream_path = self.dir.join(crate::STREAM_FILE);
+        let s = File::open(stream_path).context("Failed to open stream.json")?;
+        let mut s: Stream = serde_json::from_reader(BufReader::new(s))?;
+        s.architectures.retain(|k, _| self.bundles.contains_key(k));
+        for (arch, thisarch) in s.architectures.iter_mut() {
+            let b = &self.bundles[arch];
+            let served: HashSet<&str> =
+                b.rd.all_artifacts()
+                    .into_iter()
+                    .map(|a| a.location.as_str())
+                    .collect();
+            for p in thisarch.artifacts.values_mut() {
+                for format in p.formats.values_mut() {
+                    format.retain(|_, a| {
+                        served.contains(a.location.as_str())
+                            && crate::bundle::can_validate(a)
+                            && b.reproduces(a)
+                    });
+                    for a in format.values_mut() {
+                        let name = crate::uncompressed_name(a.filename()).to_string();
+                        let sig = b.signature_for(a).map(String::from);
+                        if let Some(sha256) = a.uncompressed_sha256.take() {
+                            a.sha256 = sha256;
+                        }
+                        a.uncompressed_sha256 = None;
+                        a.signature = sig.map(|sig| format!("{}/{}/{}", base_url, arch, sig));
+                        a.location = format!("{}/{}/{}", base_url, arch, name);
+                    }
+                }
+                p.formats.retain(|_, f| !f.is_empty());
+            }
+            thisarch.artifacts.retain(|_, p| !p.formats.is_empty());
+        }
+        Ok(s)
+    }
+
+    fn respond(self: &Arc<Self>, req: &Request) -> Result<ResponseBox> {
+        if !matches!(req.method(), Method::Get | Method::Head) {
+            return Ok(Response::empty(405).boxed());
+        }
+        let path = req.url().split('?').next().unwrap();
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let (body, size, content_type): (Body, _, _) = match parts.as_slice() {
+            [name] if *name == crate::STREAM_FILE => {
+                let base_url = match self.base_url.as_ref() {
+                    Some(u) => u.clone(),
+                    None => {
+                        let host = req
+                            .headers()
+                            .iter()
+                            .find(|h| h.field.equiv("Host"))
+                            .map(|h| h.value.as_str())
+                            .unwrap_or_else(|| self.listen.as_str());
+                        format!("http://{}", host)
+                    }
+                };
+                let buf = serde_json::to_vec_pretty(&self.stream(&base_url)?)?;
+                let size = buf.len() as u64;
+                (
+                    Box::new(std::io::Cursor::new(buf)),
+                    Some(size),
+                    "application/json",
+                )
+            }
+            [arch, name] if self.bundles.contains_key(*arch) => {
+                let b = &self.bundles[*arch];
+                match b.find(name) {
+                    Some(Item::Signature(_)) => {
+                        let f = File::open(b.srcdir.join(name))?;
+                        let size = f.metadata()?.len();
+                        (Box::new(f), Some(size), "application/pgp-signature")
+                    }
+                    // Headers only; the body isn't sent.
+                    Some(_) if *req.method() == Method::Head => {
+                        let size = self.size(arch, name)?;
+                        (Box::new(std::io::empty()), size, "application/octet-stream")
+                    }
+                    Some(_) => {
+                        let (body, size) = self.open(arch, name)?;
+                        (body, size, "application/octet-stream")
+                    }
+                    None => return Ok(not_found()),
+                }
+            }
+            _ => return Ok(not_found()),
+        };
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+        let size = size.map(|s| s.try_into()).transpose()?;
+        // Send the Content-Length whenever it's known, rather than only
+        // for small responses, so that HEAD requests get it.
+        Ok(
+            Response::new(StatusCode(200), vec![header], body, size, None)
+                .with_chunked_threshold(usize::MAX),
+        )
+    }
+
+    fn handle(self: &Arc<Self>, req: Request) {
+        info!("{} {}", req.method(), req.url());
+        let resp = self.respond(&req).unwrap_or_else(|e| {
+            error!("{} {}: {:#}", req.method(), req.url(), e);
+            Response::from_string(format!("{:#}\n", e))
+                .with_status_code(500)
+                .boxed()
+        });
+        if let Err(e) = req.respond(resp) {
+            error!("Sending response: {}", e);
+        }
+    }
+}
+
+fn not_found() -> ResponseBox {
+    Response::from_string("Not found\n")
+        .with_status_code(404)
+        .boxed()
+}
+
+/// Serve the bundle until killed, handling requests with `opts.workers`
+/// threads.
+pub fn serve(opts: &ServeOptions) -> Result<(), Error> {
+    serve_impl(opts).map_err(Error::from_anyhow)
+}
+
+fn serve_impl(opts: &ServeOptions) -> Result<()> {
+    if opts.workers == 0 {
+        return Err(anyhow!("Need at least 1 worker"));
+    }
+    let server = Arc::new(Server::new(opts)?);
+    let http = tiny_http::Server::http(opts.listen.as_str())
+        .map_err(|e| anyhow!("Listening on {}: {}", opts.listen, e))?;
+    let http = Arc::new(http);
+    let arches: Vec<&str> = server.bundles.keys().map(|s| s.as_str()).collect();
+    info!(
+        "Serving {} on http://{}/",
+        arches.join(" "),
+        http.server_addr()
+    );
+    let workers: Vec<_> = (0..opts.workers)
+        .map(|_| {
+            let (server, http) = (Arc::clone(&server), Arc::clone(&http));
+            std::thread::spawn(move || {
+                for req in http.incoming_requests() {
+                    server.handle(req);
+                }
+            })
+        })
+        .collect();
+    for w in workers {
+        w.join().map_err(|_| anyhow!("Worker thread panicked"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let mut c = Cache::new(dir.to_owned(), 10);
+        for (k, size) in [("a", 4), ("b", 4)].iter() {
+            std::fs::write(dir.join(k), vec![0u8; *size])?;
+            c.insert(k, *size as u64)?;
+        }
+        // Using `a` makes `b` the least recently used.
+        assert!(c.open("a")?.is_some());
+        std::fs::write(dir.join("c"), vec![0u8; 4])?;
+        c.insert("c", 4)?;
+        assert!(c.open("b")?.is_none());
+        assert!(!dir.join("b").exists());
+        assert!(c.open("a")?.is_some());
+        assert_eq!(c.size, 8);
+        // An image larger than the cache is kept until the next one.
+        std::fs::write(dir.join("d"), vec![0u8; 20])?;
+        c.insert("d", 20)?;
+        assert_eq!(c.entries.len(), 1);
+        assert!(c.open("d")?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_generation() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let (g, mut w) = Generation::new(dir.join("img"))?;
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut r = g.reader()?;
+                Ok(std::thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    r.read_to_end(&mut buf).map(|_| buf)
+                }))
+            })
+            .collect::<Result<_>>()?;
+        let data = b"hello world".repeat(1000);
+        for chunk in data.chunks(1000) {
+            w.write_all(chunk)?;
+        }
+        g.finish(Ok(()));
+        for r in readers {
+            assert!(r.join().unwrap()? == data);
+        }
+
+        let (g, mut w) = Generation::new(dir.join("failed"))?;
+        w.write_all(b"partial")?;
+        g.finish(Err("oops".to_string()));
+        let mut buf = Vec::new();
+        assert!(g.reader()?.read_to_end(&mut buf).is_err());
+        Ok(())
+    }
+}
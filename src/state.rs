@@ -0,0 +1,191 @@
+//! Tracking of the outputs of `build dehydrate`, so that an interrupted or
+//! repeated run can reuse the outputs whose inputs haven't changed.
+
+use crate::rsync;
+use crate::utils;
+use crate::vmdk;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use fn_error_context::context;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::Mutex;
+
+/// Name of the file in a bundle recording how each output was generated
+pub(crate) const STATE_FILE: &str = "dehydrate-state.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct StateData {
+    /// Keyed by output filename.
+    outputs: BTreeMap<String, OutputState>,
+    /// Size of the uncompressed qemu image.
+    qemu_size: Option<u64>,
+    /// The options of each VMDK, keyed by the delta of its grains.
+    vmdk_options: BTreeMap<String, vmdk::Options>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct OutputState {
+    /// Identifies what the output was generated from; the SHA-256 of the
+    /// input artifacts, and anything else affecting the output.
+    inputs: Vec<String>,
+    /// The versions of the formats it was written with (see
+    /// [`format_versions`]).
+    formats: Vec<String>,
+    /// SHA-256 of the output.
+    sha256: String,
+}
+
+/// The versions of the formats we write: deltas, VMDK layouts, and the
+/// zlib fingerprint which layouts depend on.  Outputs written with other
+/// versions are regenerated.
+pub(crate) fn format_versions() -> Result<Vec<String>> {
+    Ok(vec![
+        format!("rdelta={}", rsync::FORMAT_VERSION),
+        format!("vmdk-layout={}", vmdk::LAYOUT_VERSION),
+        format!("zlib={}", vmdk::zlib_fingerprint()?),
+    ])
+}
+
+/// The outputs in a directory, which is updated as they're generated.
+#[derive(Debug)]
+pub(crate) struct State {
+    dir: Utf8PathBuf,
+    /// The current [`format_versions`].
+    formats: Vec<String>,
+    data: Mutex<StateData>,
+}
+
+impl State {
+    /// Load the state for `dir`; it's empty if there's no state file.
+    pub(crate) fn load(dir: &Utf8Path) -> Result<Self> {
+        Self::load_with_formats(dir, format_versions()?)
+    }
+
+    #[context("Loading dehydration state")]
+    fn load_with_formats(dir: &Utf8Path, formats: Vec<String>) -> Result<Self> {
+        let p = &dir.join(STATE_FILE);
+        let data = if p.exists() {
+            let f = BufReader::new(File::open(p)?);
+            serde_json::from_reader(f).with_context(|| format!("Parsing {}", p))?
+        } else {
+            StateData::default()
+        };
+        Ok(Self {
+            dir: dir.to_owned(),
+            formats,
+            data: Mutex::new(data),
+        })
+    }
+
+    /// Whether `name` was generated from `inputs` with the current format
+    /// versions, and is unmodified.
+    pub(crate) fn is_current(&self, name: &str, inputs: &[String]) -> Result<bool> {
+        let o = match self.data.lock().unwrap().outputs.get(name) {
+            Some(o) if o.inputs == inputs && o.formats == self.formats => o.clone(),
+            _ => return Ok(false),
+        };
+        let p = &self.dir.join(name);
+        Ok(p.exists() && utils::sha256_file(p)? == o.sha256)
+    }
+
+    /// Record that `name` has been generated from `inputs`.
+    pub(crate) fn record(&self, name: &str, inputs: &[String]) -> Result<()> {
+        let sha256 = utils::sha256_file(self.dir.join(name))?;
+        let mut data = self.data.lock().unwrap();
+        let o = OutputState {
+            inputs: inputs.to_vec(),
+            formats: self.formats.clone(),
+            sha256,
+        };
+        data.outputs.insert(name.to_string(), o);
+        self.save(&data)
+    }
+
+    pub(crate) fn qemu_size(&self) -> Option<u64> {
+        self.data.lock().unwrap().qemu_size
+    }
+
+    pub(crate) fn set_qemu_size(&self, size: u64) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.qemu_size = Some(size);
+        self.save(&data)
+    }
+
+    /// The options of the VMDK whose grains are the delta `name`.
+    pub(crate) fn vmdk_options(&self, name: &str) -> Option<vmdk::Options> {
+        self.data.lock().unwrap().vmdk_options.get(name).cloned()
+    }
+
+    pub(crate) fn set_vmdk_options(&self, name: &str, opts: vmdk::Options) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.vmdk_options.insert(name.to_string(), opts);
+        self.save(&data)
+    }
+
+    /// Forget outputs other than `names`.
+    pub(crate) fn retain(&self, names: &BTreeSet<String>) -> Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.outputs.retain(|k, _| names.contains(k));
+        data.vmdk_options.retain(|k, _| names.contains(k));
+        self.save(&data)
+    }
+
+    /// Write the state file, replacing it atomically.
+    fn save(&self, data: &StateData) -> Result<()> {
+        let mut tmpf = tempfile::NamedTempFile::new_in(&self.dir)?;
+        {
+            let mut w = BufWriter::new(tmpf.as_file_mut());
+            serde_json::to_writer_pretty(&mut w, data)?;
+            w.flush()?;
+        }
+        tmpf.persist(self.dir.join(STATE_FILE))
+            .map_err(|e| e.error)
+            .context("Writing dehydration state")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_state() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let inputs = &["abc".to_string(), "def".to_string()];
+        let s = State::load(dir)?;
+        std::fs::write(dir.join("foo.rdelta"), b"delta")?;
+        assert!(!s.is_current("foo.rdelta", inputs)?);
+        s.record("foo.rdelta", inputs)?;
+        s.set_qemu_size(42)?;
+        s.set_vmdk_options("foo.rdelta", vmdk::Options::default())?;
+        assert!(s.is_current("foo.rdelta", inputs)?);
+
+        let s = State::load(dir)?;
+        assert!(s.is_current("foo.rdelta", inputs)?);
+        assert!(!s.is_current("foo.rdelta", &inputs[..1])?);
+        assert!(!s.is_current("bar.rdelta", inputs)?);
+        // Bumping a format version regenerates everything.
+        let formats = format_versions()?;
+        let mut bumped = formats.clone();
+        bumped[0] = format!("rdelta={}", rsync::FORMAT_VERSION + 1);
+        assert!(State::load_with_formats(dir, formats)?.is_current("foo.rdelta", inputs)?);
+        assert!(!State::load_with_formats(dir, bumped)?.is_current("foo.rdelta", inputs)?);
+        assert_eq!(s.qemu_size(), Some(42));
+        assert_eq!(s.vmdk_options("foo.rdelta"), Some(vmdk::Options::default()));
+        // A modified output is regenerated.
+        std::fs::write(dir.join("foo.rdelta"), b"truncated")?;
+        assert!(!s.is_current("foo.rdelta", inputs)?);
+        s.retain(&BTreeSet::new())?;
+        let s = State::load(dir)?;
+        assert!(!s.is_current("foo.rdelta", inputs)?);
+        assert_eq!(s.vmdk_options("foo.rdelta"), None);
+        Ok(())
+    }
+}
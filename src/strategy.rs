@@ -0,0 +1,174 @@
+//! The table mapping each platform (and optionally format) in a stream
+//! to how its artifacts are dehydrated.
+
+use crate::Error;
+use anyhow::{anyhow, Context, Result};
+use camino::Utf8Path;
+use fn_error_context::context;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use strum_macros::{Display, EnumString};
+
+/// How an artifact is dehydrated and regenerated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, Display)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum Strategy {
+    /// A disk image stored as an rsync delta from the qemu image.
+    RsyncFromQemu,
+    /// A streamOptimized VMDK, stored as an rsync delta from the qemu image
+    /// of its uncompressed grains, along with its layout: everything else
+    /// in the file, and any grains which don't compress the same again.
+    Vmdk,
+    /// An OVA containing any number of streamOptimized VMDKs, each stored
+    /// as a delta of its grains like [`Strategy::Vmdk`], along with a
+    /// single layout of the whole OVA.
+    Ova,
+    /// Included as is; only for uncompressed artifacts.
+    Copy,
+    /// An ISO stored as an rsync delta from the PXE rootfs.
+    IsoFromRootfs,
+    /// Not included.
+    Skip,
+}
+
+/// Built-in strategies, keyed by `platform` or `platform/format`.
+const DEFAULT_STRATEGIES: &[(&str, Strategy)] = &[
+    // Most of these are just just qcow2 images.
+    // gcp is a tarball with a sparse disk image inside it, but for rsync that's
+    // not really different than a qcow2.
+    // A few others are raw disk images (azure, vultr).
+    ("aliyun", Strategy::RsyncFromQemu),
+    ("azure", Strategy::RsyncFromQemu),
+    ("digitalocean", Strategy::RsyncFromQemu),
+    ("exoscale", Strategy::RsyncFromQemu),
+    ("gcp", Strategy::RsyncFromQemu),
+    ("ibmcloud", Strategy::RsyncFromQemu),
+    ("openstack", Strategy::RsyncFromQemu),
+    ("vultr", Strategy::RsyncFromQemu),
+    // This is the VMDK, not the AMIs.
+    ("aws", Strategy::Vmdk),
+    ("vmware", Strategy::Ova),
+    // The Live ISO and PXE data; we don't handle the raw metal images yet.
+    ("metal", Strategy::Skip),
+    ("metal/iso", Strategy::IsoFromRootfs),
+    ("metal/pxe", Strategy::Copy),
+];
+
+/// An assignment of a strategy to a `platform` or `platform/format`,
+/// parsed from e.g. `nutanix=rsync-from-qemu`.
+#[derive(Debug, Clone)]
+pub struct StrategyOverride {
+    key: String,
+    strategy: Strategy,
+}
+
+impl std::str::FromStr for StrategyOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, '=');
+        let key = parts.next().unwrap();
+        let strategy = parts
+            .next()
+            .ok_or_else(|| anyhow!("Expected PLATFORM[/FORMAT]=STRATEGY, found: {}", s))?;
+        Ok(Self {
+            key: key.to_string(),
+            strategy: strategy
+                .parse()
+                .map_err(|_| anyhow!("Unknown delta strategy: {}", strategy))?,
+        })
+    }
+}
+
+/// Strategies keyed by `platform` or `platform/format`; an entry for a
+/// format takes precedence over one for its platform.  The qemu image is
+/// always the base image, and is not part of the table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct StrategyTable(BTreeMap<String, Strategy>);
+
+impl Default for StrategyTable {
+    fn default() -> Self {
+        Self(
+            DEFAULT_STRATEGIES
+                .iter()
+                .map(|&(k, v)| (k.to_string(), v))
+                .collect(),
+        )
+    }
+}
+
+impl StrategyTable {
+    /// Read a table from a JSON file, e.g. `{"nutanix": "rsync-from-qemu"}`.
+    pub fn load(p: &Utf8Path) -> Result<Self, Error> {
+        Self::read(p).map_err(Error::from_anyhow)
+    }
+
+    #[context("Reading strategy table {}", p)]
+    fn read(p: &Utf8Path) -> Result<Self> {
+        let f = BufReader::new(File::open(p)?);
+        serde_json::from_reader(f).context("Parsing")
+    }
+
+    /// Add (or replace) entries from `other`.
+    pub fn merge(&mut self, other: StrategyTable) {
+        self.0.extend(other.0);
+    }
+
+    /// Add (or replace) a single entry.
+    pub fn set(&mut self, o: &StrategyOverride) {
+        self.0.insert(o.key.clone(), o.strategy);
+    }
+
+    /// Find the strategy for a platform and format.
+    pub(crate) fn lookup(&self, platform: &str, format: &str) -> Option<Strategy> {
+        self.0
+            .get(&format!("{}/{}", platform, format))
+            .or_else(|| self.0.get(platform))
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategy_table() -> Result<()> {
+        let mut t = StrategyTable::default();
+        assert_eq!(
+            t.lookup("openstack", "qcow2.xz"),
+            Some(Strategy::RsyncFromQemu)
+        );
+        assert_eq!(t.lookup("metal", "iso"), Some(Strategy::IsoFromRootfs));
+        assert_eq!(t.lookup("metal", "raw.xz"), Some(Strategy::Skip));
+        assert_eq!(t.lookup("nutanix", "qcow2"), None);
+
+        t.set(&"nutanix=rsync-from-qemu".parse()?);
+        let o: StrategyTable = serde_json::from_str(r#"{"metal/raw.xz": "rsync-from-qemu"}"#)?;
+        t.merge(o);
+        assert_eq!(t.lookup("nutanix", "qcow2"), Some(Strategy::RsyncFromQemu));
+        assert_eq!(t.lookup("metal", "raw.xz"), Some(Strategy::RsyncFromQemu));
+        assert_eq!(t.lookup("metal", "iso"), Some(Strategy::IsoFromRootfs));
+
+        assert_eq!(Strategy::IsoFromRootfs.to_string(), "iso-from-rootfs");
+        // The names are the same as in JSON.
+        for &s in &[
+            Strategy::RsyncFromQemu,
+            Strategy::Vmdk,
+            Strategy::Ova,
+            Strategy::Copy,
+            Strategy::IsoFromRootfs,
+            Strategy::Skip,
+        ] {
+            assert_eq!(serde_json::to_value(s)?, s.to_string());
+            assert_eq!(s.to_string().parse::<Strategy>()?, s);
+        }
+        assert!("nutanix".parse::<StrategyOverride>().is_err());
+        assert!("nutanix=bsdiff".parse::<StrategyOverride>().is_err());
+        Ok(())
+    }
+}
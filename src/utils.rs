@@ -1,16 +1,131 @@
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
 use camino::Utf8Path;
-use std::process::{Command, Stdio};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
 
+/// A SHA-256 checksum didn't match the expected value.
+#[derive(Debug, thiserror::Error)]
+#[error("SHA-256 mismatch for {name} ({len} bytes) - expected: {expected} actual: {actual}")]
+pub struct ChecksumMismatch {
+    /// The file or image which was checked.
+    pub name: String,
+    /// The number of bytes checked.
+    pub len: u64,
+    /// The expected checksum, in hex.
+    pub expected: String,
+    /// The checksum of the data, in hex.
+    pub actual: String,
+}
+
+/// Compute the hex SHA-256 of a file.
 pub(crate) fn sha256_file(p: impl AsRef<Utf8Path>) -> Result<String> {
     let p = p.as_ref();
-    let s = Command::new("sha256sum")
-        .arg(p)
-        .stdout(Stdio::piped())
-        .output()?;
-    if !s.status.success() {
-        return Err(anyhow!("sha256sum failed: {}", s.status));
+    let mut f = BufReader::new(File::open(p).with_context(|| format!("Opening {}", p))?);
+    let mut w = Sha256Writer::new(std::io::sink());
+    std::io::copy(&mut f, &mut w).with_context(|| format!("Reading {}", p))?;
+    Ok(w.finish().1)
+}
+
+/// Verify that a file has the `expected` SHA-256, returning its size.
+pub(crate) fn verify_sha256_file(p: impl AsRef<Utf8Path>, expected: &str) -> Result<u64> {
+    let p = p.as_ref();
+    let mut f = BufReader::new(File::open(p).with_context(|| format!("Opening {}", p))?);
+    let mut w = Sha256Writer::new(std::io::sink());
+    let len = std::io::copy(&mut f, &mut w).with_context(|| format!("Reading {}", p))?;
+    w.verify(p.as_str(), expected)?;
+    Ok(len)
+}
+
+/// A writer which passes data through to an inner writer while
+/// also computing its SHA-256.
+pub(crate) struct Sha256Writer<W: Write> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Sha256Writer<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    /// Create a writer whose checksum also covers `prefix`, e.g. data that
+    /// was previously written to the inner writer.
+    pub(crate) fn with_prefix(inner: W, mut prefix: impl Read) -> Result<Self> {
+        let mut hasher = Sha256Writer::new(std::io::sink());
+        let len = std::io::copy(&mut prefix, &mut hasher)?;
+        Ok(Self {
+            inner,
+            hasher: hasher.hasher,
+            len,
+        })
+    }
+
+    /// Return the inner writer and the hex SHA-256 of all data written.
+    pub(crate) fn finish(self) -> (W, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+
+    /// Finish, and verify that the data written has the `expected` checksum.
+    pub(crate) fn verify(self, name: &str, expected: &str) -> Result<W> {
+        let len = self.len;
+        let (w, actual) = self.finish();
+        if expected != actual {
+            return Err(ChecksumMismatch {
+                name: name.to_string(),
+                len,
+                expected: expected.to_string(),
+                actual,
+            }
+            .into());
+        }
+        Ok(w)
+    }
+}
+
+impl<W: Write> Write for Sha256Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_sha256() -> Result<()> {
+        let mut w = Sha256Writer::new(Vec::new());
+        w.write_all(b"hello ")?;
+        w.write_all(b"world")?;
+        let expected = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        let mut w2 = Sha256Writer::new(std::io::sink());
+        w2.write_all(b"hello world!")?;
+        let e = w2.verify("test", expected).unwrap_err();
+        let e = e.downcast_ref::<ChecksumMismatch>().unwrap();
+        assert_eq!(e.len, 12);
+        assert_eq!(w.verify("test", expected)?, b"hello world");
+
+        let mut tmpf = tempfile::NamedTempFile::new()?;
+        tmpf.write_all(b"hello world")?;
+        let p: &Utf8Path = tmpf.path().try_into()?;
+        assert_eq!(verify_sha256_file(p, expected)?, 11);
+        assert!(verify_sha256_file(p, &"0".repeat(64))
+            .unwrap_err()
+            .is::<ChecksumMismatch>());
+        Ok(())
     }
-    let stdout = std::str::from_utf8(&s.stdout)?;
-    Ok(stdout.split_whitespace().next().unwrap().to_string())
 }
@@ -0,0 +1,1709 @@
+//! Reading and writing streamOptimized VMDKs.
+//!
+//! A streamOptimized VMDK is a header and descriptor, followed by grains
+//! (runs of sectors of the virtual disk, 64KiB by default) which are each
+//! individually deflate-compressed and preceded by a marker, along with the
+//! grain directory and tables which locate them.
+//!
+//! The compressed grains don't delta well, so we split a file containing
+//! VMDKs (a VMDK itself, or an OVA with one or more) into the uncompressed
+//! grains of each, which are stored as deltas from the qemu image, and a
+//! layout file holding everything else.  Regenerating the file compresses
+//! each grain again; qemu-img uses zlib's `compress2()`, and with the same
+//! settings the same version of zlib produces the same data, so we link a
+//! static zlib and record a fingerprint of its output in the layout.  Any
+//! grains for which it doesn't are stored in the layout file as they are.
+//! If the fingerprint doesn't match ours, the file is written anew instead.
+
+use crate::ova::{self, Ova};
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use camino::{Utf8Path, Utf8PathBuf};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use fn_error_context::context;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use tracing::{info, warn};
+
+/// VMDKs are addressed in sectors.
+const SECTOR: u64 = 512;
+/// The magic number at the start of a sparse extent header, `KDMV`.
+const VMDK_MAGIC: u32 = 0x564d_444b;
+/// Newlines are checked for corruption by the header.
+const FLAG_NL_DETECT: u32 = 1;
+/// There's a redundant grain directory.
+const FLAG_RGD: u32 = 1 << 1;
+/// Grains are compressed.
+const FLAG_COMPRESSED: u32 = 1 << 16;
+/// Grains and metadata are preceded by markers.
+const FLAG_MARKERS: u32 = 1 << 17;
+/// Grains are compressed with deflate.
+const COMPRESSION_DEFLATE: u16 = 1;
+/// Sectors of the descriptor in the VMDKs we write.
+const DESC_SIZE: u64 = 20;
+/// The grain directory offset in the header of a VMDK with a footer.
+const GD_AT_END: u64 = u64::MAX;
+/// Size of a grain marker: the LBA of the grain and its compressed size.
+const GRAIN_MARKER_SIZE: u64 = 12;
+/// zlib compression levels to try when finding the one which reproduces
+/// the grains, starting with the default.
+const LEVELS: &[u32] = &[6, 1, 2, 3, 4, 5, 7, 8, 9];
+/// How many grains to try finding the level from, before giving up and
+/// storing the rest as they are.
+const LEVEL_TRIES: usize = 16;
+
+/// Magic bytes at the start of every layout file.
+const LAYOUT_MAGIC: &[u8; 8] = b"VMDKLAY\0";
+/// Current version of the layout file format.
+pub(crate) const LAYOUT_VERSION: u32 = 1;
+
+/// The sparse extent header of a VMDK.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Header {
+    version: u32,
+    flags: u32,
+    /// Size of the disk, in sectors.
+    capacity: u64,
+    /// Size of a grain, in sectors.
+    grain_size: u64,
+    /// Location of the embedded descriptor, in sectors.
+    desc_offset: u64,
+    desc_size: u64,
+    gtes_per_gt: u32,
+    /// Locations of the redundant and primary grain directories, in sectors.
+    rgd_offset: u64,
+    gd_offset: u64,
+    /// Sectors before the first grain.
+    overhead: u64,
+}
+
+impl Header {
+    fn parse(mut buf: &[u8]) -> Result<Self> {
+        let magic = buf.read_u32::<LittleEndian>()?;
+        if magic != VMDK_MAGIC {
+            return Err(anyhow!("Not a VMDK sparse extent (invalid magic)"));
+        }
+        let h = Self {
+            version: buf.read_u32::<LittleEndian>()?,
+            flags: buf.read_u32::<LittleEndian>()?,
+            capacity: buf.read_u64::<LittleEndian>()?,
+            grain_size: buf.read_u64::<LittleEndian>()?,
+            desc_offset: buf.read_u64::<LittleEndian>()?,
+            desc_size: buf.read_u64::<LittleEndian>()?,
+            gtes_per_gt: buf.read_u32::<LittleEndian>()?,
+            rgd_offset: buf.read_u64::<LittleEndian>()?,
+            gd_offset: buf.read_u64::<LittleEndian>()?,
+            overhead: buf.read_u64::<LittleEndian>()?,
+        };
+        let stream_flags = FLAG_COMPRESSED | FLAG_MARKERS;
+        if h.flags & stream_flags != stream_flags {
+            return Err(anyhow!("Not a streamOptimized VMDK (flags {:#x})", h.flags));
+        }
+        if !(1..=3).contains(&h.version) {
+            return Err(anyhow!("Unsupported VMDK version {}", h.version));
+        }
+        if h.grain_size == 0 || h.gtes_per_gt == 0 || h.overhead == 0 {
+            return Err(anyhow!("Invalid VMDK header"));
+        }
+        Ok(h)
+    }
+
+    fn write(&self, mut w: impl Write) -> Result<()> {
+        let mut buf = Vec::with_capacity(SECTOR as usize);
+        buf.write_u32::<LittleEndian>(VMDK_MAGIC)?;
+        buf.write_u32::<LittleEndian>(self.version)?;
+        buf.write_u32::<LittleEndian>(self.flags)?;
+        for &v in &[
+            self.capacity,
+            self.grain_size,
+            self.desc_offset,
+            self.desc_size,
+        ] {
+            buf.write_u64::<LittleEndian>(v)?;
+        }
+        buf.write_u32::<LittleEndian>(self.gtes_per_gt)?;
+        for &v in &[self.rgd_offset, self.gd_offset, self.overhead] {
+            buf.write_u64::<LittleEndian>(v)?;
+        }
+        // Not an unclean shutdown, then the characters for detecting
+        // newline corruption.
+        buf.extend_from_slice(b"\0\n \r\n");
+        buf.write_u16::<LittleEndian>(COMPRESSION_DEFLATE)?;
+        buf.resize(SECTOR as usize, 0);
+        w.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// The number of grain tables.
+    fn gt_count(&self) -> u64 {
+        let grains = ceil_div(self.capacity, self.grain_size);
+        ceil_div(grains, self.gtes_per_gt as u64)
+    }
+}
+
+fn ceil_div(n: u64, d: u64) -> u64 {
+    let q = n / d;
+    if q * d == n {
+        q
+    } else {
+        q + 1
+    }
+}
+
+/// The embedded disk descriptor of a VMDK: `key=value` lines, with the
+/// extents, and comments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Descriptor {
+    fields: BTreeMap<String, String>,
+    /// The file of the first extent.
+    extent: Option<String>,
+}
+
+impl Descriptor {
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut fields = BTreeMap::new();
+        let mut extent = None;
+        for line in text.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            // e.g. `RW 8388608 SPARSE "disk.vmdk"`
+            if ["RW ", "RDONLY ", "NOACCESS "]
+                .iter()
+                .any(|&p| line.starts_with(p))
+            {
+                let mut parts = line.split('"');
+                if let (None, Some(file)) = (&extent, parts.nth(1)) {
+                    extent = Some(file.to_string());
+                }
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+                let v = v.trim();
+                let v = v
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(v);
+                fields.insert(k.trim().to_string(), v.to_string());
+            }
+        }
+        Self { fields, extent }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(|s| s.as_str())
+    }
+
+    /// The file of the first extent, e.g. the VMDK itself.
+    pub(crate) fn extent(&self) -> Option<&str> {
+        self.extent.as_deref()
+    }
+}
+
+/// The subformat of the VMDKs we read.
+const STREAM_OPTIMIZED: &str = "streamOptimized";
+
+/// The options a VMDK is created with, as in its descriptor.  They're
+/// recorded when dehydrating, and rehydrating checks the descriptor in the
+/// layout still has them before regenerating the VMDK with them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Options {
+    /// The disk adapter, e.g. `lsilogic` or `ide`.
+    pub(crate) adapter_type: String,
+    /// The `createType`, e.g. `streamOptimized`.
+    pub(crate) subformat: String,
+    /// The virtual hardware version, e.g. `6`.
+    pub(crate) hw_version: String,
+    /// The content ID, in hex; qemu-img makes up a random one.
+    pub(crate) cid: Option<String>,
+}
+
+impl Default for Options {
+    /// What coreos-assembler uses:
+    /// `adapter_type=lsilogic,subformat=streamOptimized,compat6`.
+    fn default() -> Self {
+        Self {
+            adapter_type: "lsilogic".to_string(),
+            subformat: STREAM_OPTIMIZED.to_string(),
+            hw_version: "6".to_string(),
+            cid: None,
+        }
+    }
+}
+
+impl Options {
+    /// Read the options from a descriptor; missing ones are the defaults.
+    pub(crate) fn from_descriptor(d: &Descriptor) -> Self {
+        let default = Self::default();
+        let get = |k, default: String| d.get(k).map(String::from).unwrap_or(default);
+        Self {
+            adapter_type: get("ddb.adapterType", default.adapter_type),
+            subformat: get("createType", default.subformat),
+            hw_version: get("ddb.virtualHWVersion", default.hw_version),
+            cid: d.get("CID").map(String::from),
+        }
+    }
+}
+
+impl std::fmt::Display for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "adapter_type={},subformat={},hw_version={}",
+            self.adapter_type, self.subformat, self.hw_version
+        )?;
+        if let Some(cid) = self.cid.as_deref() {
+            write!(f, ",cid={}", cid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a grain is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GrainLocation {
+    /// The first sector of the disk in the grain.
+    lba: u64,
+    /// Offset of the grain marker from the start of the VMDK.
+    offset: u64,
+}
+
+/// A streamOptimized VMDK in a file.
+#[derive(Debug)]
+pub(crate) struct Vmdk {
+    header: Header,
+    pub(crate) descriptor: Descriptor,
+    /// The allocated grains, in the order they're stored.
+    grains: Vec<GrainLocation>,
+}
+
+impl Vmdk {
+    /// Parse the VMDK of `len` bytes at `offset` in `f`, finding its grains
+    /// from the grain directory.
+    fn open(f: &File, offset: u64, len: u64) -> Result<Self> {
+        Self::parse(len, |pos, n| {
+            let mut buf = vec![0u8; n as usize];
+            f.read_exact_at(&mut buf, offset + pos)?;
+            Ok(buf)
+        })
+    }
+
+    /// Parse a VMDK of `len` bytes, reading `n` bytes of it at `pos` with
+    /// `read_at`.  Only its metadata is read, not the grains.
+    fn parse(len: u64, read_at: impl Fn(u64, u64) -> Result<Vec<u8>>) -> Result<Self> {
+        let read = |pos: u64, n: u64| -> Result<Vec<u8>> {
+            if pos.checked_add(n).filter(|&end| end <= len).is_none() {
+                return Err(anyhow!("Invalid VMDK: offset {} is past the end", pos));
+            }
+            read_at(pos, n)
+        };
+        let mut header = Header::parse(&read(0, SECTOR)?)?;
+        let desc = read(
+            header.desc_offset.saturating_mul(SECTOR),
+            header.desc_size.saturating_mul(SECTOR),
+        )?;
+        let desc = desc.split(|&c| c == 0).next().unwrap_or_default();
+        let descriptor = Descriptor::parse(&String::from_utf8_lossy(desc));
+        // A VMDK written as a stream has its grain directory at the end,
+        // found from the footer: a copy of the header before the
+        // end-of-stream marker.
+        let mut gd_offset = header.gd_offset;
+        if gd_offset == GD_AT_END {
+            let footer = len
+                .checked_sub(2 * SECTOR)
+                .ok_or_else(|| anyhow!("Invalid VMDK: missing footer"))?;
+            gd_offset = Header::parse(&read(footer, SECTOR)?)?.gd_offset;
+        }
+        let mut grains = Vec::new();
+        let gd = read(
+            gd_offset.saturating_mul(SECTOR),
+            header.gt_count().saturating_mul(4),
+        )?;
+        let gt_len = header.gtes_per_gt as u64 * 4;
+        for (i, gt_offset) in gd.chunks(4).enumerate() {
+            let gt_offset = (&gt_offset[..]).read_u32::<LittleEndian>()? as u64;
+            if gt_offset == 0 {
+                continue;
+            }
+            let gt = read(gt_offset * SECTOR, gt_len)?;
+            for (j, sector) in gt.chunks(4).enumerate() {
+                let sector = (&sector[..]).read_u32::<LittleEndian>()? as u64;
+                // 1 is a grain of zeroes
+                if sector > 1 {
+                    let index = (i * header.gtes_per_gt as usize + j) as u64;
+                    grains.push(GrainLocation {
+                        lba: index * header.grain_size,
+                        offset: sector * SECTOR,
+                    });
+                }
+            }
+        }
+        grains.sort_by_key(|g| g.offset);
+        header.gd_offset = gd_offset;
+        Ok(Self {
+            header,
+            descriptor,
+            grains,
+        })
+    }
+
+    /// The options the VMDK was created with.
+    pub(crate) fn options(&self) -> Options {
+        Options::from_descriptor(&self.descriptor)
+    }
+}
+
+/// Everything in a file containing a VMDK but its compressed grains.
+#[derive(Debug, Serialize, Deserialize)]
+struct Layout {
+    /// The zlib compression level which reproduces the grains.
+    level: u32,
+    /// The rest of the file, in order.
+    #[serde(with = "serde_bytes")]
+    meta: Vec<u8>,
+    grains: Vec<Grain>,
+}
+
+/// A compressed grain in the file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Grain {
+    /// Offset of the compressed data in the file.
+    offset: u64,
+    /// Size of the compressed data.
+    csize: u32,
+    /// Size of the uncompressed data.
+    len: u32,
+    /// Whether the compressed data is stored in the layout file, because
+    /// compressing the grain doesn't reproduce it.
+    stored: bool,
+}
+
+/// A fingerprint of the zlib we compress grains with: the SHA-256 of a fixed
+/// buffer compressed at each of [`LEVELS`], in hex.  Another zlib with the
+/// same fingerprint compresses the same way, whatever its version.
+pub(crate) fn zlib_fingerprint() -> Result<String> {
+    let words: &[&[u8]] = &[b"coreos ", b"disk ", b"image ", b"\0\0\0\0", b"\n"];
+    let mut data = Vec::with_capacity(65536);
+    let mut x = 1u32;
+    while data.len() < 65536 {
+        x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        data.extend_from_slice(words[(x >> 16) as usize % words.len()]);
+    }
+    let mut w = crate::utils::Sha256Writer::new(std::io::sink());
+    for &level in LEVELS {
+        w.write_all(&deflate(&data, level)?)?;
+    }
+    // Half the digest is plenty to tell zlibs apart.
+    Ok(w.finish().1[..32].to_string())
+}
+
+/// Compress a grain as qemu-img does.
+fn deflate(data: &[u8], level: u32) -> Result<Vec<u8>> {
+    let mut c = Compress::new(Compression::new(level), true);
+    let mut out = Vec::with_capacity(data.len() + data.len() / 8 + 64);
+    match c.compress_vec(data, &mut out, FlushCompress::Finish)? {
+        Status::StreamEnd => Ok(out),
+        _ => Err(anyhow!("Failed to compress grain")),
+    }
+}
+
+/// The first of [`LEVELS`] at which compressing `data` reproduces
+/// `compressed`, if any.
+fn find_level(data: &[u8], compressed: &[u8]) -> Result<Option<u32>> {
+    for &level in LEVELS {
+        if deflate(data, level)? == compressed {
+            return Ok(Some(level));
+        }
+    }
+    Ok(None)
+}
+
+/// Decompress a grain of at most `max` bytes.
+fn inflate(data: &[u8], max: usize) -> Result<Vec<u8>> {
+    let mut d = Decompress::new(true);
+    let mut out = Vec::with_capacity(max);
+    match d.decompress_vec(data, &mut out, FlushDecompress::Finish)? {
+        Status::StreamEnd if d.total_in() == data.len() as u64 => Ok(out),
+        _ => Err(anyhow!("Invalid compressed grain")),
+    }
+}
+
+/// Write a streamOptimized VMDK anew, laid out as qemu-img does: the grain
+/// directories and tables follow the descriptor, and it ends with an
+/// end-of-stream marker.  The disk has the capacity and grain size of
+/// `template`, and `grains` are its allocated grains, each with the first
+/// sector in it, compressed at zlib `level`.  The descriptor is generated
+/// from `opts`, naming the extent `filename`; without a CID (content ID),
+/// it's derived from the data, so the same disk always gives the same VMDK.
+fn write_vmdk(
+    template: &Header,
+    opts: &Options,
+    filename: &str,
+    level: u32,
+    grains: impl IntoIterator<Item = Result<(u64, Vec<u8>)>>,
+    mut out: impl Write + Seek,
+) -> Result<()> {
+    if opts.subformat != STREAM_OPTIMIZED {
+        return Err(anyhow!("Unsupported VMDK subformat {}", opts.subformat));
+    }
+    let mut header = Header {
+        version: 3,
+        flags: FLAG_NL_DETECT | FLAG_RGD | FLAG_COMPRESSED | FLAG_MARKERS,
+        capacity: template.capacity,
+        grain_size: template.grain_size,
+        desc_offset: 1,
+        desc_size: DESC_SIZE,
+        gtes_per_gt: template.gtes_per_gt,
+        rgd_offset: 0,
+        gd_offset: 0,
+        overhead: 0,
+    };
+    let gt_count = header.gt_count();
+    let gt_sectors = ceil_div(header.gtes_per_gt as u64 * 4, SECTOR);
+    let gd_sectors = ceil_div(gt_count * 4, SECTOR);
+    let tables = gd_sectors + gt_sectors * gt_count;
+    header.rgd_offset = header.desc_offset + header.desc_size;
+    header.gd_offset = header.rgd_offset + tables;
+    header.overhead = ceil_div(header.gd_offset + tables, header.grain_size) * header.grain_size;
+
+    // Write the grains, then go back and fill in everything before them.
+    let mut pos = header.overhead * SECTOR;
+    out.seek(SeekFrom::Start(pos))?;
+    let mut gt = vec![0u32; (gt_count * header.gtes_per_gt as u64) as usize];
+    let mut hasher = crate::utils::Sha256Writer::new(std::io::sink());
+    for grain in grains {
+        let (lba, data) = grain?;
+        let entry = gt
+            .get_mut((lba / header.grain_size) as usize)
+            .filter(|_| lba % header.grain_size == 0)
+            .ok_or_else(|| anyhow!("Invalid grain at sector {}", lba))?;
+        hasher.write_u64::<LittleEndian>(lba)?;
+        hasher.write_all(&data)?;
+        let c = deflate(&data, level)?;
+        let mut grain = Vec::with_capacity(c.len() + SECTOR as usize);
+        grain.write_u64::<LittleEndian>(lba)?;
+        grain.write_u32::<LittleEndian>(c.len() as u32)?;
+        grain.extend(c);
+        grain.resize(
+            ceil_div(grain.len() as u64, SECTOR) as usize * SECTOR as usize,
+            0,
+        );
+        out.write_all(&grain)?;
+        *entry = (pos / SECTOR)
+            .try_into()
+            .context("VMDK is too large for its grain tables")?;
+        pos += grain.len() as u64;
+    }
+
+    // The end-of-stream marker.
+    out.write_all(&[0u8; SECTOR as usize])?;
+
+    out.seek(SeekFrom::Start(0))?;
+    header.write(&mut out)?;
+    let sha256 = hasher.finish().1;
+    let cid = opts.cid.as_deref().unwrap_or(&sha256[..8]);
+    let cid = u32::from_str_radix(cid, 16).with_context(|| anyhow!("Invalid CID {}", cid))?;
+    let heads = if opts.adapter_type == "ide" { 16 } else { 255 };
+    let desc = format!(
+        "# Disk DescriptorFile\n\
+         version=1\n\
+         CID={:08x}\n\
+         parentCID=ffffffff\n\
+         createType=\"{}\"\n\
+         \n\
+         # Extent description\n\
+         RW {} SPARSE \"{}\"\n\
+         \n\
+         # The Disk Data Base\n\
+         #DDB\n\
+         \n\
+         ddb.virtualHWVersion = \"{}\"\n\
+         ddb.geometry.cylinders = \"{}\"\n\
+         ddb.geometry.heads = \"{}\"\n\
+         ddb.geometry.sectors = \"63\"\n\
+         ddb.adapterType = \"{}\"\n",
+        cid,
+        opts.subformat,
+        header.capacity,
+        filename,
+        opts.hw_version,
+        ceil_div(header.capacity, heads * 63).min(65535),
+        heads,
+        opts.adapter_type
+    );
+    let mut desc = desc.into_bytes();
+    if desc.len() as u64 > header.desc_size * SECTOR {
+        return Err(anyhow!("VMDK descriptor is too long"));
+    }
+    desc.resize((header.desc_size * SECTOR) as usize, 0);
+    out.write_all(&desc)?;
+    // Both grain directories, each followed by its grain tables.
+    for &gd_offset in &[header.rgd_offset, header.gd_offset] {
+        let mut tables = Vec::with_capacity((tables * SECTOR) as usize);
+        for i in 0..gt_count {
+            let gt_offset = gd_offset + gd_sectors + i * gt_sectors;
+            tables.write_u32::<LittleEndian>(gt_offset as u32)?;
+        }
+        tables.resize((gd_sectors * SECTOR) as usize, 0);
+        for &e in gt.iter() {
+            tables.write_u32::<LittleEndian>(e)?;
+        }
+        out.write_all(&tables)?;
+    }
+    out.seek(SeekFrom::End(0))?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads a file sequentially, tracking the offset.
+struct Input<R: Read> {
+    r: R,
+    pos: u64,
+}
+
+impl<R: Read> Input<R> {
+    fn read(&mut self, n: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; n as usize];
+        self.r
+            .read_exact(&mut buf)
+            .with_context(|| anyhow!("Reading {} bytes at offset {}", n, self.pos))?;
+        self.pos += n;
+        Ok(buf)
+    }
+
+    /// Read `n` bytes, appending them to `meta`.
+    fn copy(&mut self, n: u64, meta: &mut Vec<u8>) -> Result<()> {
+        meta.extend(self.read(n)?);
+        Ok(())
+    }
+}
+
+/// Split `src`, which contains streamOptimized VMDKs at the given offsets and
+/// of the given lengths, into the uncompressed grains of each, written to the
+/// corresponding writer in `grains`, and a layout file `layout_path` with the
+/// rest, from which [`join`] regenerates it.  Temporary files are created in
+/// `tmpdir`.  Returns the options each VMDK was created with.
+#[context("Splitting VMDK in {}", src)]
+pub(crate) fn split<W: Write>(
+    src: &Utf8Path,
+    disks: &[(u64, u64)],
+    grains: &mut [W],
+    layout_path: &Utf8Path,
+    tmpdir: &Utf8Path,
+) -> Result<Vec<Options>> {
+    assert_eq!(disks.len(), grains.len());
+    let f = &File::open(src).with_context(|| anyhow!("Opening {}", src))?;
+    let mut input = Input {
+        r: BufReader::new(f),
+        pos: 0,
+    };
+    let mut meta = Vec::new();
+
+    // Grains which don't compress the same, to be appended to the layout.
+    let mut stored = BufWriter::new(tempfile::tempfile_in(tmpdir)?);
+    let mut layout = Layout {
+        level: LEVELS[0],
+        meta: Vec::new(),
+        grains: Vec::new(),
+    };
+    let mut level = None;
+    let mut tries = 0;
+    let mut options = Vec::new();
+    let mut disk_grains = Vec::new();
+    for (&(offset, len), out) in disks.iter().zip(grains.iter_mut()) {
+        let vmdk = Vmdk::open(f, offset, len)?;
+        let max = (vmdk.header.grain_size * SECTOR) as usize;
+        for g in vmdk.grains.iter() {
+            let start = offset + g.offset;
+            let n = start
+                .checked_sub(input.pos)
+                .ok_or_else(|| anyhow!("Overlapping grains at offset {}", start))?;
+            input.copy(n, &mut meta)?;
+            let marker = input.read(GRAIN_MARKER_SIZE)?;
+            let lba = (&marker[..]).read_u64::<LittleEndian>()?;
+            let csize = (&marker[8..]).read_u32::<LittleEndian>()?;
+            meta.extend(marker);
+            if lba != g.lba || g.offset + GRAIN_MARKER_SIZE + csize as u64 > len {
+                return Err(anyhow!("Invalid grain marker at offset {}", start));
+            }
+            let offset = input.pos;
+            let compressed = input.read(csize as u64)?;
+            let data =
+                inflate(&compressed, max).with_context(|| anyhow!("At offset {}", offset))?;
+            out.write_all(&data)?;
+            let reproduced = match level {
+                Some(level) => deflate(&data, level)? == compressed,
+                // Find the level from the first grains any level reproduces.
+                // If none of them does, the rest won't either, so just store
+                // them all.
+                None if tries < LEVEL_TRIES => {
+                    tries += 1;
+                    level = find_level(&data, &compressed)?;
+                    if level.is_none() && tries == LEVEL_TRIES {
+                        warn!(
+                            "Compressing the first {} grains with zlib doesn't reproduce them at any level; storing all grains",
+                            tries
+                        );
+                    }
+                    level.is_some()
+                }
+                None => false,
+            };
+            if !reproduced {
+                stored.write_all(&compressed)?;
+            }
+            layout.grains.push(Grain {
+                offset,
+                csize,
+                len: data.len() as u32,
+                stored: !reproduced,
+            });
+        }
+        out.flush()?;
+        options.push(vmdk.options());
+        disk_grains.push(vmdk.grains.len() as u64);
+    }
+    input.r.read_to_end(&mut meta)?;
+    layout.level = level.unwrap_or(LEVELS[0]);
+    layout.meta = meta;
+    let size = layout.meta.len() as u64 + layout.grains.iter().map(|g| g.csize as u64).sum::<u64>();
+
+    let mut stored = stored.into_inner().map_err(|e| e.into_error())?;
+    stored.seek(SeekFrom::Start(0))?;
+    let mut out = BufWriter::new(File::create(layout_path)?);
+    out.write_all(LAYOUT_MAGIC)?;
+    out.write_u32::<LittleEndian>(LAYOUT_VERSION)?;
+    out.write_u64::<LittleEndian>(size)?;
+    let zlib = zlib_fingerprint()?;
+    out.write_u8(zlib.len() as u8)?;
+    out.write_all(zlib.as_bytes())?;
+    out.write_u32::<LittleEndian>(disk_grains.len() as u32)?;
+    for &n in disk_grains.iter() {
+        out.write_u64::<LittleEndian>(n)?;
+    }
+    let mut out = zstd::Encoder::new(out, 10)?;
+    bincode::serialize_into(&mut out, &layout)?;
+    std::io::copy(&mut BufReader::new(stored), &mut out)?;
+    out.finish()?.flush()?;
+    let n_stored = layout.grains.iter().filter(|g| g.stored).count();
+    info!(
+        "Split {} VMDK(s): {} grains, {} stored as is",
+        disks.len(),
+        layout.grains.len(),
+        n_stored
+    );
+    Ok(options)
+}
+
+/// The header of a layout file.
+#[derive(Debug)]
+struct LayoutHeader {
+    /// Size of the file it describes.
+    size: u64,
+    /// The number of grains of each VMDK in the file, in order.
+    disk_grains: Vec<u64>,
+    /// The fingerprint of the zlib which reproduces the grains (see
+    /// [`zlib_fingerprint`]).
+    zlib: String,
+}
+
+impl LayoutHeader {
+    fn read(mut r: impl Read) -> Result<Self> {
+        let mut magic = [0u8; LAYOUT_MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != LAYOUT_MAGIC {
+            return Err(anyhow!("Not a VMDK layout file (invalid magic)"));
+        }
+        let version = r.read_u32::<LittleEndian>()?;
+        if version != LAYOUT_VERSION {
+            return Err(anyhow!(
+                "Unsupported VMDK layout version {} (expected {})",
+                version,
+                LAYOUT_VERSION
+            ));
+        }
+        let size = r.read_u64::<LittleEndian>()?;
+        let mut zlib = vec![0u8; r.read_u8()? as usize];
+        r.read_exact(&mut zlib)?;
+        let zlib = String::from_utf8(zlib).context("Invalid zlib fingerprint")?;
+        let n = r.read_u32::<LittleEndian>()?;
+        let disk_grains = (0..n)
+            .map(|_| r.read_u64::<LittleEndian>())
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            size,
+            disk_grains,
+            zlib,
+        })
+    }
+}
+
+fn read_layout_header(layout_path: &Utf8Path) -> Result<LayoutHeader> {
+    let f = File::open(layout_path)?;
+    LayoutHeader::read(BufReader::new(f))
+}
+
+/// The size of the file described by a layout file.
+#[context("Reading VMDK layout {}", layout_path)]
+pub(crate) fn layout_size(layout_path: &Utf8Path) -> Result<u64> {
+    Ok(read_layout_header(layout_path)?.size)
+}
+
+/// The number of VMDKs in the file described by a layout file.
+#[context("Reading VMDK layout {}", layout_path)]
+pub(crate) fn layout_disks(layout_path: &Utf8Path) -> Result<usize> {
+    Ok(read_layout_header(layout_path)?.disk_grains.len())
+}
+
+/// The names of the VMDKs in the OVA described by a layout file, in order,
+/// or `None` for a VMDK.
+#[context("Reading VMDK layout {}", layout_path)]
+pub(crate) fn layout_disk_names(layout_path: &Utf8Path) -> Result<Vec<Option<Utf8PathBuf>>> {
+    let disks = read_layout_header(layout_path)?.disk_grains.len();
+    let (header, layout, _) = read_layout(layout_path, disks)?;
+    Ok(match Skeleton::new(&layout, header.size).ova()? {
+        Some(ova) => ova.disks().map(|e| Some(e.name.clone())).collect(),
+        None => vec![None],
+    })
+}
+
+/// Whether our zlib compresses like the one the file described by a layout
+/// file was split with, so [`join`] can reproduce it.  Otherwise, it has to
+/// be written anew with [`write`].
+#[context("Reading VMDK layout {}", layout_path)]
+pub(crate) fn reproducible(layout_path: &Utf8Path) -> Result<bool> {
+    Ok(read_layout_header(layout_path)?.zlib == zlib_fingerprint()?)
+}
+
+/// Read a layout file for `disks` VMDKs, returning its header, the layout,
+/// and a reader for the grains stored in it.
+fn read_layout(layout_path: &Utf8Path, disks: usize) -> Result<(LayoutHeader, Layout, impl Read)> {
+    let mut r = BufReader::new(File::open(layout_path)?);
+    let header = LayoutHeader::read(&mut r)?;
+    if disks != header.disk_grains.len() {
+        return Err(anyhow!(
+            "Layout has {} VMDK(s), but got grains for {}",
+            header.disk_grains.len(),
+            disks
+        ));
+    }
+    let mut r = zstd::Decoder::with_buffer(r)?;
+    let layout: Layout = bincode::deserialize_from(&mut r)?;
+    if header.disk_grains.iter().sum::<u64>() != layout.grains.len() as u64 {
+        return Err(anyhow!("Invalid VMDK layout grain counts"));
+    }
+    Ok((header, layout, r))
+}
+
+/// Regenerate the file described by the layout file `layout_path` from the
+/// uncompressed `grains` of each VMDK in it, writing it to `out`.  Each VMDK
+/// must have been created with the corresponding `options`.
+#[context("Regenerating from VMDK layout {}", layout_path)]
+pub(crate) fn join<R: Read>(
+    layout_path: &Utf8Path,
+    options: &[Options],
+    mut grains: Vec<R>,
+    mut out: impl Write,
+) -> Result<()> {
+    let (header, layout, mut r) = read_layout(layout_path, grains.len())?;
+    let ours = zlib_fingerprint()?;
+    if header.zlib != ours {
+        return Err(anyhow!(
+            "Layout was made with a zlib which compresses differently (fingerprint {}, ours is {})",
+            header.zlib,
+            ours
+        ));
+    }
+    let skeleton = &Skeleton::new(&layout, header.size);
+    let ova = skeleton.ova()?;
+    let vmdks = skeleton.vmdks(ova.as_ref(), options)?;
+    if let Some(ova) = ova.as_ref() {
+        // The regenerated members are the same size as the originals.
+        let sizes = ova
+            .entries
+            .iter()
+            .map(|e| (e.name.to_string(), e.size))
+            .collect();
+        for e in ova.descriptors() {
+            check_ovf(e, skeleton.text(e)?, &sizes, &capacities(&vmdks))?;
+        }
+    }
+    // Which VMDK each grain is from.
+    let mut disks = Vec::with_capacity(layout.grains.len());
+    for (i, &n) in header.disk_grains.iter().enumerate() {
+        disks.resize(disks.len() + n as usize, i);
+    }
+    let mut meta = layout.meta.as_slice();
+    let mut pos = 0u64;
+    let mut data = Vec::new();
+    for (g, disk) in layout.grains.iter().zip(disks) {
+        let n = g
+            .offset
+            .checked_sub(pos)
+            .filter(|&n| n <= meta.len() as u64)
+            .ok_or_else(|| anyhow!("Invalid grain offset {}", g.offset))?;
+        let (before, rest) = meta.split_at(n as usize);
+        out.write_all(before)?;
+        meta = rest;
+        data.resize(g.len as usize, 0);
+        grains[disk].read_exact(&mut data)?;
+        let compressed = if g.stored {
+            let mut buf = vec![0u8; g.csize as usize];
+            r.read_exact(&mut buf)?;
+            buf
+        } else {
+            deflate(&data, layout.level)?
+        };
+        if compressed.len() != g.csize as usize {
+            return Err(anyhow!(
+                "Compressed grain at offset {} to {} bytes, expected {}",
+                g.offset,
+                compressed.len(),
+                g.csize
+            ));
+        }
+        out.write_all(&compressed)?;
+        pos = g.offset + g.csize as u64;
+    }
+    out.write_all(meta)?;
+    let actual = pos + meta.len() as u64;
+    if actual != header.size {
+        return Err(anyhow!(
+            "Generated {} bytes, expected {}",
+            actual,
+            header.size
+        ));
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The file described by a layout without its compressed grains, which is
+/// everything [`write`] needs to know about it.
+struct Skeleton<'a> {
+    layout: &'a Layout,
+    /// Size of the file.
+    size: u64,
+    /// The total compressed size of the grains before each one, then of
+    /// all of them.
+    before: Vec<u64>,
+}
+
+impl<'a> Skeleton<'a> {
+    fn new(layout: &'a Layout, size: u64) -> Self {
+        let mut before = vec![0];
+        for g in layout.grains.iter() {
+            before.push(before[before.len() - 1] + g.csize as u64);
+        }
+        Self {
+            layout,
+            size,
+            before,
+        }
+    }
+
+    /// The index of the first grain which doesn't end before `pos`.
+    fn grain_at(&self, pos: u64) -> usize {
+        self.layout
+            .grains
+            .partition_point(|g| g.offset + g.csize as u64 <= pos)
+    }
+
+    /// The `n` bytes at `pos` in the file, which mustn't be in a grain.
+    fn get(&self, pos: u64, n: u64) -> Result<&'a [u8]> {
+        let i = self.grain_at(pos);
+        pos.checked_add(n)
+            .filter(|&end| {
+                self.layout
+                    .grains
+                    .get(i)
+                    .filter(|g| g.offset < end)
+                    .is_none()
+            })
+            .ok_or_else(|| anyhow!("Invalid VMDK layout: no metadata at offset {}", pos))?;
+        pos.checked_sub(self.before[i])
+            .and_then(|start| self.layout.meta.get(start as usize..(start + n) as usize))
+            .ok_or_else(|| anyhow!("Invalid VMDK layout: offset {} is past the end", pos))
+    }
+
+    /// The contents of the member `e` of the OVA, which must be text.
+    fn text(&self, e: &ova::Entry) -> Result<&'a str> {
+        std::str::from_utf8(self.get(e.offset, e.size)?)
+            .with_context(|| anyhow!("Invalid text file {}", e.name))
+    }
+
+    /// Read the file, with zeroes instead of the compressed grains.
+    fn reader(&self) -> SkeletonReader<'_, 'a> {
+        SkeletonReader { s: self, pos: 0 }
+    }
+
+    /// The OVA containing the VMDKs, or `None` for a VMDK.
+    fn ova(&self) -> Result<Option<Ova>> {
+        let magic = VMDK_MAGIC.to_le_bytes();
+        if self.get(0, magic.len() as u64)? == magic {
+            return Ok(None);
+        }
+        Ok(Some(Ova::read(self.reader())?))
+    }
+
+    /// Parse the VMDKs in the file, or in `ova` if it's one, returning the
+    /// name of each in the OVA, its offset, and the VMDK.  Each must have
+    /// been created with the corresponding `options`.
+    fn vmdks<'o>(
+        &self,
+        ova: Option<&'o Ova>,
+        options: &[Options],
+    ) -> Result<Vec<(Option<&'o Utf8Path>, u64, Vmdk)>> {
+        let disks: Vec<_> = match ova {
+            Some(ova) => ova
+                .disks()
+                .map(|e| (Some(e.name.as_path()), e.offset, e.size))
+                .collect(),
+            None => vec![(None, 0, self.size)],
+        };
+        if disks.len() != options.len() {
+            return Err(anyhow!(
+                "Invalid VMDK layout: found {} VMDK(s), expected {}",
+                disks.len(),
+                options.len()
+            ));
+        }
+        let mut r = Vec::new();
+        for ((name, offset, len), expected) in disks.into_iter().zip(options) {
+            let vmdk = Vmdk::parse(len, |pos, n| Ok(self.get(offset + pos, n)?.to_vec()))?;
+            let opts = vmdk.options();
+            if &opts != expected {
+                return Err(anyhow!(
+                    "VMDK {} was created with {}, but {} is recorded",
+                    name.map_or("", |n| n.as_str()),
+                    opts,
+                    expected
+                ));
+            }
+            r.push((name, offset, vmdk));
+        }
+        Ok(r)
+    }
+}
+
+/// Reads a [`Skeleton`].
+struct SkeletonReader<'s, 'a> {
+    s: &'s Skeleton<'a>,
+    pos: u64,
+}
+
+impl Read for SkeletonReader<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.pos;
+        let (n, grain) = match self.s.layout.grains.get(self.s.grain_at(pos)) {
+            Some(g) if g.offset <= pos => (g.offset + g.csize as u64 - pos, true),
+            Some(g) => (g.offset - pos, false),
+            None => (self.s.size.saturating_sub(pos), false),
+        };
+        let n = n.min(buf.len() as u64) as usize;
+        let buf = &mut buf[..n];
+        if grain {
+            buf.iter_mut().for_each(|b| *b = 0);
+        } else {
+            let data = self
+                .s
+                .get(pos, buf.len() as u64)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            buf.copy_from_slice(data);
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+/// Write the file described by the layout file `layout_path` anew from the
+/// uncompressed `grains` of each VMDK in it, for when [`join`] can't
+/// reproduce it.  Each VMDK is written as [`write_vmdk`] does, and the other
+/// members of an OVA are kept, but with the sizes of the disks in their tar
+/// headers and their digests in the manifest updated.  Each VMDK is created
+/// with the corresponding `options`.  Temporary files are created in
+/// `tmpdir`.
+#[context("Writing anew from VMDK layout {}", layout_path)]
+pub(crate) fn write<R: Read>(
+    layout_path: &Utf8Path,
+    options: &[Options],
+    mut grains: Vec<R>,
+    tmpdir: &Utf8Path,
+    mut out: impl Write + Seek,
+) -> Result<()> {
+    let (header, layout, _) = read_layout(layout_path, grains.len())?;
+    let skeleton = &Skeleton::new(&layout, header.size);
+    let ova = skeleton.ova()?;
+    let vmdks = skeleton.vmdks(ova.as_ref(), options)?;
+    let capacities = capacities(&vmdks);
+    let mut layout_grains = layout.grains.iter();
+    let mut written = Vec::new();
+    for (((name, offset, vmdk), opts), r) in vmdks.into_iter().zip(options).zip(grains.iter_mut()) {
+        let filename = vmdk
+            .descriptor
+            .extent()
+            .or_else(|| name.and_then(|n| n.file_name()))
+            .ok_or_else(|| anyhow!("VMDK descriptor has no extent"))?;
+        let max = vmdk.header.grain_size * SECTOR;
+        let data = vmdk
+            .grains
+            .iter()
+            .zip(layout_grains.by_ref())
+            .map(|(loc, g)| {
+                if g.offset != offset + loc.offset + GRAIN_MARKER_SIZE || g.len as u64 > max {
+                    return Err(anyhow!("Invalid VMDK layout grain at offset {}", g.offset));
+                }
+                let mut data = vec![0u8; g.len as usize];
+                r.read_exact(&mut data)?;
+                Ok((loc.lba, data))
+            });
+        if ova.is_none() {
+            write_vmdk(&vmdk.header, opts, filename, layout.level, data, &mut out)?;
+        } else {
+            let mut f = tempfile::tempfile_in(tmpdir)?;
+            write_vmdk(
+                &vmdk.header,
+                opts,
+                filename,
+                layout.level,
+                data,
+                BufWriter::new(&mut f),
+            )?;
+            written.push(f);
+        }
+    }
+    if layout_grains.next().is_some() {
+        return Err(anyhow!("Invalid VMDK layout: grains outside the VMDKs"));
+    }
+    if let Some(ova) = ova.as_ref() {
+        write_ova(skeleton, ova, written, &capacities, &mut out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The capacity of each of `vmdks` in bytes, by name in the OVA.
+fn capacities(vmdks: &[(Option<&Utf8Path>, u64, Vmdk)]) -> BTreeMap<String, u64> {
+    vmdks
+        .iter()
+        .filter_map(|(name, _, v)| {
+            Some((
+                (*name)?.to_string(),
+                v.header.capacity.saturating_mul(SECTOR),
+            ))
+        })
+        .collect()
+}
+
+/// Check the file and disk sizes in the OVF descriptor `e`, whose contents
+/// are `xml`, against the `sizes` of the members of the OVA and the
+/// `capacities` of its VMDKs.
+fn check_ovf(
+    e: &ova::Entry,
+    xml: &str,
+    sizes: &BTreeMap<String, u64>,
+    capacities: &BTreeMap<String, u64>,
+) -> Result<()> {
+    ova::Ovf::parse(xml)
+        .and_then(|ovf| ovf.check(sizes, capacities))
+        .with_context(|| anyhow!("Checking OVF descriptor {}", e.name))
+}
+
+/// Write the OVA `ova` described by `skeleton` to `out`, with its disks
+/// replaced by the VMDKs in `disks`, whose `capacities` are by name.  The
+/// file sizes in the OVF descriptors and the digests in the manifests are
+/// updated to match.
+fn write_ova(
+    skeleton: &Skeleton,
+    ova: &Ova,
+    disks: Vec<File>,
+    capacities: &BTreeMap<String, u64>,
+    mut out: impl Write,
+) -> Result<()> {
+    // The new contents of the members which change, and their sizes.
+    let mut members: BTreeMap<&Utf8Path, (u64, Box<dyn Read>)> = BTreeMap::new();
+    let mut digests = BTreeMap::new();
+    for (e, mut f) in ova.disks().zip(disks) {
+        f.seek(SeekFrom::Start(0))?;
+        let mut w = crate::utils::Sha256Writer::new(std::io::sink());
+        let n = std::io::copy(&mut f, &mut w)?;
+        f.seek(SeekFrom::Start(0))?;
+        digests.insert(e.name.to_string(), w.finish().1);
+        members.insert(&e.name, (n, Box::new(BufReader::new(f))));
+    }
+    let sizes: BTreeMap<_, _> = members
+        .iter()
+        .map(|(name, (n, _))| (name.to_string(), *n))
+        .collect();
+    let mut ovfs = Vec::new();
+    for e in ova.descriptors() {
+        let ovf = ova::update_ovf(skeleton.text(e)?, &sizes)?;
+        let mut w = crate::utils::Sha256Writer::new(std::io::sink());
+        w.write_all(ovf.as_bytes())?;
+        digests.insert(e.name.to_string(), w.finish().1);
+        let data = ovf.clone().into_bytes();
+        members.insert(
+            &e.name,
+            (data.len() as u64, Box::new(std::io::Cursor::new(data))),
+        );
+        ovfs.push((e, ovf));
+    }
+    for e in ova.entries.iter().filter(|e| e.is_manifest()) {
+        let mf = ova::update_manifest(skeleton.text(e)?, &digests)?.into_bytes();
+        for cert in ova
+            .entries
+            .iter()
+            .filter(|e| e.name.extension() == Some("cert"))
+        {
+            warn!("{} doesn't sign the regenerated {}", cert.name, e.name);
+        }
+        members.insert(
+            &e.name,
+            (mf.len() as u64, Box::new(std::io::Cursor::new(mf))),
+        );
+    }
+    let sizes = ova
+        .entries
+        .iter()
+        .map(|e| {
+            let n = members.get(e.name.as_path()).map_or(e.size, |m| m.0);
+            (e.name.to_string(), n)
+        })
+        .collect();
+    for (e, ovf) in ovfs {
+        check_ovf(e, &ovf, &sizes, capacities)?;
+    }
+    let mut pos = 0;
+    for e in ova.entries.iter() {
+        let (n, mut data) = match members.remove(e.name.as_path()) {
+            Some(m) => m,
+            None => continue,
+        };
+        let header = e
+            .offset
+            .checked_sub(ova::BLOCK)
+            .filter(|&h| h >= pos)
+            .ok_or_else(|| anyhow!("Invalid tar header for {}", e.name))?;
+        out.write_all(skeleton.get(pos, header - pos)?)?;
+        let mut h = tar::Header::from_byte_slice(skeleton.get(header, ova::BLOCK)?).clone();
+        h.set_size(n);
+        h.set_cksum();
+        out.write_all(h.as_bytes())?;
+        if std::io::copy(&mut data, &mut out)? != n {
+            return Err(anyhow!("Failed to write {}", e.name));
+        }
+        let padded = ceil_div(n, ova::BLOCK) * ova::BLOCK;
+        out.write_all(&vec![0u8; (padded - n) as usize])?;
+        pos = e.offset + ceil_div(e.size, ova::BLOCK) * ova::BLOCK;
+    }
+    let rest = skeleton
+        .size
+        .checked_sub(pos)
+        .ok_or_else(|| anyhow!("Invalid OVA: truncated"))?;
+    out.write_all(skeleton.get(pos, rest)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Sectors in a grain, as written by qemu-img.
+    const GRAIN_SIZE: u64 = 128;
+    /// Entries in a grain table, as written by qemu-img.
+    const GTES_PER_GT: u32 = 512;
+
+    /// Build a streamOptimized VMDK of the disk image `raw` to split and
+    /// join, laid out like qemu-img's: the grain directories and tables
+    /// follow the descriptor, grains of zeroes aren't stored, and each grain
+    /// is compressed with `compress`.  Unlike [`write_vmdk`], it can compress
+    /// grains differently.
+    fn fixture(
+        raw: &[u8],
+        opts: &Options,
+        compress: impl Fn(&[u8]) -> Result<Vec<u8>>,
+    ) -> Result<Vec<u8>> {
+        assert_eq!(raw.len() as u64 % SECTOR, 0);
+        let grain_bytes = (GRAIN_SIZE * SECTOR) as usize;
+        let capacity = raw.len() as u64 / SECTOR;
+        let gt_count = ceil_div(ceil_div(capacity, GRAIN_SIZE), GTES_PER_GT as u64);
+        let gt_sectors = ceil_div(GTES_PER_GT as u64 * 4, SECTOR);
+        let gd_sectors = ceil_div(gt_count * 4, SECTOR);
+        let tables = gd_sectors + gt_sectors * gt_count;
+        let rgd_offset = 1 + DESC_SIZE;
+        let gd_offset = rgd_offset + tables;
+        let overhead = ceil_div(gd_offset + tables, GRAIN_SIZE) * GRAIN_SIZE;
+
+        let mut grains = Vec::new();
+        let mut gt = vec![0u32; (gt_count * GTES_PER_GT as u64) as usize];
+        for (i, chunk) in raw.chunks(grain_bytes).enumerate() {
+            if chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+            gt[i] = (overhead + grains.len() as u64 / SECTOR) as u32;
+            let c = compress(chunk)?;
+            grains.write_u64::<LittleEndian>((i * grain_bytes) as u64 / SECTOR)?;
+            grains.write_u32::<LittleEndian>(c.len() as u32)?;
+            grains.extend(c);
+            grains.resize(
+                ceil_div(grains.len() as u64, SECTOR) as usize * SECTOR as usize,
+                0,
+            );
+        }
+        // The end-of-stream marker.
+        grains.resize(grains.len() + SECTOR as usize, 0);
+
+        let mut out = Vec::new();
+        out.write_u32::<LittleEndian>(VMDK_MAGIC)?;
+        out.write_u32::<LittleEndian>(3)?;
+        out.write_u32::<LittleEndian>(FLAG_NL_DETECT | FLAG_RGD | FLAG_COMPRESSED | FLAG_MARKERS)?;
+        for &v in &[capacity, GRAIN_SIZE, 1, DESC_SIZE] {
+            out.write_u64::<LittleEndian>(v)?;
+        }
+        out.write_u32::<LittleEndian>(GTES_PER_GT)?;
+        for &v in &[rgd_offset, gd_offset, overhead] {
+            out.write_u64::<LittleEndian>(v)?;
+        }
+        // Not an unclean shutdown, then the characters for detecting
+        // newline corruption.
+        out.extend_from_slice(b"\0\n \r\n");
+        out.write_u16::<LittleEndian>(COMPRESSION_DEFLATE)?;
+        out.resize(SECTOR as usize, 0);
+        out.extend_from_slice(descriptor(opts, capacity).as_bytes());
+        out.resize((rgd_offset * SECTOR) as usize, 0);
+        // Both grain directories, each followed by its grain tables.
+        for &gd_offset in &[rgd_offset, gd_offset] {
+            for i in 0..gt_count {
+                let gt_offset = gd_offset + gd_sectors + i * gt_sectors;
+                out.write_u32::<LittleEndian>(gt_offset as u32)?;
+            }
+            out.resize(((gd_offset + gd_sectors) * SECTOR) as usize, 0);
+            for &e in gt.iter() {
+                out.write_u32::<LittleEndian>(e)?;
+            }
+        }
+        out.resize((overhead * SECTOR) as usize, 0);
+        out.extend(grains);
+        Ok(out)
+    }
+
+    /// The descriptor of a [`fixture`] of `capacity` sectors.
+    fn descriptor(opts: &Options, capacity: u64) -> String {
+        let cid = opts.cid.as_deref().unwrap_or("12345678");
+        format!(
+            "# Disk DescriptorFile\n\
+             version=1\n\
+             CID={}\n\
+             parentCID=ffffffff\n\
+             createType=\"{}\"\n\
+             \n\
+             # Extent description\n\
+             RW {} SPARSE \"disk.vmdk\"\n\
+             \n\
+             # The Disk Data Base\n\
+             #DDB\n\
+             \n\
+             ddb.virtualHWVersion = \"{}\"\n\
+             ddb.adapterType = \"{}\"\n",
+            cid, opts.subformat, capacity, opts.hw_version, opts.adapter_type
+        )
+    }
+
+    /// Build a streamOptimized VMDK of the disk image `raw` laid out as
+    /// VMware writes one as a stream: the grains follow the descriptor, and
+    /// the grain tables and directory follow them, each after a marker.  The
+    /// header has [`GD_AT_END`], and the grain directory is found from the
+    /// footer.  Grains are compressed at zlib's default level.
+    fn stream_fixture(raw: &[u8], opts: &Options) -> Result<Vec<u8>> {
+        assert_eq!(raw.len() as u64 % SECTOR, 0);
+        let grain_bytes = (GRAIN_SIZE * SECTOR) as usize;
+        let capacity = raw.len() as u64 / SECTOR;
+        let gt_count = ceil_div(ceil_div(capacity, GRAIN_SIZE), GTES_PER_GT as u64);
+        let gt_sectors = ceil_div(GTES_PER_GT as u64 * 4, SECTOR);
+        let gd_sectors = ceil_div(gt_count * 4, SECTOR);
+        let mut header = Header {
+            version: 3,
+            flags: FLAG_NL_DETECT | FLAG_COMPRESSED | FLAG_MARKERS,
+            capacity,
+            grain_size: GRAIN_SIZE,
+            desc_offset: 1,
+            desc_size: DESC_SIZE,
+            gtes_per_gt: GTES_PER_GT,
+            rgd_offset: 0,
+            gd_offset: GD_AT_END,
+            overhead: ceil_div(1 + DESC_SIZE, GRAIN_SIZE) * GRAIN_SIZE,
+        };
+        let mut out = Vec::new();
+        header.write(&mut out)?;
+        out.extend_from_slice(descriptor(opts, capacity).as_bytes());
+        out.resize((header.overhead * SECTOR) as usize, 0);
+        let sector = |out: &Vec<u8>| out.len() as u64 / SECTOR;
+        let pad = |out: &mut Vec<u8>| {
+            let len = ceil_div(out.len() as u64, SECTOR) * SECTOR;
+            out.resize(len as usize, 0);
+        };
+
+        let mut gt = vec![0u32; (gt_count * GTES_PER_GT as u64) as usize];
+        for (i, chunk) in raw.chunks(grain_bytes).enumerate() {
+            if chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+            gt[i] = sector(&out) as u32;
+            let c = deflate(chunk, LEVELS[0])?;
+            out.write_u64::<LittleEndian>((i * grain_bytes) as u64 / SECTOR)?;
+            out.write_u32::<LittleEndian>(c.len() as u32)?;
+            out.extend(c);
+            pad(&mut out);
+        }
+        // A metadata marker: the sectors of metadata after it, and its type.
+        let marker = |out: &mut Vec<u8>, sectors: u64, kind: u32| -> Result<()> {
+            out.write_u64::<LittleEndian>(sectors)?;
+            out.write_u32::<LittleEndian>(0)?;
+            out.write_u32::<LittleEndian>(kind)?;
+            pad(out);
+            Ok(())
+        };
+        let mut gd = Vec::new();
+        for table in gt.chunks(GTES_PER_GT as usize) {
+            marker(&mut out, gt_sectors, 1)?;
+            gd.push(sector(&out) as u32);
+            for &e in table {
+                out.write_u32::<LittleEndian>(e)?;
+            }
+            pad(&mut out);
+        }
+        marker(&mut out, gd_sectors, 2)?;
+        header.gd_offset = sector(&out);
+        for &e in gd.iter() {
+            out.write_u32::<LittleEndian>(e)?;
+        }
+        pad(&mut out);
+        // The footer, then the end-of-stream marker.
+        marker(&mut out, 1, 3)?;
+        header.write(&mut out)?;
+        marker(&mut out, 0, 0)?;
+        Ok(out)
+    }
+
+    /// A [`fixture`] with the default options, whose grains are compressed
+    /// at zlib's default level.
+    pub(crate) fn default_fixture(raw: &[u8]) -> Result<Vec<u8>> {
+        fixture(raw, &Options::default(), |d| deflate(d, LEVELS[0]))
+    }
+
+    /// The uncompressed grains of `vmdk`.
+    pub(crate) fn grains_of(vmdk: &[u8]) -> Result<Vec<u8>> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let src = &dir.join("src");
+        std::fs::write(src, vmdk)?;
+        let mut grains = vec![Vec::new()];
+        let disks = [(0, vmdk.len() as u64)];
+        split(src, &disks, &mut grains, &dir.join("layout"), dir)?;
+        Ok(grains.remove(0))
+    }
+
+    /// Data which compresses differently at different levels.
+    fn grain_data(seed: u32, len: usize) -> Vec<u8> {
+        let words: &[&[u8]] = &[b"coreos ", b"disk ", b"image ", b"vmdk ", b"grain\n"];
+        let mut r = Vec::new();
+        let mut x = seed;
+        while r.len() < len {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            r.extend_from_slice(words[(x >> 16) as usize % words.len()]);
+        }
+        r.truncate(len);
+        r
+    }
+
+    #[test]
+    fn test_descriptor() {
+        let d = Descriptor::parse(
+            "# Disk DescriptorFile\nversion=1\ncreateType=\"streamOptimized\"\n\
+             RW 8 SPARSE \"disk.vmdk\"\n#DDB\nddb.adapterType = \"ide\"\n",
+        );
+        assert_eq!(d.extent(), Some("disk.vmdk"));
+        assert_eq!(d.get("version"), Some("1"));
+        assert_eq!(d.get("createType"), Some("streamOptimized"));
+        assert_eq!(d.get("#DDB"), None);
+        let opts = Options::from_descriptor(&d);
+        assert_eq!(opts.adapter_type, "ide");
+        assert_eq!(opts.subformat, "streamOptimized");
+        assert_eq!(opts.hw_version, Options::default().hw_version);
+        assert_eq!(opts.cid, None);
+    }
+
+    #[test]
+    fn test_split_join() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let grain = (GRAIN_SIZE * SECTOR) as usize;
+        let data: Vec<_> = (0..4).map(|i| grain_data(i, grain)).collect();
+        // A grain of zeroes, which isn't stored, and a partial last grain.
+        let mut disk = [&data[0][..], &data[1], &vec![0u8; grain], &data[2]].concat();
+        disk.extend_from_slice(&data[3][..grain / 2]);
+        let opts = Options {
+            adapter_type: "ide".to_string(),
+            hw_version: "4".to_string(),
+            cid: Some("0badcafe".to_string()),
+            ..Default::default()
+        };
+        // The last grain isn't compressed like the others, and must be stored.
+        let last = &disk[4 * grain..].to_vec();
+        let vmdk = fixture(&disk, &opts, |d| {
+            deflate(d, if d == last.as_slice() { 1 } else { 6 })
+        })?;
+        assert!(Header::parse(&vmdk).is_ok());
+        assert!(Header::parse(&vmdk[1..]).is_err());
+        // Offsets past the end are errors, even when adding to them overflows.
+        let mut bad = vmdk.clone();
+        bad[28..36].copy_from_slice(&(u64::MAX / SECTOR).to_le_bytes());
+        let read_at = |pos: u64, n: u64| Ok(bad[pos as usize..(pos + n) as usize].to_vec());
+        assert!(Vmdk::parse(bad.len() as u64, read_at).is_err());
+
+        // A second VMDK of a single grain.
+        let vmdk2 = fixture(&data[3], &opts, |d| deflate(d, 6))?;
+
+        // Both a bare VMDK, and two in an OVA.
+        for &container in &[false, true] {
+            let (file, disks) = if container {
+                let mut b = tar::Builder::new(Vec::new());
+                for (name, data) in &[
+                    ("a.ovf", &b"ovf"[..]),
+                    ("a-1.vmdk", &vmdk),
+                    ("a-2.vmdk", &vmdk2),
+                ] {
+                    let mut h = tar::Header::new_ustar();
+                    h.set_size(data.len() as u64);
+                    h.set_cksum();
+                    b.append_data(&mut h, name, *data)?;
+                }
+                let file = b.into_inner()?;
+                let ova = Ova::read(file.as_slice())?;
+                let disks = ova.disks().map(|e| (e.offset, e.size)).collect();
+                (file, disks)
+            } else {
+                (vmdk.clone(), vec![(0, vmdk.len() as u64)])
+            };
+            let src = &dir.join("src");
+            std::fs::write(src, &file)?;
+            let (offset, len) = disks[0];
+            let v = Vmdk::open(&File::open(src)?, offset, len)?;
+            assert_eq!(v.options(), opts);
+            assert_eq!(v.descriptor.get("createType"), Some("streamOptimized"));
+            let lbas: Vec<_> = v.grains.iter().map(|g| g.lba).collect();
+            assert_eq!(lbas, [0, 128, 384, 512]);
+
+            let layout_path = &dir.join("layout");
+            let mut grains = vec![Vec::new(); disks.len()];
+            let split_opts = split(src, &disks, &mut grains, layout_path, dir)?;
+            assert_eq!(split_opts, vec![opts.clone(); disks.len()]);
+            assert_eq!(grains[0], [&data[0][..], &data[1], &data[2], last].concat());
+            if container {
+                assert_eq!(grains[1], data[3]);
+            }
+            assert_eq!(layout_size(layout_path)?, file.len() as u64);
+            assert_eq!(layout_disks(layout_path)?, disks.len());
+            let names: Vec<_> = layout_disk_names(layout_path)?
+                .into_iter()
+                .map(|n| n.map(String::from))
+                .collect();
+            if container {
+                assert_eq!(names, [Some("a-1.vmdk".into()), Some("a-2.vmdk".into())]);
+            } else {
+                assert_eq!(names, [None]);
+            }
+            let mut out = Vec::new();
+            let readers = grains.iter().map(|g| g.as_slice()).collect();
+            join(layout_path, &split_opts, readers, &mut out)?;
+            assert_eq!(out, file);
+            let readers = vec![&grains[0][..]; 3];
+            assert!(join(layout_path, &split_opts, readers, std::io::sink()).is_err());
+            // The VMDKs must have been created with the recorded options.
+            let mut other = split_opts.clone();
+            other[0].hw_version = "6".to_string();
+            let readers = grains.iter().map(|g| g.as_slice()).collect();
+            assert!(join(layout_path, &other, readers, std::io::sink()).is_err());
+            // Storing a grain costs its compressed size.
+            let stored = deflate(last, 1)?;
+            assert_ne!(stored, deflate(last, 6)?);
+            let mut r = BufReader::new(File::open(layout_path)?);
+            LayoutHeader::read(&mut r)?;
+            let mut r = zstd::Decoder::with_buffer(r)?;
+            let layout: Layout = bincode::deserialize_from(&mut r)?;
+            assert_eq!(layout.level, 6);
+            let flags: Vec<_> = layout.grains.iter().map(|g| g.stored).collect();
+            assert_eq!(flags[..4], [false, false, false, true]);
+            assert_eq!(flags.len(), 4 + disks.len() - 1);
+            let mut rest = Vec::new();
+            r.read_to_end(&mut rest)?;
+            assert_eq!(rest, stored);
+
+            // A different zlib might not reproduce the grains.
+            let mut f = std::fs::read(layout_path)?;
+            assert_eq!(f[20] as usize, zlib_fingerprint()?.len());
+            f[21] ^= 1;
+            std::fs::write(layout_path, f)?;
+            let readers = grains.iter().map(|g| g.as_slice()).collect();
+            assert!(join(layout_path, &split_opts, readers, std::io::sink()).is_err());
+        }
+
+        // The OVF descriptor of an OVA must have the sizes of its members.
+        let mut b = tar::Builder::new(Vec::new());
+        let ovf = format!(
+            r#"<File ovf:href="a-1.vmdk" ovf:id="file1" ovf:size="{}"/>
+<Disk ovf:capacity="{}" ovf:fileRef="file1"/>"#,
+            vmdk.len() + 1,
+            disk.len()
+        );
+        for (name, data) in &[("a.ovf", ovf.as_bytes()), ("a-1.vmdk", &vmdk)] {
+            let mut h = tar::Header::new_ustar();
+            h.set_size(data.len() as u64);
+            h.set_cksum();
+            b.append_data(&mut h, name, *data)?;
+        }
+        let file = b.into_inner()?;
+        let src = &dir.join("src");
+        std::fs::write(src, &file)?;
+        let disks: Vec<_> = Ova::read(file.as_slice())?
+            .disks()
+            .map(|e| (e.offset, e.size))
+            .collect();
+        let layout_path = &dir.join("layout");
+        let mut grains = vec![Vec::new()];
+        let split_opts = split(src, &disks, &mut grains, layout_path, dir)?;
+        let readers = vec![grains[0].as_slice()];
+        let e = join(layout_path, &split_opts, readers, std::io::sink()).unwrap_err();
+        assert!(format!("{:#}", e).contains("OVF gives the size of a-1.vmdk"));
+
+        // The level is found from the first grains any level reproduces, and
+        // when none does, every grain is stored.
+        let cases = [(6, [true, false, false, false]), (0, [true; 4])];
+        for &(level, stored) in cases.iter() {
+            let first = &data[0];
+            let vmdk = fixture(&disk, &opts, |d| {
+                deflate(d, if d == first.as_slice() { 0 } else { level })
+            })?;
+            let src = &dir.join("src");
+            std::fs::write(src, &vmdk)?;
+            let layout_path = &dir.join("layout");
+            let disks = [(0, vmdk.len() as u64)];
+            let mut grains = vec![Vec::new()];
+            let split_opts = split(src, &disks, &mut grains, layout_path, dir)?;
+            let mut r = BufReader::new(File::open(layout_path)?);
+            LayoutHeader::read(&mut r)?;
+            let layout: Layout = bincode::deserialize_from(zstd::Decoder::with_buffer(r)?)?;
+            let flags: Vec<_> = layout.grains.iter().map(|g| g.stored).collect();
+            assert_eq!(flags, stored);
+            let mut out = Vec::new();
+            join(
+                layout_path,
+                &split_opts,
+                vec![grains[0].as_slice()],
+                &mut out,
+            )?;
+            assert_eq!(out, vmdk);
+        }
+
+        // Other layout versions are rejected.
+        let mut f = std::fs::read(layout_path)?;
+        f[LAYOUT_MAGIC.len()] += 1;
+        assert!(LayoutHeader::read(f.as_slice()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_gd_at_end() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let grain = (GRAIN_SIZE * SECTOR) as usize;
+        let data: Vec<_> = (0..3).map(|i| grain_data(i, grain)).collect();
+        let disk = [&data[0][..], &vec![0u8; grain], &data[1], &data[2]].concat();
+        let opts = Options {
+            cid: Some("0badcafe".to_string()),
+            ..Default::default()
+        };
+        let vmdk = stream_fixture(&disk, &opts)?;
+        assert_eq!(Header::parse(&vmdk)?.gd_offset, GD_AT_END);
+        let src = &dir.join("src");
+        std::fs::write(src, &vmdk)?;
+        let v = Vmdk::open(&File::open(src)?, 0, vmdk.len() as u64)?;
+        assert_ne!(v.header.gd_offset, GD_AT_END);
+        assert_eq!(v.options(), opts);
+        let lbas: Vec<_> = v.grains.iter().map(|g| g.lba).collect();
+        assert_eq!(lbas, [0, 256, 384]);
+
+        let layout_path = &dir.join("layout");
+        let mut grains = vec![Vec::new()];
+        let disks = [(0, vmdk.len() as u64)];
+        let split_opts = split(src, &disks, &mut grains, layout_path, dir)?;
+        assert_eq!(grains[0], data.concat());
+        let mut out = Vec::new();
+        join(
+            layout_path,
+            &split_opts,
+            vec![grains[0].as_slice()],
+            &mut out,
+        )?;
+        assert_eq!(out, vmdk);
+
+        // Without its footer, the grain directory can't be found.
+        let truncated = &vmdk[..SECTOR as usize];
+        std::fs::write(src, truncated)?;
+        assert!(Vmdk::open(&File::open(src)?, 0, truncated.len() as u64).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir: &Utf8Path = td.path().try_into()?;
+        let grain = (GRAIN_SIZE * SECTOR) as usize;
+        let data: Vec<_> = (0..3).map(|i| grain_data(i, grain)).collect();
+        let disk = [&data[0][..], &vec![0u8; grain], &data[1], &data[2]].concat();
+        let opts = Options {
+            adapter_type: "ide".to_string(),
+            cid: Some("0badcafe".to_string()),
+            ..Default::default()
+        };
+        let vmdk = fixture(&disk, &opts, |d| deflate(d, 6))?;
+        let src = &dir.join("src");
+        std::fs::write(src, &vmdk)?;
+        let layout_path = &dir.join("layout");
+        let mut grains = vec![Vec::new()];
+        let disks = [(0, vmdk.len() as u64)];
+        let split_opts = split(src, &disks, &mut grains, layout_path, dir)?;
+        assert!(reproducible(layout_path)?);
+
+        // Split with a zlib which compresses differently, it's written anew.
+        let mut f = std::fs::read(layout_path)?;
+        f[21] ^= 1;
+        std::fs::write(layout_path, f)?;
+        assert!(!reproducible(layout_path)?);
+        let mut out = std::io::Cursor::new(Vec::new());
+        // With the recorded options, which must match the descriptor.
+        let readers = vec![grains[0].as_slice()];
+        write(layout_path, &split_opts, readers, dir, &mut out)?;
+        let written = out.into_inner();
+        assert_ne!(written, vmdk);
+        std::fs::write(src, &written)?;
+        let v = Vmdk::open(&File::open(src)?, 0, written.len() as u64)?;
+        assert_eq!(v.options(), opts);
+        assert_eq!(v.descriptor.extent(), Some("disk.vmdk"));
+        let lbas: Vec<_> = v.grains.iter().map(|g| g.lba).collect();
+        assert_eq!(lbas, [0, 256, 384]);
+        assert_eq!(grains_of(&written)?, grains[0]);
+        let out = std::io::Cursor::new(Vec::new());
+        let readers = vec![&grains[0][..]; 2];
+        assert!(write(layout_path, &split_opts, readers, dir, out).is_err());
+        let out = std::io::Cursor::new(Vec::new());
+        let other = [Options::default()];
+        assert!(write(layout_path, &other, vec![grains[0].as_slice()], dir, out).is_err());
+        Ok(())
+    }
+}
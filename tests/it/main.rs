@@ -1,6 +1,114 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use assert_cmd::prelude::*;
-use std::process::{Command, Stdio};
+use camino::{Utf8Path, Utf8PathBuf};
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+const QEMU: &str = "fedora-coreos-qemu.x86_64.qcow2";
+const OPENSTACK: &str = "fedora-coreos-openstack.x86_64.qcow2";
+const EXOSCALE: &str = "fedora-coreos-exoscale.x86_64.qcow2";
+
+fn rehydrator() -> Result<Command> {
+    Ok(Command::cargo_bin("coreos-diskimage-rehydrator")?)
+}
+
+/// Run the rehydrator with `args`, returning its stdout.
+fn run(args: &[&str]) -> Result<Vec<u8>> {
+    let out = rehydrator()?.args(args).output()?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "{:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    Ok(out.stdout)
+}
+
+/// Disk data which compresses (but not too well).
+fn disk_data(seed: u32, len: usize) -> Vec<u8> {
+    let mut x = seed;
+    (0..len)
+        .map(|i| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if i % 4096 < 1024 {
+                0
+            } else {
+                (x >> 24) as u8 & 0x3f
+            }
+        })
+        .collect()
+}
+
+fn artifact(dir: &Utf8Path, name: &str, data: &[u8]) -> Result<serde_json::Value> {
+    std::fs::write(dir.join(name), data)?;
+    Ok(serde_json::json!({
+        "location": format!("https://example.com/{}", name),
+        "sha256": format!("{:x}", Sha256::digest(data)),
+    }))
+}
+
+/// Write the original images and stream metadata to `dir`.
+fn write_stream(dir: &Utf8Path) -> Result<()> {
+    let qemu = disk_data(1, 4 * 65536);
+    let mut openstack = qemu.clone();
+    openstack[5000..5009].copy_from_slice(b"openstack");
+    let stream = serde_json::json!({
+        "stream": "stable",
+        "metadata": { "last-modified": "2021-05-05T08:57:10Z" },
+        "architectures": { "x86_64": { "artifacts": {
+            "qemu": { "release": "1", "formats": { "qcow2": {
+                "disk": artifact(dir, QEMU, &qemu)?,
+            } } },
+            "openstack": { "release": "1", "formats": { "qcow2": {
+                "disk": artifact(dir, OPENSTACK, &openstack)?,
+            } } },
+        } } },
+    });
+    std::fs::write(dir.join("stream.json"), serde_json::to_vec(&stream)?)?;
+    Ok(())
+}
+
+/// Add a gzipped artifact for `platform` to the stream metadata in `dir`,
+/// without the SHA-256 of its uncompressed data, as in RHCOS.
+fn add_gzipped(dir: &Utf8Path, platform: &str, name: &str, data: &[u8]) -> Result<()> {
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(data)?;
+    let gz = &gz.finish()?;
+    let path = &dir.join("stream.json");
+    let mut s: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    s["architectures"]["x86_64"]["artifacts"][platform] = serde_json::json!({
+        "release": "1", "formats": { "qcow2.gz": {
+            "disk": artifact(dir, &format!("{}.gz", name), gz)?,
+        } },
+    });
+    std::fs::write(path, serde_json::to_vec(&s)?)?;
+    Ok(())
+}
+
+/// Dehydrate the original images in `dir` into a bundle there, whose
+/// path is returned.
+fn dehydrate(dir: &Utf8Path) -> Result<Utf8PathBuf> {
+    run(&[
+        "build",
+        "dehydrate",
+        "--workdir",
+        dir.as_str(),
+        "--arch",
+        "x86_64",
+    ])?;
+    Ok(dir.join("coreos-images-dehydrated"))
+}
+
+/// Write the original images to `dir`, and dehydrate them into a bundle
+/// there, whose path is returned.
+fn bundle(dir: &Utf8Path) -> Result<Utf8PathBuf> {
+    write_stream(dir)?;
+    dehydrate(dir)
+}
 
 #[test]
 fn test_run_help() -> Result<()> {
@@ -12,3 +120,238 @@ fn test_run_help() -> Result<()> {
     assert!(s.success());
     Ok(())
 }
+
+#[test]
+fn test_build_verify() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    write_stream(dir)?;
+    let args = &[
+        "build",
+        "verify",
+        "--skip-signatures",
+        "--workdir",
+        dir.as_str(),
+        "--arch",
+        "x86_64",
+    ];
+    run(args)?;
+    std::fs::write(dir.join(OPENSTACK), b"corrupt")?;
+    assert!(run(args).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_list() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    let bundle = bundle(dir)?;
+    let out = run(&[
+        "list",
+        "--bundle",
+        bundle.as_str(),
+        "--arch",
+        "x86_64",
+        "--json",
+    ])?;
+    let l: serde_json::Value = serde_json::from_slice(&out)?;
+    assert_eq!(l["stream"], "stable");
+    let images = l["images"].as_array().unwrap();
+    let names: Vec<_> = images.iter().map(|i| i["filename"].as_str()).collect();
+    assert_eq!(names, [Some(QEMU), Some(OPENSTACK)]);
+    assert!(images.iter().all(|i| i["validated"] == true));
+
+    let out = String::from_utf8(run(&[
+        "list",
+        "--bundle",
+        bundle.as_str(),
+        "--arch",
+        "x86_64",
+    ])?)?;
+    assert!(out.starts_with("PLATFORM"));
+    assert!(out.contains(OPENSTACK));
+    Ok(())
+}
+
+#[test]
+fn test_rehydrate_all() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    let bundle = bundle(dir)?;
+    let out = &dir.join("out");
+    std::fs::create_dir(out)?;
+    run(&[
+        "rehydrate",
+        "--bundle",
+        bundle.as_str(),
+        "--arch",
+        "x86_64",
+        "--all",
+        out.as_str(),
+    ])?;
+    for &name in [QEMU, OPENSTACK].iter() {
+        assert_eq!(
+            std::fs::read(out.join(name))?,
+            std::fs::read(dir.join(name))?
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_rehydrate_compress() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    let bundle = bundle(dir)?;
+    let out = &dir.join("out");
+    std::fs::create_dir(out)?;
+    run(&[
+        "rehydrate",
+        "--bundle",
+        bundle.as_str(),
+        "--arch",
+        "x86_64",
+        "--disk",
+        "openstack",
+        "--compress",
+        "gz",
+        out.as_str(),
+    ])?;
+    let f = std::fs::File::open(out.join(format!("{}.gz", OPENSTACK)))?;
+    let mut data = Vec::new();
+    flate2::read::GzDecoder::new(f).read_to_end(&mut data)?;
+    assert_eq!(data, std::fs::read(dir.join(OPENSTACK))?);
+    Ok(())
+}
+
+#[test]
+fn test_rehydrate_stdout() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    let bundle = bundle(dir)?;
+    let original = |name: &str| std::fs::read(dir.join(name));
+    // A single image is written to stdout as is, and multiple as a tar stream.
+    let args: &[&str] = &["rehydrate", "--bundle", bundle.as_str(), "--arch", "x86_64"];
+    let out = run(&[args, &["-", "--disk", "openstack"][..]].concat())?;
+    assert_eq!(out, original(OPENSTACK)?);
+    let out = run(&[args, &["-", "--disk", "qemu", "--disk", "openstack"][..]].concat())?;
+    let mut names = Vec::new();
+    for e in tar::Archive::new(out.as_slice()).entries()? {
+        let mut e = e?;
+        let name = e.path()?.to_str().unwrap().to_string();
+        let mut data = Vec::new();
+        e.read_to_end(&mut data)?;
+        assert_eq!(data, original(name.as_str())?);
+        let h = e.header();
+        assert_eq!(h.mtime()?, 1620205030);
+        assert_eq!((h.uid()?, h.gid()?, h.mode()?), (0, 0, 0o644));
+        names.push(name);
+    }
+    assert_eq!(names, [OPENSTACK, QEMU]);
+    Ok(())
+}
+
+#[test]
+fn test_rehydrate_unvalidated() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    write_stream(dir)?;
+    let mut data = disk_data(1, 4 * 65536);
+    data[7000..7008].copy_from_slice(b"exoscale");
+    add_gzipped(dir, "exoscale", EXOSCALE, &data)?;
+    let bundle = dehydrate(dir)?;
+    let out = run(&[
+        "rehydrate",
+        "--bundle",
+        bundle.as_str(),
+        "--arch",
+        "x86_64",
+        "-",
+        "--disk",
+        "exoscale",
+    ])?;
+    assert_eq!(out, data);
+    Ok(())
+}
+
+#[test]
+fn test_mirror() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    let bundle = bundle(dir)?;
+    let out = &dir.join("mirror");
+    run(&[
+        "rehydrate",
+        "--bundle",
+        bundle.as_str(),
+        "--mirror",
+        out.as_str(),
+        "--base-url",
+        "https://mirror.example.com/fcos/",
+    ])?;
+    let s: serde_json::Value = serde_json::from_slice(&std::fs::read(out.join("stream.json"))?)?;
+    let disk = &s["architectures"]["x86_64"]["artifacts"]["openstack"]["formats"]["qcow2"]["disk"];
+    assert_eq!(
+        disk["location"],
+        format!("https://mirror.example.com/fcos/x86_64/{}", OPENSTACK)
+    );
+    let data = std::fs::read(out.join("x86_64").join(OPENSTACK))?;
+    assert_eq!(data, std::fs::read(dir.join(OPENSTACK))?);
+    assert_eq!(disk["sha256"], format!("{:x}", Sha256::digest(&data)));
+    Ok(())
+}
+
+/// Kills the server when dropped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_serve() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let dir: &Utf8Path = td.path().try_into()?;
+    write_stream(dir)?;
+    let exoscale = disk_data(2, 65536);
+    add_gzipped(dir, "exoscale", EXOSCALE, &exoscale)?;
+    let bundle = dehydrate(dir)?;
+    let listen = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let _server = Server(
+        rehydrator()?
+            .args(&["serve", "--bundle", bundle.as_str(), "--arch", "x86_64"])
+            .args(&["--listen", listen.as_str(), "--cachedir", dir.as_str()])
+            .stderr(Stdio::null())
+            .spawn()?,
+    );
+    let url = &format!("http://{}", listen);
+    let stream_url = &format!("{}/stream.json", url);
+    let mut tries = 0;
+    let s: serde_json::Value = loop {
+        match reqwest::blocking::get(stream_url) {
+            Ok(resp) => break serde_json::from_slice(&resp.error_for_status()?.bytes()?)?,
+            Err(_) if tries < 100 => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => return Err(e.into()),
+        }
+        tries += 1;
+    };
+    let disk = &s["architectures"]["x86_64"]["artifacts"]["openstack"]["formats"]["qcow2"]["disk"];
+    let location = format!("{}/x86_64/{}", url, OPENSTACK);
+    assert_eq!(disk["location"], location.as_str());
+    let resp = reqwest::blocking::get(&location)?.error_for_status()?;
+    assert_eq!(resp.bytes()?.to_vec(), std::fs::read(dir.join(OPENSTACK))?);
+    // The digest of the gzipped artifact wouldn't match what's served, so
+    // it's left out, but can still be fetched.
+    let artifacts = &s["architectures"]["x86_64"]["artifacts"];
+    assert!(artifacts["exoscale"].is_null());
+    let resp = reqwest::blocking::get(&format!("{}/x86_64/{}", url, EXOSCALE))?;
+    assert_eq!(resp.error_for_status()?.bytes()?.to_vec(), exoscale);
+    let resp = reqwest::blocking::get(&format!("{}/x86_64/nope.qcow2", url))?;
+    assert_eq!(resp.status(), 404);
+    Ok(())
+}